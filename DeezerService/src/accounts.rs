@@ -0,0 +1,317 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+use reqwest::cookie::Jar;
+use reqwest::{Client, Url};
+use serde::Serialize;
+use tokio::sync::{Mutex, RwLock};
+use crate::deezer::{ApiError, SongFormat, UserOptions};
+
+/// First cooldown of a failing account, doubled for every further failure.
+const BASE_COOLDOWN: Duration = Duration::from_secs(60);
+const MAX_COOLDOWN: Duration = Duration::from_secs(30 * 60);
+
+/// How long an account sits out after `consecutive_failures` failures in a row.
+fn cooldown(consecutive_failures: u32) -> Duration {
+    BASE_COOLDOWN
+        .saturating_mul(1 << consecutive_failures.saturating_sub(1).min(10))
+        .min(MAX_COOLDOWN)
+}
+
+#[derive(Default)]
+struct Health {
+    cooldown_until: Option<Instant>,
+    consecutive_failures: u32,
+    last_error: Option<String>,
+}
+
+/// One Deezer login: its own cookie jar and the tokens fetched with it.
+pub struct Account {
+    pub(crate) index: usize,
+    pub(crate) client: Client,
+    pub(crate) api_token: RwLock<String>,
+    pub(crate) license_token: RwLock<String>,
+    pub(crate) token_update_lock: Mutex<()>,
+    /// What the subscription includes, unknown until its tokens are fetched.
+    pub(crate) options: RwLock<Option<UserOptions>>,
+    health: StdMutex<Health>,
+    requests: AtomicU64,
+}
+
+impl Account {
+    fn new(index: usize, arl: &str) -> Self {
+        let jar = Arc::new(Jar::default());
+        let client = Client::builder().cookie_provider(Arc::clone(&jar)).build().unwrap();
+        let url = Url::parse("https://www.deezer.com").unwrap();
+
+        // Add a cookie to the jar
+        jar.add_cookie_str(format!("arl={}", arl).as_str(), &url);
+        Self {
+            index,
+            client,
+            api_token: RwLock::new(String::new()),
+            license_token: RwLock::new(String::new()),
+            token_update_lock: Mutex::new(()),
+            options: RwLock::new(None),
+            health: StdMutex::new(Health::default()),
+            requests: AtomicU64::new(0),
+        }
+    }
+
+    async fn lossless(&self) -> Option<bool> {
+        self.options.read().await.as_ref().map(|options| options.web_lossless)
+    }
+
+    /// `formats` without the ones the subscription doesn't include, while
+    /// the options aren't known yet everything is allowed.
+    pub(crate) async fn playable_formats(&self, formats: &[SongFormat]) -> Result<Vec<SongFormat>, ApiError> {
+        let playable: Vec<SongFormat> = match &*self.options.read().await {
+            Some(options) => formats.iter().copied().filter(|f| options.can_stream(*f)).collect(),
+            None => formats.to_vec(),
+        };
+
+        if playable.is_empty() {
+            return Err(ApiError::FormatUnavailable(formats.iter().map(SongFormat::api_name).collect()));
+        }
+        Ok(playable)
+    }
+
+    fn cooldown_remaining(&self, now: Instant) -> Option<Duration> {
+        let health = self.health.lock().unwrap();
+        health.cooldown_until
+            .and_then(|until| until.checked_duration_since(now))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    pub(crate) fn record_success(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let mut health = self.health.lock().unwrap();
+        health.consecutive_failures = 0;
+        health.cooldown_until = None;
+    }
+
+    /// Takes the account out of rotation for a while, longer every time it
+    /// fails again.
+    pub(crate) fn record_failure(&self, error: &ApiError) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let mut health = self.health.lock().unwrap();
+        health.consecutive_failures += 1;
+
+        let cooldown = cooldown(health.consecutive_failures);
+        health.cooldown_until = Some(Instant::now() + cooldown);
+        health.last_error = Some(error.to_string());
+
+        eprintln!("Deezer account {} failed ({}), cooling down for {}s.", self.index, error, cooldown.as_secs());
+    }
+}
+
+#[derive(Serialize)]
+pub struct AccountInfo {
+    pub index: usize,
+    pub options: Option<UserOptions>,
+}
+
+#[derive(Serialize)]
+pub struct AccountStatus {
+    pub index: usize,
+    pub available: bool,
+    pub cooldown_remaining_secs: u64,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub lossless: Option<bool>,
+    pub requests: u64,
+}
+
+/// The Deezer accounts requests are spread over.
+pub struct AccountPool {
+    accounts: Vec<Arc<Account>>,
+    next: AtomicUsize,
+}
+
+impl AccountPool {
+    pub fn new(arls: &[String]) -> Self {
+        Self {
+            accounts: arls.iter().enumerate().map(|(i, arl)| Arc::new(Account::new(i, arl))).collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Arc<Account>> {
+        self.accounts.iter()
+    }
+
+    /// Next account in round-robin order that isn't cooling down. With
+    /// `lossless` accounts known to lack HiFi are only used when nothing
+    /// else is available.
+    pub async fn pick(&self, lossless: bool) -> Result<Arc<Account>, ApiError> {
+        let now = Instant::now();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let available = (0..self.accounts.len())
+            .map(|i| &self.accounts[(start + i) % self.accounts.len()])
+            .filter(|account| account.cooldown_remaining(now).is_none());
+
+        let mut fallback = None;
+        for account in available {
+            if !lossless || account.lossless().await != Some(false) {
+                return Ok(account.clone());
+            }
+            fallback.get_or_insert_with(|| account.clone());
+        }
+
+        fallback.ok_or(ApiError::NoAccountAvailable)
+    }
+
+    /// `formats` without the ones none of the accounts out of cooldown can
+    /// stream, in the same order.
+    pub async fn playable_formats(&self, formats: &[SongFormat]) -> Result<Vec<SongFormat>, ApiError> {
+        let now = Instant::now();
+        let mut playable = Vec::new();
+        let mut available = false;
+
+        for account in self.accounts.iter().filter(|account| account.cooldown_remaining(now).is_none()) {
+            available = true;
+            if let Ok(formats) = account.playable_formats(formats).await {
+                playable.extend(formats);
+            }
+        }
+
+        if !available {
+            return Err(ApiError::NoAccountAvailable);
+        }
+        let playable: Vec<SongFormat> = formats.iter().copied().filter(|f| playable.contains(f)).collect();
+        if playable.is_empty() {
+            return Err(ApiError::FormatUnavailable(formats.iter().map(SongFormat::api_name).collect()));
+        }
+        Ok(playable)
+    }
+
+    pub async fn status(&self) -> Vec<AccountStatus> {
+        let now = Instant::now();
+        let mut status = Vec::with_capacity(self.accounts.len());
+
+        for account in &self.accounts {
+            let remaining = account.cooldown_remaining(now);
+            let (consecutive_failures, last_error) = {
+                let health = account.health.lock().unwrap();
+                (health.consecutive_failures, health.last_error.clone())
+            };
+
+            status.push(AccountStatus {
+                index: account.index,
+                available: remaining.is_none(),
+                cooldown_remaining_secs: remaining.map_or(0, |r| r.as_secs()),
+                consecutive_failures,
+                last_error,
+                lossless: account.lossless().await,
+                requests: account.requests.load(Ordering::Relaxed),
+            });
+        }
+
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(n: usize) -> AccountPool {
+        AccountPool::new(&(0..n).map(|i| format!("arl{}", i)).collect::<Vec<_>>())
+    }
+
+    async fn set_lossless(account: &Account, lossless: bool) {
+        *account.options.write().await = Some(UserOptions { web_hq: true, web_lossless: lossless, ..Default::default() });
+    }
+
+    #[test]
+    fn cooldown_doubles_and_caps_at_30_minutes() {
+        assert_eq!(cooldown(1), Duration::from_secs(60));
+        assert_eq!(cooldown(2), Duration::from_secs(120));
+        assert_eq!(cooldown(3), Duration::from_secs(240));
+        assert_eq!(cooldown(5), Duration::from_secs(960));
+        assert_eq!(cooldown(6), MAX_COOLDOWN);
+        assert_eq!(cooldown(u32::MAX), MAX_COOLDOWN);
+    }
+
+    #[test]
+    fn failures_put_the_account_on_cooldown_until_a_success() {
+        let pool = pool(1);
+        let account = &pool.accounts[0];
+        let error = ApiError::TokenRequired(String::new());
+
+        account.record_failure(&error);
+        account.record_failure(&error);
+        let remaining = account.cooldown_remaining(Instant::now()).unwrap();
+        assert!(remaining > Duration::from_secs(60) && remaining <= Duration::from_secs(120));
+
+        account.record_success();
+        assert!(account.cooldown_remaining(Instant::now()).is_none());
+        assert_eq!(account.requests.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn pick_round_robins_and_skips_accounts_on_cooldown() {
+        let pool = pool(3);
+        assert_eq!(pool.pick(false).await.unwrap().index, 0);
+        assert_eq!(pool.pick(false).await.unwrap().index, 1);
+
+        // Account 2's turn goes to the next one in line.
+        pool.accounts[2].record_failure(&ApiError::TokenRequired(String::new()));
+        assert_eq!(pool.pick(false).await.unwrap().index, 0);
+        assert_eq!(pool.pick(false).await.unwrap().index, 0);
+        assert_eq!(pool.pick(false).await.unwrap().index, 1);
+    }
+
+    #[tokio::test]
+    async fn lossless_prefers_accounts_with_hifi() {
+        let pool = pool(3);
+        set_lossless(&pool.accounts[0], false).await;
+        set_lossless(&pool.accounts[1], true).await;
+        set_lossless(&pool.accounts[2], false).await;
+
+        for _ in 0..4 {
+            assert_eq!(pool.pick(true).await.unwrap().index, 1);
+        }
+
+        // Without a HiFi account left the others are still better than nothing.
+        pool.accounts[1].record_failure(&ApiError::TokenRequired(String::new()));
+        assert_ne!(pool.pick(true).await.unwrap().index, 1);
+    }
+
+    #[tokio::test]
+    async fn no_account_is_available_when_all_are_cooling_down() {
+        let pool = pool(2);
+        for account in &pool.accounts {
+            account.record_failure(&ApiError::TokenRequired(String::new()));
+        }
+        assert!(matches!(pool.pick(false).await, Err(ApiError::NoAccountAvailable)));
+        assert!(matches!(AccountPool::new(&[]).pick(false).await, Err(ApiError::NoAccountAvailable)));
+    }
+
+    #[tokio::test]
+    async fn pool_plays_what_any_available_account_can() {
+        let pool = pool(2);
+        let all = SongFormat::ALL.to_vec();
+        // Nothing is known about the accounts yet.
+        assert_eq!(pool.playable_formats(&all).await.unwrap(), all);
+
+        *pool.accounts[0].options.write().await = Some(UserOptions::default());
+        *pool.accounts[1].options.write().await = Some(UserOptions { web_hq: true, ..Default::default() });
+        assert_eq!(pool.playable_formats(&all).await.unwrap(), [SongFormat::Mp3_320, SongFormat::Mp3_128]);
+
+        pool.accounts[1].record_failure(&ApiError::TokenRequired(String::new()));
+        assert_eq!(pool.playable_formats(&all).await.unwrap(), [SongFormat::Mp3_128]);
+        assert!(matches!(pool.playable_formats(&[SongFormat::Flac]).await, Err(ApiError::FormatUnavailable(_))));
+
+        pool.accounts[0].record_failure(&ApiError::TokenRequired(String::new()));
+        assert!(matches!(pool.playable_formats(&all).await, Err(ApiError::NoAccountAvailable)));
+    }
+}
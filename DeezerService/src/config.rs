@@ -0,0 +1,62 @@
+use std::net::SocketAddr;
+use serde::Deserialize;
+use music_core::config::{self, ConfigError, S3Config, Secret, SessionConfig, TranscodeConfig};
+
+fn default_bind_addr() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 3000))
+}
+
+/// DeezerService settings, read from `DEEZER_*` env vars and the optional
+/// TOML file in `DEEZER_CONFIG`.
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    /// `arl` cookies of the Deezer accounts used for streaming, comma
+    /// separated in the environment.
+    pub arls: Vec<Secret>,
+    pub database_url: Secret,
+    #[serde(flatten)]
+    pub s3: S3Config,
+    #[serde(flatten)]
+    pub sessions: SessionConfig,
+    #[serde(flatten)]
+    pub transcoding: TranscodeConfig,
+    #[serde(default = "default_bind_addr")]
+    pub bind_addr: SocketAddr,
+}
+
+impl Config {
+    pub const ENV_PREFIX: &'static str = "DEEZER_";
+
+    pub fn load() -> Result<Self, ConfigError> {
+        let config: Config = config::load(Self::ENV_PREFIX)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        validate_arls(&self.arls)?;
+        config::validate_database_url(&self.database_url)?;
+        self.s3.validate()?;
+        self.sessions.validate()?;
+        self.transcoding.validate()
+    }
+}
+
+pub fn validate_arls(arls: &[Secret]) -> Result<(), ConfigError> {
+    if arls.is_empty() {
+        return Err(ConfigError::invalid("arls", "at least one ARL is required"));
+    }
+    arls.iter().try_for_each(validate_arl)
+}
+
+/// ARLs are long hex strings, anything else is most likely a copy-paste error.
+pub fn validate_arl(arl: &Secret) -> Result<(), ConfigError> {
+    let arl = arl.expose();
+    if arl.is_empty() {
+        return Err(ConfigError::invalid("arl", "must not be empty"));
+    }
+    if !arl.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(ConfigError::invalid("arl", "must only contain hex digits"));
+    }
+    Ok(())
+}
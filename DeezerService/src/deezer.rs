@@ -1,61 +1,184 @@
-use std::io::Read;
-use std::pin::Pin;
 use serde_json::{self, Value};
-use reqwest::{ Method, Client, Url};
-use reqwest::cookie::Jar;
+use reqwest::{Method, Url};
 use std::sync::Arc;
-use axum::body::Body;
-use axum::extract::{FromRef, Path, State};
-use axum::http::{Response, StatusCode};
 use blowfish::Blowfish;
 use bytes::Bytes;
 use cbc::cipher::{BlockDecryptMut, KeyIvInit};
-use tokio::sync::{mpsc, Mutex, RwLock};
+use tokio::sync::mpsc;
 use thiserror::Error;
-use serde::{Deserialize, Serialize};
-use futures::{Stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Deserializer, Serialize};
+use futures::{StreamExt, TryStreamExt};
 use futures::stream::BoxStream;
-use md5;
 use tokio_stream::wrappers::ReceiverStream;
-use crate::postgres_service::{AlbumInput, AuthorInput, TrackInput};
-use crate::SharedState;
+use async_trait::async_trait;
+use reqwest::header::{CONTENT_RANGE, RANGE};
+use music_core::range::{self, ByteRange};
+use music_core::s3::CacheError;
+use music_core::tags::{Picture, TrackTags};
+use music_core::transcode::TranscodeError;
+use music_core::users::UserError;
+use crate::accounts::{Account, AccountInfo, AccountPool};
+use music_core::{self as core, AudioFormat, AudioStream, MusicProvider, ProviderError, SearchKind, SearchQuery, SearchResults};
 
 const BASE_URL: &str = "https://www.deezer.com/ajax/gw-light.php";
 
-const MEDIA_EL: &str = "
-                {
-                    \"type\": \"FULL\",
-                    \"formats\": [
-                        {
-                            \"cipher\": \"BF_CBC_STRIPE\",
-                            \"format\": \"FLAC\"
-                        },
-                        {
-                            \"cipher\": \"BF_CBC_STRIPE\",
-                            \"format\": \"MP3_320\"
-                        },
-                        {
-                            \"cipher\": \"BF_CBC_STRIPE\",
-                            \"format\": \"MP3_128\"
-                        },
-                        {
-                            \"cipher\": \"BF_CBC_STRIPE\",
-                            \"format\": \"MP3_64\"
-                        },
-                        {
-                            \"cipher\": \"BF_CBC_STRIPE\",
-                            \"format\": \"MP3_MISC\"
-                        }
-                    ]
-                }";
+const MEDIA_URL: &str = "https://media.deezer.com/v1/get_url";
 
 
-// #[derive(Debug)]
+/// Formats Deezer can serve, best first. Names mirror the `format` values of `get_url`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
 pub enum SongFormat {
-   FLAC, MP3,
+    #[serde(rename = "FLAC")]
+    Flac,
+    #[serde(rename = "MP3_320")]
+    Mp3_320,
+    #[serde(rename = "MP3_128")]
+    Mp3_128,
+}
+
+impl SongFormat {
+    pub const ALL: [SongFormat; 3] = [SongFormat::Flac, SongFormat::Mp3_320, SongFormat::Mp3_128];
+
+    pub fn api_name(&self) -> &'static str {
+        match self {
+            SongFormat::Flac => "FLAC",
+            SongFormat::Mp3_320 => "MP3_320",
+            SongFormat::Mp3_128 => "MP3_128",
+        }
+    }
+
+    pub fn from_api_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|format| format.api_name().eq_ignore_ascii_case(name))
+    }
+
+    /// This format followed by every lower one, in the order they should be tried.
+    pub fn with_fallback(self) -> Vec<SongFormat> {
+        Self::ALL.into_iter().skip_while(|format| *format != self).collect()
+    }
+
+    pub fn audio_format(&self) -> AudioFormat {
+        match self {
+            SongFormat::Flac => AudioFormat::Flac,
+            SongFormat::Mp3_320 | SongFormat::Mp3_128 => AudioFormat::Mp3,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        self.audio_format().extension()
+    }
+
+    /// Bitrate of the CBR MP3 formats.
+    pub fn mp3_kbps(&self) -> Option<u32> {
+        match self {
+            SongFormat::Flac => None,
+            SongFormat::Mp3_320 => Some(320),
+            SongFormat::Mp3_128 => Some(128),
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        self.audio_format().mime_type()
+    }
+}
+
+pub struct TrackUrl {
+    pub url: String,
+    pub format: SongFormat,
+}
+
+/// Decrypted track body together with the format Deezer actually returned.
+pub struct TrackStream {
+    pub format: SongFormat,
+    /// Size of the whole track, if the CDN reported it.
+    pub total_len: Option<u64>,
+    /// Inclusive byte range of the track this stream covers, set for ranged requests.
+    pub range: Option<(u64, u64)>,
+    pub stream: ReceiverStream<Result<Vec<u8>, ApiError>>,
+    /// Page the stream was resolved from, for tagging.
+    pub page: TrackPage,
+}
+
+/// Deezer encrypts tracks in 2048 byte stripes.
+const STRIPE_SIZE: u64 = 2048;
+
+/// Drops the first `skip` bytes and everything past `remaining`.
+fn trim_segment(mut segment: Vec<u8>, skip: &mut usize, remaining: &mut Option<u64>) -> Vec<u8> {
+    let skipped = (*skip).min(segment.len());
+    segment.drain(..skipped);
+    *skip -= skipped;
+
+    if let Some(remaining) = remaining {
+        segment.truncate((*remaining).min(segment.len() as u64) as usize);
+        *remaining -= segment.len() as u64;
+    }
+
+    segment
+}
+
+/// Stripe aligned `(start, end)` to download for bytes `start..=end`, so the
+/// stripe index, and with it which stripes are encrypted, stays known.
+fn aligned_range(start: u64, end: Option<u64>) -> (u64, Option<u64>) {
+    (start - start % STRIPE_SIZE, end.map(|end| end - end % STRIPE_SIZE + STRIPE_SIZE - 1))
+}
+
+/// Decrypts a track downloaded from the start of stripe `first_stripe` on and
+/// trims it to the requested bytes.
+struct StripeDecryptor {
+    key: [u8; 16],
+    stripe: u64,
+    buffer: Vec<u8>,
+    skip: usize,
+    remaining: Option<u64>,
+}
+
+impl StripeDecryptor {
+    fn new(key: [u8; 16], first_stripe: u64, skip: usize, remaining: Option<u64>) -> Self {
+        Self { key, stripe: first_stripe, buffer: Vec::with_capacity(STRIPE_SIZE as usize * 4), skip, remaining }
+    }
+
+    /// Output for the stripes `chunk` completes.
+    fn push(&mut self, chunk: &[u8]) -> Vec<Vec<u8>> {
+        const IV: [u8; 8] = [0, 1, 2, 3, 4, 5, 6, 7];
+
+        self.buffer.extend_from_slice(chunk);
+        let mut out = Vec::new();
+        while self.buffer.len() >= STRIPE_SIZE as usize && !self.is_done() {
+            let mut segment = self.buffer.drain(..STRIPE_SIZE as usize).collect::<Vec<u8>>();
+
+            // Every third stripe is encrypted, starting with the very first one.
+            if self.stripe.is_multiple_of(3) {
+                let mut cbc_decryptor = BlowfishCbcDec::new_from_slices(&self.key, &IV)
+                  .expect("Failed to create CBC decryptor");
+
+                for block in segment.chunks_mut(8) {
+                    cbc_decryptor.decrypt_block_mut(block.into());
+                }
+            }
+            self.stripe += 1;
+
+            let segment = trim_segment(segment, &mut self.skip, &mut self.remaining);
+            if !segment.is_empty() {
+                out.push(segment);
+            }
+        }
+        out
+    }
+
+    /// Whether all requested bytes are out.
+    fn is_done(&self) -> bool {
+        self.remaining == Some(0)
+    }
+
+    /// What is left once the download ended, a last partial stripe is never
+    /// encrypted.
+    fn finish(mut self) -> Vec<u8> {
+        trim_segment(std::mem::take(&mut self.buffer), &mut self.skip, &mut self.remaining)
+    }
 }
 
 #[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum ApiError {
     #[error("Request failed: {0}")]
     RequestError(#[from] reqwest::Error),
@@ -71,6 +194,58 @@ pub enum ApiError {
 
     #[error("A valid API token is required")]
     TokenRequired(String), // A specific, typed error for our retry logic
+
+    #[error("Requested range is not satisfiable")]
+    RangeNotSatisfiable,
+
+    #[error("None of the requested formats is available: {0:?}")]
+    FormatUnavailable(Vec<&'static str>),
+
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] sqlx::Error),
+
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    #[error(transparent)]
+    UserError(#[from] UserError),
+
+    #[error("No Deezer account is available right now")]
+    NoAccountAvailable,
+
+    #[error(transparent)]
+    TranscodeError(#[from] TranscodeError),
+
+    #[error("Storage error: {0}")]
+    StorageError(String),
+}
+
+impl From<CacheError> for ApiError {
+    fn from(e: CacheError) -> Self {
+        match e {
+            CacheError::RangeNotSatisfiable => ApiError::RangeNotSatisfiable,
+            CacheError::S3(e) => ApiError::StorageError(e),
+        }
+    }
+}
+
+impl ApiError {
+    /// Errors caused by the account rather than the request, another
+    /// account may well succeed.
+    pub fn is_account_error(&self) -> bool {
+        match self {
+            ApiError::TokenRequired(_) => true,
+            ApiError::ApiError(error) => {
+                ["NEED_USER_AUTH_REQUIRED", "USER_AUTH_REQUIRED", "VALID_TOKEN_REQUIRED", "QUOTA_ERROR", "RATE_LIMIT_EXCEEDED"]
+                    .iter()
+                    .any(|key| error.get(key).is_some())
+            }
+            _ => false,
+        }
+    }
 }
 
 
@@ -80,7 +255,7 @@ pub struct Artist {
     pub(crate) id: String,
     #[serde(rename = "ART_NAME")]
     pub(crate) name: String,
-    #[serde(rename = "ARTIST_IS_DUMMY")]
+    #[serde(rename = "ARTIST_IS_DUMMY", default)]
     is_dummy: bool,
     #[serde(rename = "ART_PICTURE")]
     pub(crate) picture: String,
@@ -103,14 +278,70 @@ pub struct TrackPage {
     pub alb_title:  String,
     #[serde(rename = "TRACK_TOKEN")]
     pub track_token: String,
-    #[serde(rename = "ART_PICTURE")]
+    #[serde(rename = "ART_PICTURE", default)]
     pub picture: String,
+    #[serde(rename = "ALB_PICTURE", default)]
+    pub alb_picture: String,
     #[serde(rename = "DURATION")]
     pub(crate) duration: String,
+    #[serde(rename = "TRACK_NUMBER", default)]
+    pub track_number: String,
+    #[serde(rename = "DISK_NUMBER", default)]
+    pub disk_number: String,
+}
+
+impl TrackPage {
+    /// Tags for the track, with the album's artists when its header is at
+    /// hand. The cover is left to the caller, see `Deezer::get_cover`.
+    pub fn tags(&self, album: Option<&AlbumHeader>) -> TrackTags {
+        let artists = |artists: &[Artist]| artists.iter().map(|a| a.name.clone()).collect::<Vec<_>>();
+
+        TrackTags {
+            title: self.sng_title.clone(),
+            artists: artists(&self.artists),
+            album: Some(self.alb_title.clone()),
+            album_artist: album.and_then(|album| album.artists.first()).map(|a| a.name.clone()),
+            track_number: self.track_number.parse().ok(),
+            disc_number: self.disk_number.parse().ok(),
+            duration_ms: self.duration.parse::<u64>().ok().map(|secs| secs * 1000),
+            cover: None,
+        }
+    }
 }
 
 
 
+/// What an account's subscription allows, from `USER.OPTIONS` of `deezer.getUserData`.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct UserOptions {
+    /// 320 kbps MP3.
+    #[serde(default)]
+    pub web_hq: bool,
+    /// FLAC.
+    #[serde(default)]
+    pub web_lossless: bool,
+    /// Country the catalog and licenses are resolved for.
+    #[serde(default)]
+    pub license_country: String,
+    /// Unix time the subscription ends, 0 when it doesn't.
+    #[serde(default)]
+    pub expiration_timestamp: i64,
+    #[serde(default)]
+    pub ads_display: bool,
+    #[serde(default)]
+    pub ads_audio: bool,
+}
+
+impl UserOptions {
+    pub fn can_stream(&self, format: SongFormat) -> bool {
+        match format {
+            SongFormat::Flac => self.web_lossless,
+            SongFormat::Mp3_320 => self.web_hq,
+            SongFormat::Mp3_128 => true,
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct AlbumSongs {
     pub data: Vec<TrackPage>,
@@ -140,91 +371,286 @@ pub struct Album{
     pub songs: AlbumSongs,
 }
 
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SearchAlbum {
+    #[serde(rename = "ALB_ID")]
+    pub alb_id: String,
+    #[serde(rename = "ALB_TITLE")]
+    pub alb_title: String,
+    #[serde(rename = "ALB_PICTURE", default)]
+    pub img: String,
+    #[serde(rename = "ARTISTS", default)]
+    pub artists: Vec<Artist>,
+    #[serde(rename = "NUMBER_TRACK", default)]
+    pub number_track: Option<String>,
+}
 
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PlaylistHeader {
+    #[serde(rename = "PLAYLIST_ID")]
+    pub playlist_id: String,
+    #[serde(rename = "TITLE")]
+    pub title: String,
+    #[serde(rename = "PLAYLIST_PICTURE", default)]
+    pub img: String,
+    #[serde(rename = "NB_SONG", default)]
+    pub nb_song: Option<u32>,
+    #[serde(rename = "PARENT_USERNAME", default)]
+    pub owner: Option<String>,
+}
 
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Playlist {
+    #[serde(rename = "DATA")]
+    pub playlist_header: PlaylistHeader,
+    #[serde(rename = "SONGS")]
+    pub songs: SearchSection<TrackPage>,
+}
 
-fn is_empty_array(val: &Value) -> bool {
-    val.as_array().map_or(false, |arr| arr.is_empty())
+const PLAYLIST_PAGE_SIZE: u32 = 1000;
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SearchSection<T> {
+    pub data: Vec<T>,
+    #[serde(default)]
+    pub total: u32,
+}
+
+impl<T> Default for SearchSection<T> {
+    fn default() -> Self {
+        Self { data: Vec::new(), total: 0 }
+    }
 }
 
-pub type ByteStream = Pin<Box<dyn Stream<Item = Result<bytes::Bytes, std::io::Error>> + Send>>;
+/// gw-light is inconsistent about numeric fields, some come as strings and some as numbers.
+fn string_or_number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::String(s) => s,
+        Value::Null => String::new(),
+        other => other.to_string(),
+    })
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ArtistHeader {
+    #[serde(rename = "ART_ID")]
+    pub id: String,
+    #[serde(rename = "ART_NAME")]
+    pub name: String,
+    #[serde(rename = "ART_PICTURE", default)]
+    pub picture: String,
+    #[serde(rename = "NB_FAN", default)]
+    pub nb_fan: u64,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReleaseType {
+    Single,
+    Album,
+    Compilation,
+    Ep,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct DiscographyAlbum {
+    #[serde(rename = "ALB_ID")]
+    pub alb_id: String,
+    #[serde(rename = "ALB_TITLE")]
+    pub alb_title: String,
+    #[serde(rename = "ALB_PICTURE", default)]
+    pub img: String,
+    #[serde(rename = "ART_ID")]
+    pub art_id: String,
+    #[serde(rename = "ART_NAME", default)]
+    pub art_name: String,
+    #[serde(rename = "ARTISTS", default)]
+    pub artists: Vec<Artist>,
+    #[serde(rename = "TYPE", deserialize_with = "string_or_number", default)]
+    pub release_type: String,
+    #[serde(rename = "DIGITAL_RELEASE_DATE", default)]
+    pub release_date: Option<String>,
+}
+
+impl DiscographyAlbum {
+    pub fn release_type(&self) -> ReleaseType {
+        match self.release_type.as_str() {
+            "0" => ReleaseType::Single,
+            "2" => ReleaseType::Compilation,
+            "3" => ReleaseType::Ep,
+            _ => ReleaseType::Album,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ArtistPage {
+    pub artist: ArtistHeader,
+    pub top_tracks: Vec<TrackPage>,
+    pub albums: Vec<DiscographyAlbum>,
+    pub singles: Vec<DiscographyAlbum>,
+    pub eps: Vec<DiscographyAlbum>,
+    pub related_artists: Vec<Artist>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ArtistPageResults {
+    #[serde(rename = "DATA")]
+    data: ArtistHeader,
+    #[serde(rename = "TOP", default)]
+    top: SearchSection<TrackPage>,
+    #[serde(rename = "RELATED_ARTISTS", default)]
+    related_artists: SearchSection<Artist>,
+}
+
+const DISCOGRAPHY_PAGE_SIZE: u32 = 100;
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct SearchResult {
+    #[serde(rename = "TRACK", default)]
+    pub tracks: SearchSection<TrackPage>,
+    #[serde(rename = "ALBUM", default)]
+    pub albums: SearchSection<SearchAlbum>,
+    #[serde(rename = "ARTIST", default)]
+    pub artists: SearchSection<Artist>,
+    #[serde(rename = "PLAYLIST", default)]
+    pub playlists: SearchSection<PlaylistHeader>,
+}
+
+
+
+
+fn is_empty_array(val: &Value) -> bool {
+    val.as_array().is_some_and(|arr| arr.is_empty())
+}
 
 #[derive(Clone)]
 pub struct Deezer {
-    api_token: Arc<RwLock<String>>,
-    license_token: Arc<RwLock<String>>,
-    client: Client,
-    token_update_lock: Arc<Mutex<()>>,
+    pool: Arc<AccountPool>,
 }
 
 pub type BlowfishCbcDec = cbc::Decryptor<Blowfish>;
 
 impl Deezer {
     pub fn new(
-        arl: String,
+        arls: Vec<String>,
     ) -> Self {
-        let jar = Arc::new(Jar::default());
-        let client = Client::builder().cookie_provider(Arc::clone(&jar)).build().unwrap();
-        let url = Url::parse("https://www.deezer.com").unwrap();
-
-        // Add a cookie to the jar
-        jar.add_cookie_str(format!("arl={}", arl).as_str(), &url);
         Self{
-            api_token: Arc::new(RwLock::new(String::new())),
-            license_token: Arc::new(RwLock::new(String::new())),
-            client,
-            token_update_lock: Arc::new(Mutex::new(())),
+            pool: Arc::new(AccountPool::new(&arls)),
+        }
+    }
+
+    pub fn accounts(&self) -> &AccountPool {
+        &self.pool
+    }
+
+    /// Subscription options of every account, loading them for accounts
+    /// that haven't fetched their tokens yet.
+    pub async fn account_info(&self) -> Vec<AccountInfo> {
+        let mut info = Vec::with_capacity(self.pool.len());
+
+        for account in self.pool.iter() {
+            if account.options.read().await.is_none() {
+                let current_token = account.api_token.read().await.clone();
+                if let Err(e) = Self::update_token(account, &current_token).await {
+                    eprintln!("Failed to load options of account {}: {}", account.index, e);
+                }
+            }
+
+            info.push(AccountInfo { index: account.index, options: account.options.read().await.clone() });
+        }
+
+        info
+    }
+
+    /// Runs `f` with a healthy account. When the account itself is the
+    /// problem (expired ARL, quota) it's put into cooldown and `f` is retried
+    /// with the next one, at most once per account.
+    async fn with_account<T, F, Fut>(&self, lossless: bool, f: F) -> Result<T, ApiError>
+    where
+        F: Fn(Arc<Account>) -> Fut,
+        Fut: Future<Output = Result<T, ApiError>>,
+    {
+        let mut last_error = ApiError::NoAccountAvailable;
+
+        for _ in 0..self.pool.len() {
+            let account = match self.pool.pick(lossless).await {
+                Ok(account) => account,
+                Err(e) if matches!(last_error, ApiError::NoAccountAvailable) => return Err(e),
+                Err(_) => break,
+            };
+
+            match f(account.clone()).await {
+                Err(e) if e.is_account_error() => {
+                    account.record_failure(&e);
+                    last_error = e;
+                }
+                result => {
+                    account.record_success();
+                    return result;
+                }
+            }
         }
+
+        Err(last_error)
     }
 
     // The "entry point" function that users will call.
     pub async fn call(&self, method: Method, method_endpoint: &str, is_api_token_req: bool, body: Option<String>) -> Result<Value, ApiError> {
+        self.with_account(false, |account| {
+            let (method, body) = (method.clone(), body.clone());
+            async move { Self::call_with(&account, method, method_endpoint, is_api_token_req, body).await }
+        }).await
+    }
+
+    async fn call_with(account: &Account, method: Method, method_endpoint: &str, is_api_token_req: bool, body: Option<String>) -> Result<Value, ApiError> {
         // First attempt
-        let result = self.make_request(method.clone(), method_endpoint, is_api_token_req, body.clone()).await;
+        let result = Self::make_request(account, method.clone(), method_endpoint, is_api_token_req, body.clone()).await;
 
         match result {
             Err(ApiError::TokenRequired(old_token)) => {
-                println!("Deezer API token is invalid or expired. Attempting to refresh.");
+                println!("Deezer API token of account {} is invalid or expired. Attempting to refresh.", account.index);
                 // The token is invalid, so we try to update it.
                 // The `update_token` function will acquire a lock to prevent multiple concurrent updates.
-                self.update_token(&old_token).await?;
+                // A token that can't be refreshed means the ARL itself is no good.
+                if let Err(e) = Self::update_token(account, &old_token).await {
+                    eprintln!("Failed to refresh token of account {}: {}", account.index, e);
+                    return Err(ApiError::TokenRequired(old_token));
+                }
                 println!("Token refreshed successfully. Retrying original request for '{}'.", method_endpoint);
 
                 // Retry the request with the new token.
-                self.make_request(method, method_endpoint, is_api_token_req, body).await
+                Self::make_request(account, method, method_endpoint, is_api_token_req, body).await
             }
             // For any other error or success, just return the result.
             _ => result,
         }
     }
 
-    async fn create_req_url_body(&self, track_tokens: &[&str]) -> String {
+    async fn create_req_url_body(account: &Account, track_tokens: &[&str], formats: &[SongFormat]) -> String {
         let license_token = {
-            let guard = self.license_token.read().await;
+            let guard = account.license_token.read().await;
             guard.clone()
         };
 
-        let mut media: Vec<&str> = Vec::new();
-
-        for _ in 0..track_tokens.len() {
-            media.push(MEDIA_EL);
-        }
-
-        let media =  media.join(", ");
-        let track_tokens = track_tokens.join("\", \"");
-
-        format!("
-            {{\
-                \"license_token\": \"{license_token}\",\
-                \"media\": [ {media} ],
-                \"track_tokens\": [ \"{track_tokens}\" ]
-            }}
-        ")
+        let formats: Vec<Value> = formats.iter()
+            .map(|format| serde_json::json!({
+                "cipher": "BF_CBC_STRIPE",
+                "format": format.api_name(),
+            }))
+            .collect();
+        let media = vec![serde_json::json!({ "type": "FULL", "formats": formats }); track_tokens.len()];
+
+        serde_json::json!({
+            "license_token": license_token,
+            "media": media,
+            "track_tokens": track_tokens,
+        }).to_string()
     }
 
     // The internal, recursive implementation.
     async fn make_request<'a>(
-        &'a self,
+        account: &'a Account,
         method: Method,
         method_endpoint: &'a str,
         is_api_token_req: bool,
@@ -241,49 +667,47 @@ impl Deezer {
             ("api_token", ""), // Placeholder for the token
         ];
 
-        println!("0 is_api_token_req: {}", is_api_token_req);
-
-
         if is_api_token_req {
-            token = self.api_token.read().await.clone();
+            token = account.api_token.read().await.clone();
             // We need to ensure the borrowed `token` string lives long enough.
             // It's already cloned, so it's owned by this stack frame, which is fine.
             params[3] = ("api_token", &token);
         }
 
         let url_p = Url::parse_with_params(BASE_URL, params)?; // Pass a slice reference
-        let mut req_builder = self.client.request(method.clone(), url_p.clone());
+        let mut req_builder = account.client.request(method.clone(), url_p.clone());
 
         if let Some(some_body) = body.clone() {
             req_builder = req_builder.body(some_body);
         }
         let req = req_builder.build()?;
 
-        let res: Value = self.client.execute(req).await?.json().await?;
-        // println!("{:?}", res);
+        let res: Value = account.client.execute(req).await?.json().await?;
 
-        if let Some(error) = res.get("error") {
-            if !is_empty_array(&error) {
-                if error.get("VALID_TOKEN_REQUIRED").is_some() {
-                    // Refresh token, THEN make the recursive call.
-                    return Err(ApiError::TokenRequired(token));
-                }
-                println!("{}", url_p);
-                if let Some(body) = body{
-                    println!("{}", body);
-
-                }
-                return Err(ApiError::ApiError(error.clone()));
+        if let Some(error) = res.get("error") && !is_empty_array(error) {
+            if error.get("VALID_TOKEN_REQUIRED").is_some() {
+                // Refresh token, THEN make the recursive call.
+                return Err(ApiError::TokenRequired(token));
             }
+            // The URL carries the api_token and the body track tokens, only
+            // log the method.
+            eprintln!("{} failed", method_endpoint);
+            return Err(ApiError::ApiError(error.clone()));
         }
 
         Ok(res)
     }
 
     pub async fn get_track_page(&self, id: &str) -> Result<TrackPage, ApiError> {
+        self.with_account(false, |account| async move { Self::get_track_page_with(&account, id).await }).await
+    }
+
+    /// The track token in the page only works with the account that fetched it.
+    async fn get_track_page_with(account: &Account, id: &str) -> Result<TrackPage, ApiError> {
         let req_body = format!("{{\"sng_id\": \"{}\", \"start_with_input_track\": true}}", id);
 
-        let res = self.call(
+        let res = Self::call_with(
+            account,
             Method::POST,
             "deezer.pageTrack",
             true,
@@ -314,36 +738,188 @@ impl Deezer {
         Ok(serde_json::from_value::<Album>(res.to_owned())?)
     }
 
-    pub async fn get_track_url(&self, track_token: &str) -> Result<String, ApiError> {
-        // We will now correctly handle the Result from this function
-        let track_req_body = self.create_req_url_body(&[track_token]).await;
+    /// Loads the playlist page and then pages through `playlist.getSongs` until
+    /// every track is loaded, `deezer.pagePlaylist` alone stops at the page size.
+    pub async fn get_playlist(&self, id: &str) -> Result<Playlist, ApiError> {
+        let req_body = serde_json::json!({
+            "playlist_id": id,
+            "lang": "en",
+            "nb": PLAYLIST_PAGE_SIZE,
+            "start": 0,
+            "tab": 0,
+            "tags": true,
+            "header": true,
+        });
 
-        println!("Request Body Sent to media.deezer.com:\n{}", track_req_body);
+        let res = self.call(Method::POST, "deezer.pagePlaylist", true, Some(req_body.to_string())).await?;
+        let res = res.get("results").ok_or_else(|| ApiError::ApiError(serde_json::json!({
+            "error": "Unable to parse data from playlist page"
+        })))?;
+        let mut playlist: Playlist = serde_json::from_value(res.to_owned())?;
+
+        while (playlist.songs.data.len() as u32) < playlist.songs.total {
+            let req_body = serde_json::json!({
+                "playlist_id": id,
+                "start": playlist.songs.data.len(),
+                "nb": PLAYLIST_PAGE_SIZE,
+            });
+
+            let res = self.call(Method::POST, "playlist.getSongs", true, Some(req_body.to_string())).await?;
+            let res = res.get("results").ok_or_else(|| ApiError::ApiError(serde_json::json!({
+                "error": "Unable to parse data from playlist songs"
+            })))?;
+            let page: SearchSection<TrackPage> = serde_json::from_value(res.to_owned())?;
+
+            if page.data.is_empty() {
+                break;
+            }
+            playlist.songs.data.extend(page.data);
+        }
+
+        Ok(playlist)
+    }
 
-        // --- FIX 1: Manually add the arl cookie to the request ---
-        // This is the most direct way to ensure authentication with the media subdomain.
-        let jar = Arc::new(Jar::default());
-        let url = "https://www.deezer.com".parse::<Url>().unwrap();
+    pub async fn get_artist(&self, id: &str) -> Result<ArtistPage, ApiError> {
+        let req_body = serde_json::json!({
+            "art_id": id,
+            "lang": "en",
+            "tab": 0,
+        });
+
+        let (page, discography) = tokio::join!(
+            self.call(Method::POST, "deezer.pageArtist", true, Some(req_body.to_string())),
+            self.get_discography(id)
+        );
+
+        let page = page?;
+        let page = page.get("results").ok_or_else(|| ApiError::ApiError(serde_json::json!({
+            "error": "Unable to parse data from artist page"
+        })))?;
+        let page: ArtistPageResults = serde_json::from_value(page.to_owned())?;
+
+        let mut artist_page = ArtistPage {
+            artist: page.data,
+            top_tracks: page.top.data,
+            albums: Vec::new(),
+            singles: Vec::new(),
+            eps: Vec::new(),
+            related_artists: page.related_artists.data,
+        };
+
+        for album in discography? {
+            match album.release_type() {
+                ReleaseType::Single => artist_page.singles.push(album),
+                ReleaseType::Ep => artist_page.eps.push(album),
+                ReleaseType::Album | ReleaseType::Compilation => artist_page.albums.push(album),
+            }
+        }
+
+        Ok(artist_page)
+    }
+
+    /// Every release where the artist is credited as a main artist, paged
+    /// through `album.getDiscography` until `total` is reached.
+    pub async fn get_discography(&self, id: &str) -> Result<Vec<DiscographyAlbum>, ApiError> {
+        let mut releases: Vec<DiscographyAlbum> = Vec::new();
+        let mut start = 0;
+
+        loop {
+            let req_body = serde_json::json!({
+                "art_id": id,
+                "discography_mode": "all",
+                "nb": DISCOGRAPHY_PAGE_SIZE,
+                "nb_songs": 0,
+                "start": start,
+            });
+
+            let res = self.call(Method::POST, "album.getDiscography", true, Some(req_body.to_string())).await?;
+            let res = res.get("results").ok_or_else(|| ApiError::ApiError(serde_json::json!({
+                "error": "Unable to parse data from artist discography"
+            })))?;
+            let page: SearchSection<DiscographyAlbum> = serde_json::from_value(res.to_owned())?;
+
+            let fetched = page.data.len() as u32;
+            releases.extend(page.data.into_iter().filter(|album| {
+                album.art_id == id || album.artists.iter().any(|artist| artist.id == id)
+            }));
+
+            start += fetched;
+            if fetched == 0 || start >= page.total {
+                break;
+            }
+        }
+
+        Ok(releases)
+    }
+
+    /// Searches the catalog. Without `kind` every section comes from a single
+    /// `deezer.pageSearch`, with it only that section is paged via `search.music`.
+    pub async fn search(&self, query: &str, kind: Option<SearchKind>, start: u32, nb: u32) -> Result<SearchResult, ApiError> {
+        let Some(kind) = kind else {
+            let req_body = serde_json::json!({
+                "query": query,
+                "start": start,
+                "nb": nb,
+                "suggest": true,
+                "artist_suggest": true,
+                "top_tracks": true,
+            });
+
+            let res = self.call(Method::POST, "deezer.pageSearch", true, Some(req_body.to_string())).await?;
+            let res = res.get("results").ok_or_else(|| ApiError::ApiError(serde_json::json!({
+                "error": "Unable to parse data from search page"
+            })))?;
+
+            return Ok(serde_json::from_value::<SearchResult>(res.to_owned())?);
+        };
+
+        let output = match kind {
+            SearchKind::Track => "TRACK",
+            SearchKind::Album => "ALBUM",
+            SearchKind::Artist => "ARTIST",
+            SearchKind::Playlist => "PLAYLIST",
+        };
+        let req_body = serde_json::json!({
+            "query": query,
+            "filter": "ALL",
+            "output": output,
+            "start": start,
+            "nb": nb,
+        });
+
+        let res = self.call(Method::POST, "search.music", true, Some(req_body.to_string())).await?;
+        let res = res.get("results").ok_or_else(|| ApiError::ApiError(serde_json::json!({
+            "error": "Unable to parse data from search results"
+        })))?.to_owned();
+
+        let mut result = SearchResult::default();
+        match kind {
+            SearchKind::Track => result.tracks = serde_json::from_value(res)?,
+            SearchKind::Album => result.albums = serde_json::from_value(res)?,
+            SearchKind::Artist => result.artists = serde_json::from_value(res)?,
+            SearchKind::Playlist => result.playlists = serde_json::from_value(res)?,
+        }
+
+        Ok(result)
+    }
 
+    /// Asks for the first of `formats` (best first) the account can serve for this track.
+    async fn get_track_url(account: &Account, track_token: &str, formats: &[SongFormat]) -> Result<TrackUrl, ApiError> {
+        // We will now correctly handle the Result from this function
+        let track_req_body = Self::create_req_url_body(account, &[track_token], formats).await;
 
         // Build the request
-        let request = self.client.post("https://media.deezer.com/v1/get_url")
+        let request = account.client.post(MEDIA_URL)
             // --- FIX 2: Add Content-Type Header ---
             .header("Content-Type", "application/json")
             .body(track_req_body)
             .build()?;
 
-        println!("Executing request to: {}", request.url());
-        println!("With headers: {:?}", request.headers());
-
-        // --- DEBUGGING: Get the raw response first ---
-        let response = self.client.execute(request).await?;
+        // The raw text is kept for the error below.
+        let response = account.client.execute(request).await?;
         let status = response.status();
         let response_text = response.text().await?;
 
-        println!("Response Status: {}", status);
-        println!("Response Body:\n{}", response_text);
-
         // Now, if the status was successful, parse the text
         if !status.is_success() {
             return Err(ApiError::ApiError(serde_json::json!({
@@ -355,13 +931,18 @@ impl Deezer {
 
         let res: Value = serde_json::from_str(&response_text)?;
 
-        // Your original parsing logic
-        let url = res
-            .get("data")
-            .and_then(|t| t.get(0))
+        let track_data = res.get("data").and_then(|t| t.get(0));
+        if let Some(errors) = track_data.and_then(|t| t.get("errors")) {
+            return Err(ApiError::ApiError(errors.clone()));
+        }
+
+        let media = track_data
             .and_then(|t| t.get("media"))
             .and_then(|t| t.get(0))
-            .and_then(|t| t.get("sources"))
+            .ok_or_else(|| ApiError::FormatUnavailable(formats.iter().map(SongFormat::api_name).collect()))?;
+
+        let url = media
+            .get("sources")
             .and_then(|t| t.get(0))
             .and_then(|t| t.get("url"))
             .and_then(Value::as_str)
@@ -372,56 +953,108 @@ impl Deezer {
             }))
             })?;
 
-        Ok(url.to_string())
+        let format = media
+            .get("format")
+            .and_then(Value::as_str)
+            .and_then(SongFormat::from_api_name)
+            .ok_or_else(|| {
+                ApiError::ApiError(serde_json::json!({
+                "error": "Unknown media format in the response JSON",
+                "res": res,
+            }))
+            })?;
+
+        Ok(TrackUrl { url: url.to_string(), format })
     }
 
-    pub async fn get_stream(&self, id: String, track_token: Option<String>) -> Result<ReceiverStream<Result<Vec<u8>, ApiError>>, ApiError> {
+    /// Starts downloading and decrypting a track. With a `range` the download
+    /// starts on the stripe containing its first byte, so the stripe index (and
+    /// with it which stripes are encrypted) stays correct, and the output is
+    /// trimmed to exactly the requested bytes.
+    pub async fn get_stream(&self, id: String, formats: &[SongFormat], range: Option<ByteRange>) -> Result<TrackStream, ApiError> {
         let key = Self::generate_blowfish_key(&id);
 
         let (tx, rx) = mpsc::channel(8);
 
         let stream: ReceiverStream<Result<Vec<u8>, ApiError>> = ReceiverStream::new(rx);
-        let self_s = self.clone();
-        let token = match track_token {
-            Some(url) => url,
-            None => self.get_track_page(&id).await?.track_token
+        // Only HiFi accounts get FLAC urls, prefer them when FLAC is asked for.
+        let lossless = formats.first() == Some(&SongFormat::Flac);
+        let (client, track_url, page) = self.with_account(lossless, |account| {
+            let id = &id;
+            async move {
+                let page = Self::get_track_page_with(&account, id).await?;
+                // Fetching the page loaded the account's options, refuse what
+                // its subscription doesn't cover before asking for urls.
+                let formats = account.playable_formats(formats).await?;
+                let track_url = Self::get_track_url(&account, &page.track_token, &formats).await?;
+                Ok((account.client.clone(), track_url, page))
+            }
+        }).await?;
+
+        let (start, end) = match range {
+            None => (0, None),
+            Some(ByteRange::FromTo(start, end)) => (start, Some(end)),
+            Some(ByteRange::From(start)) => (start, None),
+            Some(suffix @ ByteRange::Suffix(_)) => {
+                let total = client.head(&track_url.url).send().await?.content_length()
+                    .ok_or(ApiError::RangeNotSatisfiable)?;
+                let (start, end) = suffix.resolve(total).ok_or(ApiError::RangeNotSatisfiable)?;
+                (start, Some(end))
+            }
         };
 
-        tokio::spawn(async move {
-            let mut download_stream = match self_s.download_by_token(&token).await {
-                Ok(stream) => stream,
-                Err(e) => {
-                    let _ = tx.send(Err(e)).await;
-                    return;
+        let (aligned_start, aligned_end) = aligned_range(start, end);
+        let mut request = client.get(&track_url.url);
+        if range.is_some() {
+            let aligned_end = aligned_end.map(|end| end.to_string()).unwrap_or_default();
+            request = request.header(RANGE, format!("bytes={}-{}", aligned_start, aligned_end));
+        }
+
+        let response = request.send().await?;
+        if response.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+            return Err(ApiError::RangeNotSatisfiable);
+        }
+        let response = response.error_for_status()?;
+
+        // A CDN that ignores the range answers 200 with the whole file.
+        let (download_start, total_len) = if response.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+            let total = response.headers().get(CONTENT_RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(range::total_from_content_range);
+            (aligned_start, total)
+        } else {
+            (0, response.content_length())
+        };
+
+        let served_range = match (range, total_len) {
+            (None, _) => None,
+            (Some(_), Some(total)) => {
+                let end = end.unwrap_or(u64::MAX).min(total.saturating_sub(1));
+                if start > end {
+                    return Err(ApiError::RangeNotSatisfiable);
                 }
-            };
+                Some((start, end))
+            }
+            (Some(_), None) => return Err(ApiError::RangeNotSatisfiable),
+        };
 
-            let mut byte_buffer: Vec<u8> = Vec::with_capacity(2048 * 4);
-            let mut total_bytes_sent = 0usize;
-            let iv = [0, 1, 2, 3, 4, 5, 6, 7];
+        let skip = (start - download_start) as usize;
+        let remaining = served_range.map(|(start, end)| end - start + 1);
+        let mut decryptor = StripeDecryptor::new(key, download_start / STRIPE_SIZE, skip, remaining);
+
+        tokio::spawn(async move {
+            let mut download_stream = response.bytes_stream();
 
             while let Some(chunk_result) = download_stream.next().await {
                 match chunk_result {
                     Ok(network_chunk) => {
-                        byte_buffer.extend_from_slice(&network_chunk);
-
-                        while byte_buffer.len() >= 2048 {
-                            let mut segment = byte_buffer.drain(..2048).collect::<Vec<u8>>();
-
-                            let segment_index = total_bytes_sent / 2048;
-                            if segment_index > 0 && segment_index % 3 == 0 {
-                                let mut cbc_decryptor = BlowfishCbcDec::new_from_slices(&key, &iv)
-                                  .expect("Failed to create CBC decryptor");
-
-                                for block in segment.chunks_mut(8) {
-                                    cbc_decryptor.decrypt_block_mut(block.into());
-                                }
-                            }
-
+                        for segment in decryptor.push(&network_chunk) {
                             if tx.send(Ok(segment)).await.is_err() {
                                 return;
                             }
-                            total_bytes_sent += 2048;
+                        }
+                        if decryptor.is_done() {
+                            return;
                         }
                     }
                     Err(e) => {
@@ -431,26 +1064,37 @@ impl Deezer {
                 }
             }
 
-            if !byte_buffer.is_empty() {
-                let _ = tx.send(Ok(byte_buffer)).await;
+            let rest = decryptor.finish();
+            if !rest.is_empty() {
+                let _ = tx.send(Ok(rest)).await;
             }
         });
 
-        Ok(stream)
+        Ok(TrackStream { format: track_url.format, total_len, range: served_range, stream, page })
     }
-    pub async fn download(&self, id: &str) -> Result<BoxStream<reqwest::Result<Bytes>>, ApiError> {
-        let track_page = self.get_track_page(id).await?;
-        
-        let track_url = self.get_track_url(&track_page.track_token).await?;
-        println!("Got url: {:?}", track_url);
-        
-        let res = self.client.get(&track_url).send().await?.bytes_stream().boxed();
-        Ok(res)
+
+    /// Album cover as embedded in tags, `None` when there is none or it
+    /// couldn't be fetched.
+    pub async fn get_cover(&self, hash: &str) -> Option<Picture> {
+        let url = picture_url("cover", hash)?;
+        let res = async {
+            let account = self.pool.pick(false).await?;
+            let data = account.client.get(&url).send().await?.error_for_status()?.bytes().await?;
+            Ok::<_, ApiError>(data)
+        }.await;
+
+        match res {
+            Ok(data) => Some(Picture { mime_type: "image/jpeg".to_owned(), data }),
+            Err(e) => {
+                eprintln!("Failed to fetch cover {}: {}", hash, e);
+                None
+            }
+        }
     }
 
-    pub async fn download_by_token(&self, token: &str) -> Result<BoxStream<reqwest::Result<Bytes>>, ApiError> {
-        let track_url = self.get_track_url(token).await?;
-        let res = self.client.get(track_url).send().await?.bytes_stream().boxed();
+    pub async fn download_by_url(&self, url: &str) -> Result<BoxStream<'_, reqwest::Result<Bytes>>, ApiError> {
+        let account = self.pool.pick(false).await?;
+        let res = account.client.get(url).send().await?.bytes_stream().boxed();
         Ok(res)
     }
     
@@ -471,11 +1115,11 @@ impl Deezer {
         bf_key
     }
 
-    pub async fn update_token(&self, old_token: &str) -> Result<(), ApiError> {
-        let _lock_guard = self.token_update_lock.lock().await;
+    async fn update_token(account: &Account, old_token: &str) -> Result<(), ApiError> {
+        let _lock_guard = account.token_update_lock.lock().await;
 
         {
-            let current_token = self.api_token.read().await;
+            let current_token = account.api_token.read().await;
             if *current_token != old_token {
                 // The token was already updated by another thread. Our work is done.
                 println!("Token was already updated by another process before we started. No action needed.");
@@ -483,10 +1127,8 @@ impl Deezer {
             }
         }
 
-        let user_data = self.make_request(Method::GET, "deezer.getUserData", false, None).await?;
+        let user_data = Self::make_request(account, Method::GET, "deezer.getUserData", false, None).await?;
 
-        println!("User Data: {:?}", user_data);
-        
         let new_api_token = user_data
             .get("results")
             .and_then(|t| t.get("checkForm"))
@@ -509,10 +1151,17 @@ impl Deezer {
                         }))
             })?.to_owned();
 
+        let options = user_data
+            .get("results")
+            .and_then(|t| t.get("USER"))
+            .and_then(|t| t.get("OPTIONS"))
+            .map(|t| serde_json::from_value::<UserOptions>(t.clone()))
+            .transpose()?;
+
         println!("Successfully fetched new token.");
 
-        let mut token_guard = self.api_token.write().await;
-        let mut license_token_guard = self.license_token.write().await;
+        let mut token_guard = account.api_token.write().await;
+        let mut license_token_guard = account.license_token.write().await;
 
         if *token_guard != new_api_token {
             println!("Token in memory is the old one, updating...");
@@ -522,7 +1171,285 @@ impl Deezer {
         }
 
         *license_token_guard = new_license_token;
+        *account.options.write().await = options;
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+const IMAGES_URL: &str = "https://e-cdns-images.dzcdn.net/images";
+
+fn picture_url(kind: &str, hash: &str) -> Option<String> {
+    if hash.is_empty() {
+        return None;
+    }
+    Some(format!("{IMAGES_URL}/{kind}/{hash}/500x500-000000-80-0-0.jpg"))
+}
+
+impl From<&Artist> for core::Artist {
+    fn from(value: &Artist) -> Self {
+        Self {
+            id: value.id.clone(),
+            name: value.name.clone(),
+            picture: picture_url("artist", &value.picture),
+        }
+    }
+}
+
+impl From<&TrackPage> for core::Track {
+    fn from(value: &TrackPage) -> Self {
+        Self {
+            id: value.id.clone(),
+            title: value.sng_title.clone(),
+            artists: value.artists.iter().map(core::Artist::from).collect(),
+            album_id: Some(value.alb_id.clone()),
+            album_title: Some(value.alb_title.clone()),
+            duration_ms: value.duration.parse::<u64>().unwrap_or(0) * 1000,
+            artwork: picture_url("cover", &value.alb_picture),
+        }
+    }
+}
+
+impl From<&Album> for core::Album {
+    fn from(value: &Album) -> Self {
+        let header = &value.album_header;
+        Self {
+            id: header.alb_id.clone(),
+            title: header.alb_title.clone(),
+            artists: header.artists.iter().map(core::Artist::from).collect(),
+            artwork: picture_url("cover", &header.img),
+            tracks: value.songs.data.iter().map(core::Track::from).collect(),
+        }
+    }
+}
+
+impl From<&SearchAlbum> for core::Album {
+    fn from(value: &SearchAlbum) -> Self {
+        Self {
+            id: value.alb_id.clone(),
+            title: value.alb_title.clone(),
+            artists: value.artists.iter().map(core::Artist::from).collect(),
+            artwork: picture_url("cover", &value.img),
+            tracks: Vec::new(),
+        }
+    }
+}
+
+impl From<&PlaylistHeader> for core::Playlist {
+    fn from(value: &PlaylistHeader) -> Self {
+        Self {
+            id: value.playlist_id.clone(),
+            title: value.title.clone(),
+            owner: None,
+            artwork: picture_url("playlist", &value.img),
+            tracks: Vec::new(),
+        }
+    }
+}
+
+impl From<&Playlist> for core::Playlist {
+    fn from(value: &Playlist) -> Self {
+        let mut playlist = core::Playlist::from(&value.playlist_header);
+        playlist.tracks = value.songs.data.iter().map(core::Track::from).collect();
+        playlist
+    }
+}
+
+impl From<ApiError> for ProviderError {
+    fn from(value: ApiError) -> Self {
+        match value {
+            ApiError::NotFound(what) => ProviderError::NotFound(what),
+            ApiError::ApiError(error) if error.get("DATA_ERROR").is_some() => ProviderError::NotFound(error.to_string()),
+            e => ProviderError::Upstream(e.to_string()),
+        }
+    }
+}
+
+#[async_trait]
+impl MusicProvider for Deezer {
+    fn name(&self) -> &'static str {
+        "deezer"
+    }
+
+    async fn search(&self, query: &SearchQuery) -> Result<SearchResults, ProviderError> {
+        let res = self.search(&query.q, query.kind, query.offset, query.limit).await?;
+
+        Ok(SearchResults {
+            tracks: res.tracks.data.iter().map(core::Track::from).collect(),
+            albums: res.albums.data.iter().map(core::Album::from).collect(),
+            artists: res.artists.data.iter().map(core::Artist::from).collect(),
+            playlists: res.playlists.data.iter().map(core::Playlist::from).collect(),
+        })
+    }
+
+    async fn track(&self, id: &str) -> Result<core::Track, ProviderError> {
+        Ok(core::Track::from(&self.get_track_page(id).await?))
+    }
+
+    async fn album(&self, id: &str) -> Result<core::Album, ProviderError> {
+        Ok(core::Album::from(&self.get_album(id.to_owned()).await?))
+    }
+
+    async fn playlist(&self, id: &str) -> Result<core::Playlist, ProviderError> {
+        Ok(core::Playlist::from(&self.get_playlist(id).await?))
+    }
+
+    async fn stream(&self, id: &str) -> Result<AudioStream, ProviderError> {
+        let track_stream = self.get_stream(id.to_owned(), &SongFormat::ALL, None).await?;
+        let body = track_stream.stream
+            .map_ok(Bytes::from)
+            .map_err(std::io::Error::other)
+            .boxed();
+
+        Ok(AudioStream { format: track_stream.format.audio_format(), body })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cbc::cipher::BlockEncryptMut;
+    use std::sync::Mutex as StdMutex;
+
+    const STRIPE: usize = STRIPE_SIZE as usize;
+
+    /// A plain file of `len` bytes and the same file as Deezer serves it,
+    /// with every third stripe encrypted.
+    fn track(key: &[u8; 16], len: usize) -> (Vec<u8>, Vec<u8>) {
+        let plain: Vec<u8> = (0..len).map(|i| (i * 7 % 251) as u8).collect();
+        let mut encrypted = plain.clone();
+        for (i, stripe) in encrypted.chunks_mut(STRIPE).enumerate() {
+            if i % 3 == 0 && stripe.len() == STRIPE {
+                let mut encryptor = cbc::Encryptor::<Blowfish>::new_from_slices(key, &[0, 1, 2, 3, 4, 5, 6, 7]).unwrap();
+                for block in stripe.chunks_mut(8) {
+                    encryptor.encrypt_block_mut(block.into());
+                }
+            }
+        }
+        (plain, encrypted)
+    }
+
+    /// Decrypts bytes `start..=end` like `get_stream`, from a download fed in
+    /// `chunk_len` sized chunks.
+    fn decrypt(key: [u8; 16], encrypted: &[u8], start: u64, end: u64, chunk_len: usize) -> Vec<u8> {
+        let (aligned_start, aligned_end) = aligned_range(start, Some(end));
+        let download_end = (aligned_end.unwrap() as usize + 1).min(encrypted.len());
+        let download = &encrypted[aligned_start as usize..download_end];
+
+        let mut decryptor = StripeDecryptor::new(key, aligned_start / STRIPE_SIZE, (start - aligned_start) as usize, Some(end - start + 1));
+        let mut out = Vec::new();
+        for chunk in download.chunks(chunk_len) {
+            out.extend(decryptor.push(chunk).concat());
+            if decryptor.is_done() {
+                return out;
+            }
+        }
+        out.extend(decryptor.finish());
+        out
+    }
+
+    #[test]
+    fn ranges_align_to_stripes() {
+        assert_eq!(aligned_range(0, None), (0, None));
+        assert_eq!(aligned_range(2047, Some(2048)), (0, Some(4095)));
+        assert_eq!(aligned_range(2048, Some(2048)), (2048, Some(4095)));
+        assert_eq!(aligned_range(5000, Some(10_000)), (4096, Some(10_239)));
+    }
+
+    #[test]
+    fn whole_track_decrypts_every_third_stripe_from_the_first() {
+        let key = Deezer::generate_blowfish_key("3135556");
+        // Seven stripes and a partial one, which is never encrypted.
+        let (plain, encrypted) = track(&key, STRIPE * 7 + 1000);
+        assert_ne!(plain[..STRIPE], encrypted[..STRIPE]);
+        assert_eq!(plain[STRIPE..STRIPE * 3], encrypted[STRIPE..STRIPE * 3]);
+
+        let mut decryptor = StripeDecryptor::new(key, 0, 0, None);
+        let mut out = Vec::new();
+        for chunk in encrypted.chunks(1500) {
+            out.extend(decryptor.push(chunk).concat());
+        }
+        out.extend(decryptor.finish());
+        assert_eq!(out, plain);
+    }
+
+    #[test]
+    fn unaligned_ranges_decrypt_the_right_stripes() {
+        let key = Deezer::generate_blowfish_key("3135556");
+        let (plain, encrypted) = track(&key, STRIPE * 7 + 1000);
+        let last = plain.len() as u64 - 1;
+
+        for (start, end) in [
+            // Within stripe 0, which is encrypted.
+            (10, 100),
+            // From plain stripe 1 into encrypted stripe 3.
+            (STRIPE as u64 + 100, STRIPE as u64 * 3 + 50),
+            // Starting inside encrypted stripe 3.
+            (STRIPE as u64 * 3 + 1, STRIPE as u64 * 5),
+            // Into the partial stripe at the end.
+            (STRIPE as u64 * 6 + 7, last),
+        ] {
+            for chunk_len in [1000, STRIPE, 5000] {
+                let out = decrypt(key, &encrypted, start, end, chunk_len);
+                assert_eq!(out, plain[start as usize..=end as usize], "{}-{} in {} byte chunks", start, end, chunk_len);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn with_account_retries_account_errors_on_the_next_account() {
+        let deezer = Deezer::new(vec!["a".into(), "b".into(), "c".into()]);
+        let tried = StdMutex::new(Vec::new());
+
+        let result = deezer.with_account(false, |account| {
+            tried.lock().unwrap().push(account.index);
+            async move {
+                match account.index {
+                    0 => Err(ApiError::TokenRequired(String::new())),
+                    _ => Ok(account.index),
+                }
+            }
+        }).await;
+
+        assert_eq!(result.unwrap(), 1);
+        assert_eq!(*tried.lock().unwrap(), [0, 1]);
+        let status = deezer.pool.status().await;
+        assert!(!status[0].available && status[0].consecutive_failures == 1);
+        assert!(status[1].available);
+    }
+
+    #[tokio::test]
+    async fn with_account_returns_other_errors_without_cooling_down() {
+        let deezer = Deezer::new(vec!["a".into(), "b".into()]);
+        let tried = StdMutex::new(0);
+
+        let result: Result<(), ApiError> = deezer.with_account(false, |_| {
+            *tried.lock().unwrap() += 1;
+            async { Err(ApiError::NotFound("track".into())) }
+        }).await;
+
+        assert!(matches!(result, Err(ApiError::NotFound(_))));
+        assert_eq!(*tried.lock().unwrap(), 1);
+        assert!(deezer.pool.status().await.iter().all(|s| s.available));
+    }
+
+    #[tokio::test]
+    async fn with_account_tries_every_account_once_then_gives_up() {
+        let deezer = Deezer::new(vec!["a".into(), "b".into(), "c".into()]);
+        let tried = StdMutex::new(Vec::new());
+
+        let result: Result<(), ApiError> = deezer.with_account(false, |account| {
+            tried.lock().unwrap().push(account.index);
+            async { Err(ApiError::TokenRequired("expired".into())) }
+        }).await;
+
+        assert!(matches!(result, Err(ApiError::TokenRequired(token)) if token == "expired"));
+        let mut tried = tried.into_inner().unwrap();
+        tried.sort();
+        assert_eq!(tried, [0, 1, 2]);
+
+        // Everything is cooling down now, so nothing is even attempted.
+        let result: Result<(), ApiError> = deezer.with_account(false, |_| async { unreachable!() }).await;
+        assert!(matches!(result, Err(ApiError::NoAccountAvailable)));
+    }
+}
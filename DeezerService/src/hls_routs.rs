@@ -0,0 +1,152 @@
+use axum::body::Body;
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderValue, Response};
+use bytes::Bytes;
+use music_core::hls::{self, Mp3Layout, Variant};
+use music_core::range::{self, ByteRange};
+use crate::deezer::{ApiError, SongFormat};
+use crate::private_api_routs::{cache_track, record_listening, StreamParams};
+use music_core::sessions::Authenticated;
+use crate::SharedState;
+
+/// Deezer's MP3s are all 44.1 kHz.
+const SAMPLE_RATE: u32 = 44100;
+/// Enough of the file to skip its ID3v2 tag.
+const HEAD_LEN: u64 = 10;
+
+/// Bytes `start..=end` of the cached track and its total length, `None`
+/// when it isn't cached.
+async fn read_range(state: &SharedState, id: &str, format: SongFormat, start: u64, end: u64) -> Result<Option<(Bytes, u64)>, ApiError> {
+    let Some((_, file)) = state.s3.try_get_song(id, &[format], Some(ByteRange::FromTo(start, end))).await? else {
+        return Ok(None);
+    };
+    let total = file.content_range()
+        .and_then(range::total_from_content_range)
+        .ok_or_else(|| ApiError::StorageError(format!("No length for cached track {}", id)))?;
+    let data = file.body.collect().await.map_err(|e| ApiError::StorageError(e.to_string()))?;
+    Ok(Some((data.into_bytes(), total)))
+}
+
+/// The head of the track in `format`. A track that isn't cached yet is
+/// fetched into the cache first, so Deezer is asked for it once instead of
+/// for every segment. Concurrent requests for it wait for that fetch.
+async fn read_head(state: &SharedState, id: &str, format: SongFormat) -> Result<(Bytes, u64), ApiError> {
+    if let Some(head) = read_range(state, id, format, 0, HEAD_LEN - 1).await? {
+        return Ok(head);
+    }
+
+    let key = format!("{}/{}", id, format.api_name());
+    let fill = state.cache_fills.lock().unwrap().entry(key.clone()).or_default().clone();
+    let fill = fill.lock_owned().await;
+
+    // Another request may have cached it while this one waited.
+    if let Some(head) = read_range(state, id, format, 0, HEAD_LEN - 1).await? {
+        return Ok(head);
+    }
+    // Spawned, so the fetch goes on and others keep waiting for it even if
+    // this request goes away.
+    let task = tokio::spawn({
+        let (state, id) = (state.clone(), id.to_owned());
+        async move {
+            let res = cache_track(&state, &id, format).await;
+            state.cache_fills.lock().unwrap().remove(&key);
+            drop(fill);
+            res
+        }
+    });
+    task.await.map_err(|e| ApiError::StorageError(e.to_string()))??;
+
+    read_range(state, id, format, 0, HEAD_LEN - 1).await?
+        .ok_or_else(|| ApiError::StorageError(format!("Track {} isn't cached as {}", id, format.api_name())))
+}
+
+/// How the track is laid out in `format`.
+async fn open_layout(state: &SharedState, id: &str, format: SongFormat) -> Result<Mp3Layout, ApiError> {
+    let kbps = format.mp3_kbps()
+        .ok_or_else(|| ApiError::InvalidInput(format!("{} can't be streamed over HLS", format.api_name())))?;
+    let (head, total) = read_head(state, id, format).await?;
+
+    Ok(Mp3Layout::cbr(kbps, SAMPLE_RATE, &head, total))
+}
+
+fn variant_format(quality: &str) -> Result<SongFormat, ApiError> {
+    SongFormat::from_api_name(quality)
+        .filter(|format| format.mp3_kbps().is_some())
+        .ok_or_else(|| ApiError::InvalidInput(format!("No HLS variant for quality '{}'", quality)))
+}
+
+fn playlist_response(playlist: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(playlist));
+    response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(hls::PLAYLIST_MIME_TYPE));
+    response
+}
+
+/// Master playlist with a variant per accepted MP3 quality the accounts can
+/// stream, best first. FLAC is left out, HLS can't carry it as packed audio.
+/// Playing starts here, so this is where the listen is recorded.
+pub async fn get_hls_playlist(
+    Path(id): Path<String>,
+    Query(params): Query<StreamParams>,
+    user: Authenticated,
+    State(state): State<SharedState>,
+) -> Result<Response<Body>, ApiError> {
+    let formats = params.formats()?;
+    let id_i = id.parse::<i32>().map_err(|_| ApiError::InvalidInput(format!("Invalid track id '{}'", id)))?;
+    let playable = state.deezer.accounts().playable_formats(&formats).await?;
+
+    let variants: Vec<Variant> = playable.iter()
+        .filter_map(|format| Some(Variant {
+            uri: format!("{}/index.m3u8", format.api_name()),
+            // Segments run a little over the nominal bitrate with their timestamp.
+            bandwidth: format.mp3_kbps()? * 1010,
+            codecs: hls::MP3_CODECS,
+        }))
+        .collect();
+    if variants.is_empty() {
+        return Err(ApiError::FormatUnavailable(formats.iter().map(SongFormat::api_name).collect()));
+    }
+
+    if let Err(e) = record_listening(state, id_i, None, Some(user.user_id)).await {
+        eprintln!("Failed to record listening: {}", e);
+    }
+
+    Ok(playlist_response(hls::master_playlist(&variants)))
+}
+
+pub async fn get_hls_media_playlist(
+    Path((id, quality)): Path<(String, String)>,
+    _user: Authenticated,
+    State(state): State<SharedState>,
+) -> Result<Response<Body>, ApiError> {
+    let format = variant_format(&quality)?;
+    let layout = open_layout(&state, &id, format).await?;
+
+    Ok(playlist_response(layout.media_playlist(format.extension())))
+}
+
+/// A segment of the media playlist, only the bytes it covers are read from
+/// the cache.
+pub async fn get_hls_segment(
+    Path((id, quality, segment)): Path<(String, String, String)>,
+    _user: Authenticated,
+    State(state): State<SharedState>,
+) -> Result<Response<Body>, ApiError> {
+    let format = variant_format(&quality)?;
+    let not_found = || ApiError::NotFound(format!("Segment '{}' of track {}", segment, id));
+    let n = segment.strip_suffix(&format!(".{}", format.extension()))
+        .and_then(|n| n.parse::<u64>().ok())
+        .ok_or_else(not_found)?;
+
+    let layout = open_layout(&state, &id, format).await?;
+    if n >= layout.segments() {
+        return Err(not_found());
+    }
+
+    let (start, end) = layout.segment_range(n);
+    let (data, _) = read_range(&state, &id, format, start, end).await?
+        .ok_or_else(|| ApiError::StorageError(format!("Track {} is no longer cached", id)))?;
+
+    let mut response = Response::new(Body::from(layout.segment(n, &data)));
+    response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(format.mime_type()));
+    Ok(response)
+}
@@ -0,0 +1,86 @@
+use axum::{Router, routing::get};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub mod accounts;
+pub mod config;
+pub mod deezer;
+mod private_api_routs;
+mod history_routs;
+mod hls_routs;
+pub mod postgres_service;
+pub mod s3_client;
+
+use crate::deezer::Deezer;
+use crate::history_routs::{get_activity, get_recent, get_top_albums, get_top_artists, get_top_tracks, get_track_listens};
+use crate::hls_routs::{get_hls_media_playlist, get_hls_playlist, get_hls_segment};
+use crate::private_api_routs::{download_album, get_account, get_accounts, get_album, get_artist, get_playlist, get_stream, get_track_page, get_track_remix, search};
+
+// use aws_sdk_s3::Client as S3Client;
+use crate::postgres_service::PostgresDb;
+use crate::s3_client::{S3Client};
+use music_core::sessions::{SessionStore, SessionStoreState};
+use music_core::transcode::Transcoder;
+use music_core::users::{self, UserStore, UserStoreState};
+
+#[derive(Clone)]
+pub struct SharedState{
+    deezer: Deezer,
+    postgres_db: Arc<PostgresDb>,
+    s3: S3Client,
+    users: Arc<UserStore>,
+    sessions: Arc<dyn SessionStore>,
+    transcoder: Transcoder,
+    /// Held while a track is fetched into the cache, keyed by its S3 key, so
+    /// concurrent requests for it wait instead of fetching it again.
+    cache_fills: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+}
+
+impl SharedState {
+    pub fn new(
+        deezer: Deezer,
+        postgres_db: Arc<PostgresDb>,
+        s3: S3Client,
+        users: Arc<UserStore>,
+        sessions: Arc<dyn SessionStore>,
+        transcoder: Transcoder,
+    ) -> Self {
+        Self { deezer, postgres_db, s3, users, sessions, transcoder, cache_fills: Arc::default() }
+    }
+}
+
+impl UserStoreState for SharedState {
+    fn user_store(&self) -> &UserStore {
+        &self.users
+    }
+}
+
+impl SessionStoreState for SharedState {
+    fn session_store(&self) -> &dyn SessionStore {
+        self.sessions.as_ref()
+    }
+}
+
+pub fn router(shared_state: SharedState) -> Router {
+    Router::new()
+        .route("/track/{id}", get(get_track_page))
+        .route("/stream/{id}", get(get_stream))
+        .route("/album/{id}", get(get_album))
+        .route("/album/{id}/download", get(download_album))
+        .route("/artist/{id}", get(get_artist))
+        .route("/playlist/{id}", get(get_playlist))
+        .route("/search", get(search))
+        .route("/mix/{id}", get(get_track_remix))
+        .route("/hls/{id}/playlist.m3u8", get(get_hls_playlist))
+        .route("/hls/{id}/{quality}/index.m3u8", get(get_hls_media_playlist))
+        .route("/hls/{id}/{quality}/{segment}", get(get_hls_segment))
+        .route("/history/recent", get(get_recent))
+        .route("/history/top/tracks", get(get_top_tracks))
+        .route("/history/top/artists", get(get_top_artists))
+        .route("/history/top/albums", get(get_top_albums))
+        .route("/history/tracks/{id}", get(get_track_listens))
+        .route("/history/activity", get(get_activity))
+        .nest("/users", users::router())
+        .route("/account", get(get_account))
+        .route("/admin/accounts", get(get_accounts)).with_state(shared_state)
+}
@@ -1,20 +1,11 @@
-use std::time::Duration;
 use axum::extract::FromRef;
-use axum::response::IntoResponse;
+use music_core::history::{ActivityParams, BucketCount, PageParams, Window};
+use serde::Serialize;
 use sqlx::{pool, FromRow, Postgres};
 use sqlx::postgres::{PgHasArrayType, PgPoolOptions, PgTypeInfo};
-use crate::deezer::{Album, AlbumHeader, Artist, TrackPage};
+use crate::deezer::{Album, AlbumHeader, Artist, ArtistPage, DiscographyAlbum, Playlist, PlaylistHeader, TrackPage};
 
 
-#[derive(Debug, FromRow)]
-pub struct Track {
-  id: i32,
-  title: String,
-  duration: u32,
-  author_id: i32,
-  album_id: i32,
-}
-
 #[derive(Debug, sqlx::Type)]
 #[sqlx(type_name = "author_input_deezer")] // Links this struct to the PG type
 pub struct AuthorInput {
@@ -75,6 +66,17 @@ impl From<AlbumHeader> for AlbumInput {
 }
 
 
+impl FromRef<DiscographyAlbum> for AlbumInput {
+  fn from_ref(value: &DiscographyAlbum) -> Self {
+    Self {
+      id: value.alb_id.parse::<i32>().unwrap(),
+      title: value.alb_title.clone(),
+      img: value.img.clone(),
+    }
+  }
+}
+
+
 #[derive(Debug, sqlx::Type)]
 #[sqlx(type_name = "track_input_deezer")]
 pub struct TrackInput {
@@ -112,6 +114,127 @@ impl PgHasArrayType for TrackInput {
   }
 }
 
+#[derive(Debug, sqlx::Type)]
+#[sqlx(type_name = "playlist_input_deezer")]
+pub struct PlaylistInput {
+  pub id: i64,
+  pub title: String,
+  pub img: String,
+}
+
+impl FromRef<PlaylistHeader> for PlaylistInput {
+  fn from_ref(value: &PlaylistHeader) -> Self {
+    Self {
+      id: value.playlist_id.parse::<i64>().unwrap(),
+      title: value.title.clone(),
+      img: value.img.clone(),
+    }
+  }
+}
+
+
+#[derive(Debug, sqlx::Type)]
+#[sqlx(type_name = "playlist_track_input_deezer")]
+pub struct PlaylistTrackInput {
+  pub id: i32,
+  pub title: String,
+  pub duration: i32,
+  pub album_id: i32,
+  pub album_title: String,
+  pub album_img: String,
+  pub author_id: i32,
+  pub author_title: String,
+  pub author_img: String,
+}
+
+impl PlaylistTrackInput {
+  /// `None` for tracks that can't be stored: without any artist there is no
+  /// author row to attach them to, user uploads have negative ids and some
+  /// tracks come without an album or duration.
+  pub fn from_track(value: &TrackPage) -> Option<Self> {
+    let author = value.artists.first()?;
+    let id = value.id.parse::<i32>().ok().filter(|id| *id > 0)?;
+
+    Some(Self {
+      id,
+      title: value.sng_title.clone(),
+      duration: value.duration.parse::<i32>().ok()?,
+      album_id: value.alb_id.parse::<i32>().ok()?,
+      album_title: value.alb_title.clone(),
+      album_img: value.alb_picture.clone(),
+      author_id: author.id.parse::<i32>().ok()?,
+      author_title: author.name.clone(),
+      author_img: author.picture.clone(),
+    })
+  }
+}
+
+impl PgHasArrayType for PlaylistTrackInput {
+  fn array_type_info() -> PgTypeInfo {
+    PgTypeInfo::with_name("_playlist_track_input_deezer")
+  }
+}
+
+// History:
+#[derive(Debug, FromRow, Serialize)]
+pub struct RecentListen {
+  /// Unix time in seconds.
+  pub listened_at: i64,
+  pub track_id: i32,
+  pub title: String,
+  pub duration: i32,
+  pub img: Option<String>,
+  pub author_id: i32,
+  pub author_title: String,
+  pub album_id: i32,
+  pub album_title: String,
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct TopTrack {
+  pub track_id: i32,
+  pub title: String,
+  pub img: Option<String>,
+  pub author_title: String,
+  pub listen_count: i64,
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct TopAuthor {
+  pub author_id: i32,
+  pub title: String,
+  pub img: Option<String>,
+  pub listen_count: i64,
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct TopAlbum {
+  pub album_id: i32,
+  pub title: String,
+  pub img: Option<String>,
+  pub author_title: String,
+  pub listen_count: i64,
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct TrackListenCount {
+  pub track_id: i32,
+  pub title: String,
+  pub listen_count: i64,
+}
+
+#[derive(Debug, FromRow)]
+struct BucketRow {
+  start: i64,
+  listen_count: i64,
+}
+
+/// `$1` is the user whose listens to count, `$2`/`$3` the window bounds in
+/// unix seconds. `NULL` means everyone or an open side respectively.
+const HISTORY_FILTER: &str = "($1::BIGINT IS NULL OR l.user_id = $1) \
+  AND ($2::BIGINT IS NULL OR l.listened_at >= to_timestamp($2)) \
+  AND ($3::BIGINT IS NULL OR l.listened_at < to_timestamp($3))";
+
 pub struct PostgresDb {
   pool: pool::Pool<Postgres>,
 }
@@ -119,10 +242,15 @@ pub struct PostgresDb {
 impl PostgresDb {
   pub async fn new(url: &str) -> Self {
     Self {
-      pool: PgPoolOptions::new().connect(&url).await.unwrap(),
+      pool: PgPoolOptions::new().connect(url).await.unwrap(),
     }
   }
 
+  /// For other stores sharing the database, like `music_core::users::UserStore`.
+  pub fn pool(&self) -> &pool::Pool<Postgres> {
+    &self.pool
+  }
+
   pub async fn add_album(
     &self,
     track: &[TrackInput],
@@ -160,12 +288,182 @@ impl PostgresDb {
     Ok(())
   }
   
-  pub async fn record_listening(&self, id: i32) -> Result<bool, sqlx::Error> {
-    let is_added: bool = sqlx::query_scalar("SELECT add_listening($1)")
+  pub async fn add_playlist_by_playlist(
+    &self,
+    playlist: &Playlist
+  ) -> Result<(), sqlx::error::Error> {
+    let playlist_input = PlaylistInput::from_ref(&playlist.playlist_header);
+    let tracks_input: Vec<PlaylistTrackInput> = playlist.songs.data.iter()
+      .filter_map(PlaylistTrackInput::from_track)
+      .collect();
+
+    sqlx::query("CALL add_playlist_deezer($1, $2)")
+      .bind(playlist_input)
+      .bind(tracks_input)
+      .execute(&self.pool)
+      .await?;
+
+    Ok(())
+  }
+
+  pub async fn add_artist_albums(
+    &self,
+    artist_page: &ArtistPage
+  ) -> Result<(), sqlx::error::Error> {
+    let artist = &artist_page.artist;
+    let releases = artist_page.albums.iter()
+      .chain(&artist_page.singles)
+      .chain(&artist_page.eps);
+    let no_tracks: Vec<TrackInput> = vec![];
+
+    let artist_id = artist.id.parse::<i32>().map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+
+    let mut tx = self.pool.begin().await?;
+    for album in releases {
+      let author_input = match album.artists.iter().find(|art| art.id == album.art_id) {
+        Some(art) => AuthorInput::from_ref(art),
+        None if album.art_id == artist.id => AuthorInput {
+          id: artist_id,
+          title: artist.name.clone(),
+          img: artist.picture.clone(),
+        },
+        None => continue,
+      };
+
+      sqlx::query("CALL add_album_deezer($1, $2, $3)")
+        .bind(author_input)
+        .bind(AlbumInput::from_ref(album))
+        .bind(&no_tracks)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+
+    Ok(())
+  }
+
+  pub async fn record_listening(&self, id: i32, user_id: Option<i64>) -> Result<bool, sqlx::Error> {
+    let is_added: bool = sqlx::query_scalar("SELECT record_listen_deezer($1, $2)")
       .bind(id)
+      .bind(user_id)
       .fetch_one(&self.pool)
       .await?;
     
     Ok(is_added)
   }
-}
\ No newline at end of file
+
+  /// Newest listens first.
+  pub async fn recent_listens(&self, user_id: Option<i64>, window: Window, page: PageParams) -> Result<Vec<RecentListen>, sqlx::Error> {
+    sqlx::query_as(&format!(
+      "SELECT EXTRACT(EPOCH FROM l.listened_at)::BIGINT AS listened_at,
+         t.id AS track_id, t.title, t.duration, t.img,
+         a.id AS author_id, a.title AS author_title,
+         al.id AS album_id, al.title AS album_title
+       FROM listenings_deezer l
+       JOIN tracks_deezer t ON t.id = l.track_id
+       JOIN authors_deezer a ON a.id = t.author_id
+       JOIN albums_deezer al ON al.id = t.album_id
+       WHERE {HISTORY_FILTER}
+       ORDER BY l.listened_at DESC, l.id DESC
+       LIMIT $4 OFFSET $5"))
+      .bind(user_id)
+      .bind(window.since)
+      .bind(window.until)
+      .bind(page.fetch_limit())
+      .bind(page.offset as i64)
+      .fetch_all(&self.pool)
+      .await
+  }
+
+  pub async fn top_tracks(&self, user_id: Option<i64>, window: Window, page: PageParams) -> Result<Vec<TopTrack>, sqlx::Error> {
+    sqlx::query_as(&format!(
+      "SELECT t.id AS track_id, t.title, t.img, a.title AS author_title, COUNT(*) AS listen_count
+       FROM listenings_deezer l
+       JOIN tracks_deezer t ON t.id = l.track_id
+       JOIN authors_deezer a ON a.id = t.author_id
+       WHERE {HISTORY_FILTER}
+       GROUP BY t.id, a.title
+       ORDER BY listen_count DESC, t.id
+       LIMIT $4 OFFSET $5"))
+      .bind(user_id)
+      .bind(window.since)
+      .bind(window.until)
+      .bind(page.fetch_limit())
+      .bind(page.offset as i64)
+      .fetch_all(&self.pool)
+      .await
+  }
+
+  pub async fn top_authors(&self, user_id: Option<i64>, window: Window, page: PageParams) -> Result<Vec<TopAuthor>, sqlx::Error> {
+    sqlx::query_as(&format!(
+      "SELECT a.id AS author_id, a.title, a.img, COUNT(*) AS listen_count
+       FROM listenings_deezer l
+       JOIN tracks_deezer t ON t.id = l.track_id
+       JOIN authors_deezer a ON a.id = t.author_id
+       WHERE {HISTORY_FILTER}
+       GROUP BY a.id
+       ORDER BY listen_count DESC, a.id
+       LIMIT $4 OFFSET $5"))
+      .bind(user_id)
+      .bind(window.since)
+      .bind(window.until)
+      .bind(page.fetch_limit())
+      .bind(page.offset as i64)
+      .fetch_all(&self.pool)
+      .await
+  }
+
+  pub async fn top_albums(&self, user_id: Option<i64>, window: Window, page: PageParams) -> Result<Vec<TopAlbum>, sqlx::Error> {
+    sqlx::query_as(&format!(
+      "SELECT al.id AS album_id, al.title, al.img, a.title AS author_title, COUNT(*) AS listen_count
+       FROM listenings_deezer l
+       JOIN tracks_deezer t ON t.id = l.track_id
+       JOIN albums_deezer al ON al.id = t.album_id
+       JOIN authors_deezer a ON a.id = al.author_id
+       WHERE {HISTORY_FILTER}
+       GROUP BY al.id, a.title
+       ORDER BY listen_count DESC, al.id
+       LIMIT $4 OFFSET $5"))
+      .bind(user_id)
+      .bind(window.since)
+      .bind(window.until)
+      .bind(page.fetch_limit())
+      .bind(page.offset as i64)
+      .fetch_all(&self.pool)
+      .await
+  }
+
+  /// All-time listens of a track, like `track_stats_deezer` but optionally
+  /// of one user only. `None` when the track isn't known.
+  pub async fn track_listen_count(&self, id: i32, user_id: Option<i64>) -> Result<Option<TrackListenCount>, sqlx::Error> {
+    sqlx::query_as(
+      "SELECT t.id AS track_id, t.title, COUNT(l.id) AS listen_count
+       FROM tracks_deezer t
+       LEFT JOIN listenings_deezer l ON l.track_id = t.id AND ($2::BIGINT IS NULL OR l.user_id = $2)
+       WHERE t.id = $1
+       GROUP BY t.id")
+      .bind(id)
+      .bind(user_id)
+      .fetch_optional(&self.pool)
+      .await
+  }
+
+  /// Listens per day or week, buckets without any are left out.
+  pub async fn listen_activity(&self, user_id: Option<i64>, params: ActivityParams, window: Window) -> Result<Vec<BucketCount>, sqlx::Error> {
+    let rows: Vec<BucketRow> = sqlx::query_as(&format!(
+      "SELECT EXTRACT(EPOCH FROM date_trunc($4, l.listened_at))::BIGINT AS start, COUNT(*) AS listen_count
+       FROM listenings_deezer l
+       WHERE {HISTORY_FILTER} AND ($5::INT IS NULL OR l.track_id = $5)
+       GROUP BY 1
+       ORDER BY 1"))
+      .bind(user_id)
+      .bind(window.since)
+      .bind(window.until)
+      .bind(params.bucket.pg_unit())
+      .bind(params.track_id)
+      .fetch_all(&self.pool)
+      .await?;
+
+    Ok(rows.into_iter().map(|r| BucketCount { start: r.start, listen_count: r.listen_count }).collect())
+  }
+}
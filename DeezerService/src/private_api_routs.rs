@@ -1,18 +1,27 @@
+use async_trait::async_trait;
 use axum::body::Body;
-use axum::extract::{FromRef, Path, State};
-use axum::http::{header, Method, Response, StatusCode};
+use axum::extract::{FromRef, Path, Query, State};
+use axum::http::{header, HeaderMap, HeaderValue, Method, Response, StatusCode};
 use axum::Json;
 use axum::response::IntoResponse;
-use futures::{SinkExt, Stream, StreamExt};
-use serde::Serialize;
-use tokio::sync::mpsc;
-use crate::deezer::{Album, AlbumHeader, ApiError, Deezer, SongFormat};
+use bytes::Bytes;
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+use music_core::{ByteStream, ProviderError, SearchKind};
+use music_core::error::ErrorBody;
+use music_core::library::TrackCatalog;
+use music_core::range::ByteRange;
+use music_core::tags::{tag_stream, Picture, Tagger, TrackTags};
+use music_core::transcode::{TranscodeError, TranscodeParams, TranscodeTarget};
+use music_core::zip::{self, ZipEntry};
+use crate::deezer::{Album, AlbumHeader, ApiError, SearchResult, SongFormat, TrackPage, TrackStream};
+use music_core::sessions::{Admin, Authenticated};
 use crate::SharedState;
-use tokio_stream::wrappers::ReceiverStream;
-use cbc::cipher::{BlockDecryptMut, KeyIvInit};
 use tokio::join;
-use tokio_util::io::{ReaderStream, StreamReader};
-use crate::postgres_service::{AlbumInput, AuthorInput, PostgresDb, TrackInput};
+use tokio_util::io::ReaderStream;
+use crate::postgres_service::AlbumInput;
 
 #[derive(Serialize)]
 struct TrackRemixBodyQuery<'a> {
@@ -20,60 +29,318 @@ struct TrackRemixBodyQuery<'a> {
     start_with_input_track: bool,
 }
 
-pub async fn get_track_remix(Path(id): Path<String>, State(state): State<SharedState>) -> Result<impl IntoResponse, StatusCode> {
+pub async fn get_track_remix(Path(id): Path<String>, _user: Authenticated, State(state): State<SharedState>) -> Result<impl IntoResponse, ApiError> {
     let deezer = state.deezer.clone();
-    let req_body = format!("{{\"sng_id\": {}, \"start_with_input_track\": true}}", id);
+    let req_body = serde_json::to_string(&TrackRemixBodyQuery {
+        sng_id: &id,
+        start_with_input_track: true,
+    })?;
     
     let res = deezer.call(
         Method::POST, 
         "song.getSearchTrackMix",
         true, 
         Some(req_body)
-    ).await.map_err(|e| { 
-        println!("{}", e);
-        StatusCode::INTERNAL_SERVER_ERROR 
-    })?;
+    ).await?;
     
     Ok(Json(res))
 }
 
-pub async fn get_track_page(Path(id): Path<String>, State(state): State<SharedState>) -> Result<impl IntoResponse, StatusCode> {
+const MAX_SEARCH_LIMIT: u32 = 100;
+
+fn default_search_limit() -> u32 {
+    25
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SearchParams {
+    q: String,
+    #[serde(rename = "type")]
+    kind: Option<SearchKind>,
+    #[serde(default = "default_search_limit")]
+    limit: u32,
+    #[serde(default)]
+    offset: u32,
+}
+
+#[derive(Serialize)]
+struct SearchPage {
+    limit: u32,
+    offset: u32,
+    next_offset: Option<u32>,
+    #[serde(flatten)]
+    results: SearchResult,
+}
+
+pub async fn search(Query(params): Query<SearchParams>, State(state): State<SharedState>) -> Result<impl IntoResponse, ApiError> {
     let deezer = state.deezer.clone();
-    let res = deezer.get_track_page(&id).await
-        .map_err(|e| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let limit = params.limit.clamp(1, MAX_SEARCH_LIMIT);
+    let end = params.offset.checked_add(limit)
+        .ok_or_else(|| ApiError::InvalidInput(format!("Offset {} is too large", params.offset)))?;
+
+    let results = deezer.search(&params.q, params.kind, params.offset, limit).await?;
+
+    let has_more = [results.tracks.total, results.albums.total, results.artists.total, results.playlists.total]
+        .iter()
+        .any(|total| *total > end);
+
+    Ok(Json(SearchPage {
+        limit,
+        offset: params.offset,
+        next_offset: has_more.then_some(end),
+        results,
+    }))
+}
+
+/// Health of the Deezer accounts, without their ARLs. Admins only.
+pub async fn get_accounts(_admin: Admin, State(state): State<SharedState>) -> impl IntoResponse {
+    Json(state.deezer.accounts().status().await)
+}
+
+/// What each account's subscription includes (HQ, lossless, country, expiry,
+/// ads). Admins only, it refreshes every account's session.
+pub async fn get_account(_admin: Admin, State(state): State<SharedState>) -> impl IntoResponse {
+    Json(state.deezer.account_info().await)
+}
+
+pub async fn get_track_page(Path(id): Path<String>, State(state): State<SharedState>) -> Result<impl IntoResponse, ApiError> {
+    let deezer = state.deezer.clone();
+    let res = deezer.get_track_page(&id).await?;
 
     Ok(Json(res))
 }
 
 // pub(crate) type BlowfishCbcDec = cbc::Decryptor<Blowfish>;
 
+const AUDIO_FORMAT_HEADER: &str = "x-audio-format";
+const QUALITY_FALLBACK_HEADER: &str = "x-quality-fallback";
+
+fn default_fallback() -> bool {
+    true
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StreamParams {
+    quality: Option<String>,
+    #[serde(default = "default_fallback")]
+    fallback: bool,
+}
+
+impl StreamParams {
+    /// Formats to ask Deezer for, best first. `quality` is the best one the
+    /// caller accepts, lower ones are only added when fallback is allowed.
+    pub(crate) fn formats(&self) -> Result<Vec<SongFormat>, ApiError> {
+        let Some(quality) = &self.quality else {
+            return Ok(SongFormat::ALL.to_vec());
+        };
+        let format = SongFormat::from_api_name(quality)
+            .ok_or_else(|| ApiError::InvalidInput(format!("Unknown quality '{}'", quality)))?;
+
+        Ok(if self.fallback { format.with_fallback() } else { vec![format] })
+    }
+}
+
+fn requested_range(headers: &HeaderMap) -> Option<ByteRange> {
+    headers.get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(ByteRange::parse)
+}
+
+/// Status and code for an error object returned by the gw-light or media API.
+fn deezer_error(error: &Value) -> (StatusCode, &'static str) {
+    // The media API answers with a list of `{ code, message }`.
+    if let Some(code) = error.get(0).and_then(|e| e.get("code")).and_then(Value::as_u64) {
+        return match code {
+            // Track token has no sufficient rights on requested media.
+            2002 => (StatusCode::FORBIDDEN, "not_available"),
+            _ => (StatusCode::BAD_GATEWAY, "upstream_error"),
+        };
+    }
+
+    let has = |key: &str| error.get(key).is_some();
+    if has("DATA_ERROR") {
+        (StatusCode::NOT_FOUND, "not_found")
+    } else if has("NEED_USER_AUTH_REQUIRED") || has("USER_AUTH_REQUIRED") || has("VALID_TOKEN_REQUIRED") {
+        (StatusCode::FORBIDDEN, "invalid_arl")
+    } else if has("QUOTA_ERROR") || has("RATE_LIMIT_EXCEEDED") {
+        (StatusCode::TOO_MANY_REQUESTS, "rate_limited")
+    } else {
+        (StatusCode::BAD_GATEWAY, "upstream_error")
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response<Body> {
+        if let ApiError::UserError(e) = self {
+            return e.into_response();
+        }
+        eprintln!("{}", self);
+
+        let (status, code) = match &self {
+            ApiError::ApiError(error) => deezer_error(error),
+            ApiError::TokenRequired(_) => (StatusCode::FORBIDDEN, "invalid_arl"),
+            ApiError::RangeNotSatisfiable => (StatusCode::RANGE_NOT_SATISFIABLE, "range_not_satisfiable"),
+            ApiError::FormatUnavailable(_) => (StatusCode::FORBIDDEN, "format_unavailable"),
+            ApiError::InvalidInput(_) => (StatusCode::BAD_REQUEST, "invalid_input"),
+            ApiError::DatabaseError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "internal_error"),
+            ApiError::NotFound(_) => (StatusCode::NOT_FOUND, "not_found"),
+            ApiError::UserError(_) => unreachable!("answered by UserError::into_response"),
+            ApiError::NoAccountAvailable => (StatusCode::SERVICE_UNAVAILABLE, "no_account_available"),
+            ApiError::TranscodeError(TranscodeError::InvalidInput(_)) => (StatusCode::BAD_REQUEST, "invalid_input"),
+            ApiError::TranscodeError(TranscodeError::Spawn { .. } | TranscodeError::Probe { .. }) => {
+                (StatusCode::SERVICE_UNAVAILABLE, "transcoder_unavailable")
+            }
+            ApiError::TranscodeError(TranscodeError::Disabled) => (StatusCode::NOT_IMPLEMENTED, "transcoding_disabled"),
+            ApiError::StorageError(_) => (StatusCode::BAD_GATEWAY, "storage_error"),
+            ApiError::RequestError(_) | ApiError::UrlParseError(_) | ApiError::JsonParseError(_) => {
+                (StatusCode::BAD_GATEWAY, "upstream_error")
+            }
+        };
+
+        (status, Json(ErrorBody::new(code, self.to_string()))).into_response()
+    }
+}
+
+/// Advertises seeking support and turns the response into a 206 when it only
+/// carries part of the track.
+fn set_range_headers(response: &mut Response<Body>, content_range: Option<String>, content_length: Option<u64>) {
+    let headers = response.headers_mut();
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+    if let Some(content_length) = content_length {
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(content_length));
+    }
+
+    if let Some(content_range) = content_range.and_then(|v| HeaderValue::from_str(&v).ok()) {
+        headers.insert(header::CONTENT_RANGE, content_range);
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+    }
+}
+
+/// Names the bytes served for `id` in `variant`, the same whether they come
+/// from the cache or are still streamed into it. Weak since the tags, cover
+/// included, can come out differently when a track is cached again.
+fn set_etag(response: &mut Response<Body>, id: &str, variant: &str) {
+    if let Ok(etag) = HeaderValue::from_str(&format!("W/\"{}-{}\"", id, variant)) {
+        response.headers_mut().insert(header::ETAG, etag);
+    }
+}
+
+/// A track streamed while it's cached: whole, tagged and of unknown length,
+/// ranges are only served once it's in the cache.
+fn set_live_headers(response: &mut Response<Body>) {
+    response.headers_mut().insert(header::ACCEPT_RANGES, HeaderValue::from_static("none"));
+}
+
+fn set_quality_fallback(response: &mut Response<Body>, formats: &[SongFormat], format: SongFormat) {
+    response.headers_mut().insert(
+        QUALITY_FALLBACK_HEADER,
+        HeaderValue::from_static(if formats.first() == Some(&format) { "false" } else { "true" }),
+    );
+}
+
+/// Serves the track from the S3 cache when one of the accepted formats is
+/// there, otherwise streams the whole track from Deezer and caches it on the
+/// way. Either way it's tagged, so a URL always has one byte layout; a range
+/// can't be served before the track is cached and is ignored until then.
+/// With `codec` the track is transcoded, see `get_transcoded_stream`.
 pub async fn get_stream(
     Path(id): Path<String>,
+    Query(params): Query<StreamParams>,
+    Query(transcode): Query<TranscodeParams>,
+    headers: HeaderMap,
+    user: Authenticated,
     State(state): State<SharedState>,
-) -> Result<Response<Body>, StatusCode> {
-    let deezer = state.deezer.clone();
-    let stream = deezer.get_stream(id, None).await.map_err(|e| {
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+) -> Result<Response<Body>, ApiError> {
+    let formats = params.formats()?;
+    let target = transcode.target()?;
+    let user_id = Some(user.user_id);
+    let range = requested_range(&headers);
+    let id_i = id.parse::<i32>().map_err(|_| ApiError::InvalidInput(format!("Invalid track id '{}'", id)))?;
+
+    if let Some(target) = target {
+        let (stream, record) = join!{
+            get_transcoded_stream(&state, &id, &formats, target, range),
+            record_listening(state.clone(), id_i, None, user_id)
+        };
+        if let Err(e) = record {
+            eprintln!("Failed to record listening: {}", e);
+        }
+        let (format, mut response) = stream?;
+        set_quality_fallback(&mut response, &formats, format);
+        return Ok(response);
+    }
+
+    let (format, mut response) = match state.s3.try_get_song(&id, &formats, range).await? {
+        Some((format, file)) => {
+            if let Err(e) = record_listening(state.clone(), id_i, None, user_id).await {
+                eprintln!("Failed to record listening: {}", e);
+            }
+
+            let content_range = file.content_range().map(str::to_owned);
+            let content_length = file.content_length().map(|len| len as u64);
+            let body = Body::from_stream(ReaderStream::new(file.body.into_async_read()));
 
-    let body = Body::from_stream(stream);
-
-    let response = Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, "application/octet-stream")
-        .header(
-            header::CONTENT_DISPOSITION,
-            "attachment; filename=\"combined_data.flac\"",
-        )
-        .body(body)
-        .map_err(|e| {
-            eprintln!("Failed to build response: {:?}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+            let mut response = create_stream_from_body(body, &id, format);
+            set_range_headers(&mut response, content_range, content_length);
+            (format, response)
+        }
+        None => {
+            let (stream, record) = join!{
+                stream_live(&state, &id, &formats, None),
+                record_listening(state.clone(), id_i, None, user_id)
+            };
+            if let Err(e) = record {
+                eprintln!("Failed to record listening: {}", e);
+            }
+
+            let (format, stream) = stream?;
+            let mut response = create_stream_from_body(Body::from_stream(stream), &id, format);
+            set_live_headers(&mut response);
+            (format, response)
+        }
+    };
+
+    set_quality_fallback(&mut response, &formats, format);
+    set_etag(&mut response, &id, format.api_name());
 
     Ok(response)
 }
 
+/// Serves `target` transcoded from the best accepted format, from the S3
+/// cache when it's there. Otherwise the whole track is transcoded, from the
+/// cached original or Deezer, and the result cached on the way. Live
+/// transcodes ignore `range` and say so, their length isn't known up front.
+async fn get_transcoded_stream(
+    state: &SharedState,
+    id: &str,
+    formats: &[SongFormat],
+    target: TranscodeTarget,
+    range: Option<ByteRange>,
+) -> Result<(SongFormat, Response<Body>), ApiError> {
+    let audio_format = target.codec.audio_format();
+
+    if let Some((source, file)) = state.s3.try_get_variant(id, formats, target, range).await? {
+        let content_range = file.content_range().map(str::to_owned);
+        let content_length = file.content_length().map(|len| len as u64);
+        let body = Body::from_stream(ReaderStream::new(file.body.into_async_read()));
+
+        let mut response = stream_response(body, id, audio_format.extension(), audio_format.mime_type(), &target.name());
+        set_range_headers(&mut response, content_range, content_length);
+        set_etag(&mut response, id, &format!("{}-{}", source.api_name(), target.name()));
+        return Ok((source, response));
+    }
+
+    let (source, stream) = open_track(state.clone(), id, formats, None).await?;
+    let stream = state.transcoder.transcode(stream, target).await?;
+    let stream = state.s3.cache_variant(id, source, target, stream);
+
+    let mut response = stream_response(Body::from_stream(stream), id, audio_format.extension(), audio_format.mime_type(), &target.name());
+    set_live_headers(&mut response);
+    set_etag(&mut response, id, &format!("{}-{}", source.api_name(), target.name()));
+    Ok((source, response))
+}
+
 impl FromRef<AlbumHeader> for AlbumInput {
     fn from_ref(input: &AlbumHeader) -> Self {
         Self{
@@ -84,126 +351,281 @@ impl FromRef<AlbumHeader> for AlbumInput {
     }
 }
 
-pub async fn get_album_and_add_to_db(id: String, state: SharedState) -> Result<Album, StatusCode> {
+pub async fn get_album_and_add_to_db(id: String, state: SharedState) -> Result<Album, ApiError> {
     let deezer = state.deezer.clone();
     let postgres = state.postgres_db.clone();
 
-    let res = deezer.get_album(id).await
-      .map_err(|e| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let res = deezer.get_album(id).await?;
 
-    postgres.add_album_by_album(&res).await;
+    if let Err(e) = postgres.add_album_by_album(&res).await {
+        eprintln!("{}", e);
+    }
 
     Ok(res)
 }
 
 
-pub async fn get_album(Path(id): Path<String>, State(state): State<SharedState>) -> Result<impl IntoResponse, StatusCode> {
+pub async fn get_album(Path(id): Path<String>, State(state): State<SharedState>) -> Result<impl IntoResponse, ApiError> {
     Ok(Json(get_album_and_add_to_db(id, state).await?))
 }
 
-pub async fn record_listening(state: SharedState, id: i32, alb_id: Option<String>) -> Result<bool, StatusCode> {
+/// Album tracks being fetched at once for a download.
+const ALBUM_DOWNLOAD_PARALLELISM: usize = 4;
+
+/// What a track from Deezer is tagged with. The cover is fetched unless the
+/// album's, if already at hand, comes along.
+async fn track_tags(state: &SharedState, page: &TrackPage, album: Option<(&AlbumHeader, Option<Picture>)>) -> TrackTags {
+    let mut tags = page.tags(album.as_ref().map(|(header, _)| *header));
+    tags.cover = match album {
+        Some((_, cover)) => cover,
+        None => state.deezer.get_cover(&page.alb_picture).await,
+    };
+    tags
+}
+
+/// Fetches the whole track in `format` from Deezer into the S3 cache, tagged
+/// like the tracks `open_track` caches.
+pub async fn cache_track(state: &SharedState, id: &str, format: SongFormat) -> Result<(), ApiError> {
+    let TrackStream { format, total_len, stream, page, .. } = state.deezer.get_stream(id.to_owned(), &[format], None).await?;
+    let stream: ByteStream = stream
+        .map_ok(Bytes::from)
+        .map_err(std::io::Error::other)
+        .boxed();
+
+    let tags = track_tags(state, &page, None).await;
+    state.s3.store_song(id, format, total_len, Tagger::new(format.audio_format(), &tags), stream).await
+}
+
+/// Opens the whole track, from the S3 cache when it's there, otherwise from
+/// Deezer, tagged and cached on the way.
+async fn open_track(
+    state: SharedState,
+    id: &str,
+    formats: &[SongFormat],
+    album: Option<(&AlbumHeader, Option<Picture>)>,
+) -> Result<(SongFormat, ByteStream), ApiError> {
+    if let Some((format, file)) = state.s3.try_get_song(id, formats, None).await? {
+        return Ok((format, ReaderStream::new(file.body.into_async_read()).boxed()));
+    }
+
+    stream_live(&state, id, formats, album).await
+}
+
+/// Streams the whole track from Deezer, tagged exactly like the copy cached on
+/// the way. The cover is fetched unless the album's, if already at hand, comes
+/// along.
+async fn stream_live(
+    state: &SharedState,
+    id: &str,
+    formats: &[SongFormat],
+    album: Option<(&AlbumHeader, Option<Picture>)>,
+) -> Result<(SongFormat, ByteStream), ApiError> {
+    let TrackStream { format, total_len, stream, page, .. } = state.deezer.get_stream(id.to_owned(), formats, None).await?;
+    let stream: ByteStream = stream
+        .map_ok(Bytes::from)
+        .map_err(std::io::Error::other)
+        .boxed();
+
+    let tags = track_tags(state, &page, album).await;
+    let audio_format = format.audio_format();
+    let stream = state.s3.cache_song(id, format, total_len, Tagger::new(audio_format, &tags), stream);
+
+    Ok((format, match Tagger::new(audio_format, &tags) {
+        Some(tagger) => tag_stream(tagger, stream),
+        None => stream,
+    }))
+}
+
+/// Streams the whole album as a ZIP: `NN - Title.ext` per track, `cover.jpg`
+/// and an M3U playlist. Tracks that can't be fetched are left out, one that
+/// fails midway ends up cut short in a still readable archive.
+pub async fn download_album(
+    Path(id): Path<String>,
+    Query(params): Query<StreamParams>,
+    _user: Authenticated,
+    State(state): State<SharedState>,
+) -> Result<Response<Body>, ApiError> {
+    let formats = params.formats()?;
+    let Album { album_header: header, songs } = get_album_and_add_to_db(id.clone(), state.clone()).await?;
+    let cover = state.deezer.get_cover(&header.img).await;
+    let album_name = zip::file_name(&header.alb_title);
+    let disposition = format!("attachment; filename=\"{}.zip\"", album_name);
+    let header = Arc::new(header);
+
+    let width = songs.data.len().to_string().len().max(2);
+    let tracks = {
+        let (cover, header) = (cover.clone(), header.clone());
+        futures::stream::iter(songs.data.into_iter().enumerate())
+            .map(move |(i, song)| {
+                let (state, header, cover, formats) = (state.clone(), header.clone(), cover.clone(), formats.clone());
+                // Spawned, so the next tracks start while the current one is being sent.
+                let task = tokio::spawn(async move {
+                    let res = open_track(state, &song.id, &formats, Some((&header, cover))).await;
+                    (song, res)
+                });
+                async move { (i, task.await) }
+            })
+            .buffered(ALBUM_DOWNLOAD_PARALLELISM)
+    };
+
+    let entries = async_stream::stream! {
+        if let Some(cover) = cover {
+            yield ZipEntry::from_bytes("cover.jpg", cover.data);
+        }
+
+        let mut playlist = String::from("#EXTM3U\n");
+        let mut tracks = std::pin::pin!(tracks);
+        while let Some((i, task)) = tracks.next().await {
+            let (song, res) = match task {
+                Ok(task) => task,
+                Err(e) => {
+                    eprintln!("Album {} track {} task failed: {}", id, i + 1, e);
+                    continue;
+                }
+            };
+            let (format, body) = match res {
+                Ok(track) => track,
+                Err(e) => {
+                    eprintln!("Leaving track {} out of album {}: {}", song.id, id, e);
+                    continue;
+                }
+            };
+
+            let name = format!("{:0width$} - {}.{}", i + 1, zip::file_name(&song.sng_title), format.extension());
+            let artists = song.artists.iter().map(|a| a.name.as_str()).collect::<Vec<_>>().join(", ");
+            let duration = song.duration.parse::<i64>().unwrap_or(-1);
+            playlist += &format!("#EXTINF:{},{} - {}\n{}\n", duration, artists, song.sng_title, name);
+
+            yield ZipEntry { name, body };
+        }
+
+        yield ZipEntry::from_bytes(format!("{}.m3u", album_name), playlist);
+    };
+
+    let mut response = Response::new(Body::from_stream(zip::zip_stream(entries)));
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/zip"));
+    if let Ok(disposition) = HeaderValue::from_str(&disposition) {
+        headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+
+    Ok(response)
+}
+
+pub async fn get_playlist(Path(id): Path<String>, State(state): State<SharedState>) -> Result<impl IntoResponse, ApiError> {
+    let deezer = state.deezer.clone();
     let postgres = state.postgres_db.clone();
 
-    let record = postgres.record_listening(id).await.map_err(|e| {
+    let res = deezer.get_playlist(&id).await?;
+
+    if let Err(e) = postgres.add_playlist_by_playlist(&res).await {
         eprintln!("{}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    }
+
+    Ok(Json(res))
+}
+
+pub async fn get_artist(Path(id): Path<String>, State(state): State<SharedState>) -> Result<impl IntoResponse, ApiError> {
+    let deezer = state.deezer.clone();
+    let postgres = state.postgres_db.clone();
+
+    let res = deezer.get_artist(&id).await?;
+
+    if let Err(e) = postgres.add_artist_albums(&res).await {
+        eprintln!("{}", e);
+    }
+
+    Ok(Json(res))
+}
+
+pub async fn record_listening(state: SharedState, id: i32, alb_id: Option<String>, user_id: Option<i64>) -> Result<bool, ApiError> {
+    let postgres = state.postgres_db.clone();
+
+    let record = postgres.record_listening(id, user_id).await?;
 
     match record {
         true => Ok(true),
         false => {
-            let deezer = state.deezer.clone();
-            
-            let alb_id = match alb_id {
-                Some(id) => id,
-                None => deezer.get_track_page(&id.to_string()).await.map_err(|e| {
-                    eprintln!("{}", e);
-                    StatusCode::BAD_REQUEST
-                })?.alb_id
-            };
-
-            get_album_and_add_to_db(alb_id, state).await?;
+            add_track_to_db(state, id, alb_id).await?;
 
-            let record = postgres.record_listening(id).await.map_err(|e| {
-                eprintln!("{}", e);
-                StatusCode::INTERNAL_SERVER_ERROR
-            })?;
+            let record = postgres.record_listening(id, user_id).await?;
 
             Ok(record)
         },
     }
 }
 
+/// Stores the album of a track, and with it the track. Without `alb_id` the
+/// album is looked up on the track's page.
+async fn add_track_to_db(state: SharedState, id: i32, alb_id: Option<String>) -> Result<(), ApiError> {
+    let alb_id = match alb_id {
+        Some(id) => id,
+        None => state.deezer.get_track_page(&id.to_string()).await?.alb_id
+    };
 
-const FLAC: &str = "flac";
-const MP3: &str = "mp3";
+    get_album_and_add_to_db(alb_id, state).await?;
 
+    Ok(())
+}
 
-pub async fn create_stream_from_body(body: Body, data_fromat: SongFormat) -> Result<Response<Body>, StatusCode> {
-    let format = match data_fromat {
-        SongFormat::FLAC => FLAC,
-        SongFormat::MP3 => MP3,
-    };
-    
-    let format = format!("attachment; filename=\"combined_data.{}\"", format);
-    
-    let response = Response::builder()
-      .status(StatusCode::OK)
-      .header(header::CONTENT_TYPE, "application/octet-stream")
-      .header(
-          header::CONTENT_DISPOSITION,
-          format,
-      )
-      .body(body)
-      .map_err(|e| {
-          eprintln!("Failed to build response: {:?}", e);
-          StatusCode::INTERNAL_SERVER_ERROR
-      })?;
+#[async_trait]
+impl TrackCatalog for SharedState {
+    fn provider(&self) -> &'static str {
+        "deezer"
+    }
 
-    Ok(response)
+    async fn add_track(&self, id: i32) -> Result<(), ProviderError> {
+        Ok(add_track_to_db(self.clone(), id, None).await?)
+    }
+
+    async fn add_album(&self, id: i32) -> Result<(), ProviderError> {
+        get_album_and_add_to_db(id.to_string(), self.clone()).await?;
+        Ok(())
+    }
 }
 
 
-pub async fn play(Path(id): Path<String>, State(state): State<SharedState>) -> Result<Response<Body>, StatusCode> {
-    let state_c =  state.clone();
-    let deezer = state.deezer.clone();
-    let postgres = state.postgres_db.clone();
-    let s3 = state.s3;
-    let id_i =  id.parse::<i32>().map_err(|e| {
-        StatusCode::BAD_REQUEST
-    })?;
-
-    match s3.try_get_song(&id).await {
-        Ok(stream) => {
-            let is_recorded = record_listening(state_c, id_i, None).await;
+pub fn create_stream_from_body(body: Body, id: &str, data_fromat: SongFormat) -> Response<Body> {
+    stream_response(body, id, data_fromat.extension(), data_fromat.mime_type(), data_fromat.api_name())
+}
 
-            let stream = stream.1.body.into_async_read();
-            let stream = ReaderStream::new(stream);
-            let body = Body::from_stream(stream);
+fn stream_response(body: Body, id: &str, extension: &str, mime_type: &'static str, format_name: &str) -> Response<Body> {
+    let disposition = format!("attachment; filename=\"{}.{}\"", id, extension);
 
-            create_stream_from_body(body, SongFormat::FLAC).await
-        }
-        Err(()) => {
-            let track_data = deezer.get_track_page(&id).await.map_err(|e| {
-                eprintln!("{}", e);
-                StatusCode::INTERNAL_SERVER_ERROR
-            })?;
-            
-            let (stream, record) = join!{
-                deezer.get_stream(id, Some(track_data.track_token)),
-                record_listening(state_c.clone(), id_i, Some(track_data.alb_id))
-            };
-            
-            let stream = stream.map_err(|e| {
-                eprintln!("{}", e);
-                StatusCode::INTERNAL_SERVER_ERROR
-            })?;
-            
-            let body = Body::from_stream(stream);
-
-            create_stream_from_body(body, SongFormat::FLAC).await
-        }
+    let mut response = Response::new(body);
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(mime_type));
+    if let Ok(format_name) = HeaderValue::from_str(format_name) {
+        headers.insert(AUDIO_FORMAT_HEADER, format_name);
+    }
+    if let Ok(disposition) = HeaderValue::from_str(&disposition) {
+        headers.insert(header::CONTENT_DISPOSITION, disposition);
     }
-    
 
-}
\ No newline at end of file
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use music_core::range;
+
+    #[test]
+    fn live_and_cached_responses_name_the_same_bytes() {
+        let mut live = create_stream_from_body(Body::empty(), "3135556", SongFormat::Flac);
+        set_live_headers(&mut live);
+        set_etag(&mut live, "3135556", SongFormat::Flac.api_name());
+
+        let total = 40_000_000;
+        let mut cached = create_stream_from_body(Body::empty(), "3135556", SongFormat::Flac);
+        set_range_headers(&mut cached, Some(range::content_range(1000, total - 1, total)), Some(total - 1000));
+        set_etag(&mut cached, "3135556", SongFormat::Flac.api_name());
+
+        assert_eq!(live.headers()[header::ACCEPT_RANGES], "none");
+        assert!(live.headers().get(header::CONTENT_LENGTH).is_none());
+        assert_eq!(cached.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(cached.headers()[header::ACCEPT_RANGES], "bytes");
+        assert_eq!(live.headers()[header::ETAG], cached.headers()[header::ETAG]);
+        assert_eq!(live.headers()[header::ETAG], "W/\"3135556-FLAC\"");
+    }
+}
@@ -1,73 +1,99 @@
 use aws_sdk_s3::Client;
-use aws_sdk_s3::config::{Credentials, Region};
 use aws_sdk_s3::operation::get_object::GetObjectOutput;
-use aws_smithy_types::error::metadata::ProvideErrorMetadata;
-use crate::deezer::SongFormat;
+use music_core::ByteStream;
+use music_core::config::S3Config;
+use music_core::range::ByteRange;
+use music_core::s3::{self, get_cached, new_s3_client, tee_to_s3, UploadTarget};
+use music_core::tags::Tagger;
+use music_core::transcode::TranscodeTarget;
+use crate::deezer::{ApiError, SongFormat};
+
+const BUCKET: &str = "deezer";
 
 #[derive(Debug, Clone)] 
 pub struct S3Client(Client);
 
 impl S3Client {
-  pub async fn new(url: &str, buckets: Vec<&str>) -> S3Client {
-    let region = Region::new("us-east-1");
+  pub async fn new(config: &S3Config, buckets: Vec<&str>) -> S3Client {
+    S3Client(new_s3_client(config, buckets).await)
+  }
+
+  fn track_key(id: &str, format: SongFormat) -> String {
+    format!("tracks/{}/{}.{}", id, format.api_name(), format.extension())
+  }
+  
+  /// Transcoded variants sit next to the track they were made from.
+  fn variant_key(id: &str, source: SongFormat, target: TranscodeTarget) -> String {
+    format!("tracks/{}/{}.{}.{}", id, source.api_name(), target.name(), target.codec.audio_format().extension())
+  }
+
+  /// Looks up the first of `formats` that is already cached, `None` when
+  /// none is.
+  pub async fn try_get_song(&self, id: &str, formats: &[SongFormat], range: Option<ByteRange>) -> Result<Option<(SongFormat, GetObjectOutput)>, ApiError> {
+    for &format in formats {
+      if let Some(file) = get_cached(&self.0, BUCKET, &Self::track_key(id, format), range).await? {
+        return Ok(Some((format, file)))
+      }
+    }
 
-    let credentials = Credentials::new(
-      "minioadmin",
-      "minioadmin",
-      None,
-      None,
-      "Static",
-    );
+    Ok(None)
+  }
 
-    // Build the AWS SDK config with all necessary settings for MinIO.
-    let config = aws_config::defaults(aws_config::BehaviorVersion::latest())
-      .region(region)
-      .credentials_provider(credentials)
-      .endpoint_url(url)
-      .load()
-      .await;
+  /// Stores the full decrypted track while it's being streamed, tagged by
+  /// `tagger` if there is one. The cached object only appears once the whole
+  /// stream went through.
+  pub fn cache_song(&self, id: &str, format: SongFormat, total_len: Option<u64>, tagger: Option<Tagger>, stream: ByteStream) -> ByteStream {
+    let target = UploadTarget {
+      client: self.0.clone(),
+      bucket: BUCKET.to_owned(),
+      key: Self::track_key(id, format),
+      content_type: format.mime_type().to_owned(),
+      expected_len: total_len,
+      tagger,
+    };
 
-    // Create the S3 client with force_path_style enabled.
-    let s3_config = aws_sdk_s3::config::Builder::from(&config)
-      .force_path_style(true)
-      .build();
+    tee_to_s3(target, stream)
+  }
 
-    let client = Client::from_conf(s3_config);
+  /// Stores the full decrypted track like `cache_song`, but without passing
+  /// it on. Returns once the cached object is there.
+  pub async fn store_song(&self, id: &str, format: SongFormat, total_len: Option<u64>, tagger: Option<Tagger>, stream: ByteStream) -> Result<(), ApiError> {
+    let target = UploadTarget {
+      client: self.0.clone(),
+      bucket: BUCKET.to_owned(),
+      key: Self::track_key(id, format),
+      content_type: format.mime_type().to_owned(),
+      expected_len: total_len,
+      tagger,
+    };
 
-    // Create buckets.
-    for bucket_name in buckets {
-      match client.create_bucket().bucket(bucket_name).send().await {
-        Ok(_) => println!("Bucket '{}' created successfully.", bucket_name),
-        Err(e) => {
-          if let Some(service_error) = e.as_service_error() {
-            if service_error.is_bucket_already_owned_by_you() || service_error.code() == Some("BucketAlreadyExists") {
-              println!("Bucket '{}' already exists and is owned by you.", bucket_name);
-            } else {
-              panic!("Service error creating bucket '{}': {}", bucket_name, service_error);
-            }
-          } else {
-            // This could be a network error like a "dispatch failure".
-            panic!("Error creating bucket '{}': {}", bucket_name, e);
-          }
-        }
+    s3::upload(target, stream).await
+      .map_err(|e| ApiError::StorageError(e.to_string()))
+  }
+
+  /// Looks up `target` transcoded from the first of `formats` it's cached for.
+  pub async fn try_get_variant(&self, id: &str, formats: &[SongFormat], target: TranscodeTarget, range: Option<ByteRange>) -> Result<Option<(SongFormat, GetObjectOutput)>, ApiError> {
+    for &format in formats {
+      if let Some(file) = get_cached(&self.0, BUCKET, &Self::variant_key(id, format, target), range).await? {
+        return Ok(Some((format, file)))
       }
     }
 
-    S3Client(client)
+    Ok(None)
   }
-  
-  pub async fn try_get_song(&self, id: &str) -> Result<(SongFormat, GetObjectOutput), ()> {
-    // let key = format!("tracks/{}.mp3", id);
-    // if let Ok(file) = self.0.get_object().bucket("soundcloud").key(key).send().await { 
-    //   return Ok(file)
-    // }
-    let key = format!("tracks/{}.flac", id);
-    
-    if let Ok(file) = self.0.get_object().bucket("soundcloud").key(key).send().await {
-      return Ok((SongFormat::FLAC, file))
-    }
-    
-    Err(())
+
+  /// Stores a transcoded track while it's being streamed, like `cache_song`.
+  pub fn cache_variant(&self, id: &str, source: SongFormat, target: TranscodeTarget, stream: ByteStream) -> ByteStream {
+    let format = target.codec.audio_format();
+    let target = UploadTarget {
+      client: self.0.clone(),
+      bucket: BUCKET.to_owned(),
+      key: Self::variant_key(id, source, target),
+      content_type: format.mime_type().to_owned(),
+      expected_len: None,
+      tagger: None,
+    };
+
+    tee_to_s3(target, stream)
   }
 }
-
@@ -0,0 +1,45 @@
+use std::net::SocketAddr;
+use serde::Deserialize;
+use music_core::config::{self, ConfigError, S3Config, Secret, SessionConfig, TranscodeConfig};
+
+fn default_bind_addr() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 3000))
+}
+
+/// Gateway settings, read from `GATEWAY_*` env vars and the optional TOML
+/// file in `GATEWAY_CONFIG`. Both providers share the database and storage.
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    pub deezer_arls: Vec<Secret>,
+    pub soundcloud_client_id: Option<String>,
+    pub database_url: Secret,
+    #[serde(flatten)]
+    pub s3: S3Config,
+    #[serde(flatten)]
+    pub sessions: SessionConfig,
+    #[serde(flatten)]
+    pub transcoding: TranscodeConfig,
+    #[serde(default = "default_bind_addr")]
+    pub bind_addr: SocketAddr,
+}
+
+impl Config {
+    pub const ENV_PREFIX: &'static str = "GATEWAY_";
+
+    pub fn load() -> Result<Self, ConfigError> {
+        let config: Config = config::load(Self::ENV_PREFIX)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        deezer_service::config::validate_arls(&self.deezer_arls)?;
+        if let Some(client_id) = &self.soundcloud_client_id {
+            soundcloud_service::config::validate_client_id(client_id)?;
+        }
+        config::validate_database_url(&self.database_url)?;
+        self.s3.validate()?;
+        self.sessions.validate()?;
+        self.transcoding.validate()
+    }
+}
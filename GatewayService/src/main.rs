@@ -0,0 +1,125 @@
+mod config;
+mod ranking;
+mod routs;
+
+use std::sync::Arc;
+use axum::Router;
+use axum::routing::get;
+use dotenvy::dotenv;
+use music_core::MusicProvider;
+use music_core::s3::new_s3_client;
+use music_core::sessions::{SessionStore, SessionStoreState};
+use music_core::library::{self, LibraryState, LibraryStore, TrackCatalog};
+use music_core::transcode::Transcoder;
+use music_core::users::{self, UserStore, UserStoreState};
+use deezer_service::deezer::Deezer;
+use soundcloud_service::soundcloud_api::SoundCloudApi;
+use crate::config::Config;
+use crate::routs::{search, stream};
+
+#[derive(Clone)]
+pub struct GatewayState {
+    providers: Vec<Arc<dyn MusicProvider>>,
+    users: Arc<UserStore>,
+    sessions: Arc<dyn SessionStore>,
+    library: Arc<LibraryStore>,
+    catalogs: Vec<Arc<dyn TrackCatalog>>,
+}
+
+impl UserStoreState for GatewayState {
+    fn user_store(&self) -> &UserStore {
+        &self.users
+    }
+}
+
+impl SessionStoreState for GatewayState {
+    fn session_store(&self) -> &dyn SessionStore {
+        self.sessions.as_ref()
+    }
+}
+
+impl LibraryState for GatewayState {
+    fn library(&self) -> &LibraryStore {
+        &self.library
+    }
+
+    fn catalog(&self, provider: &str) -> Option<&dyn TrackCatalog> {
+        self.catalogs.iter().find(|c| c.provider() == provider).map(|c| c.as_ref())
+    }
+}
+
+impl GatewayState {
+    fn provider(&self, name: &str) -> Option<&Arc<dyn MusicProvider>> {
+        self.providers.iter().find(|p| p.name() == name)
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    dotenv().ok();
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(1);
+    });
+    println!("Starting with {:?}", config);
+    let database_url = config.database_url.expose();
+
+    let deezer_db = Arc::new(deezer_service::postgres_service::PostgresDb::new(database_url).await);
+    // One user store for all routes, a token works for every provider.
+    let users = Arc::new(UserStore::new(deezer_db.pool().clone()));
+    let library = Arc::new(LibraryStore::new(deezer_db.pool().clone()));
+
+    let deezer = Deezer::new(config.deezer_arls.iter().map(|arl| arl.expose().to_owned()).collect());
+    let sessions = config.sessions.connect().await.unwrap_or_else(|e| {
+        eprintln!("Failed to connect to the session store: {}", e);
+        std::process::exit(1);
+    });
+    let transcoder = Transcoder::new(&config.transcoding);
+    transcoder.probe().await.unwrap_or_else(|e| {
+        eprintln!("Transcoding is enabled but ffmpeg isn't usable: {}", e);
+        std::process::exit(1);
+    });
+    let deezer_state = deezer_service::SharedState::new(
+        deezer.clone(),
+        deezer_db,
+        deezer_service::s3_client::S3Client::new(&config.s3, vec!["deezer"]).await,
+        users.clone(),
+        sessions.clone(),
+        transcoder,
+    );
+
+    // Without an id the current one is discovered from soundcloud.com.
+    let soundcloud = match &config.soundcloud_client_id {
+        Some(client_id) => SoundCloudApi::new().with_client_id(client_id),
+        None => SoundCloudApi::new(),
+    };
+    let soundcloud = Arc::new(soundcloud);
+    let soundcloud_state = Arc::new(soundcloud_service::SharedState::new(
+        soundcloud.clone(),
+        Arc::new(soundcloud_service::postgres_service::PostgresDb::new(database_url).await),
+        new_s3_client(&config.s3, vec!["soundcloud"]).await,
+        users.clone(),
+        sessions.clone(),
+    ));
+
+    let gateway_state = GatewayState {
+        providers: vec![Arc::new(deezer), soundcloud],
+        users,
+        sessions,
+        library,
+        // Library items are resolved against the tables the services fill.
+        catalogs: vec![Arc::new(deezer_state.clone()), soundcloud_state.clone()],
+    };
+
+    let app = Router::new()
+        .route("/search", get(search))
+        .route("/stream/{id}", get(stream))
+        .nest("/users", users::router())
+        .nest("/library", library::router())
+        .with_state(gateway_state)
+        .nest("/deezer", deezer_service::router(deezer_state))
+        .nest("/soundcloud", soundcloud_service::router(soundcloud_state));
+
+    let listener = tokio::net::TcpListener::bind(config.bind_addr).await.unwrap();
+    axum::serve(listener, app).await.unwrap();
+}
@@ -0,0 +1,186 @@
+use serde::Serialize;
+use music_core::{Album, Artist, Playlist, QualifiedId, SearchKind, SearchResults, Track};
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum SearchItem {
+    Track(Track),
+    Album(Album),
+    Artist(Artist),
+    Playlist(Playlist),
+}
+
+#[derive(Serialize)]
+pub struct RankedItem {
+    pub id: String,
+    pub provider: &'static str,
+    pub kind: SearchKind,
+    pub score: f32,
+    pub item: SearchItem,
+}
+
+fn tokens(text: &str) -> Vec<String> {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+fn artist_names(artists: &[Artist]) -> String {
+    artists.iter().map(|a| a.name.as_str()).collect::<Vec<_>>().join(" ")
+}
+
+/// Share of query tokens found in the title or credits, a bonus for an exact
+/// title match and a small bias towards the provider's own ordering.
+fn score(query: &[String], title: &str, credits: &str, position: usize) -> f32 {
+    let title_tokens = tokens(title);
+    let credit_tokens = tokens(credits);
+
+    let matched = query.iter()
+        .filter(|t| title_tokens.contains(t) || credit_tokens.contains(t))
+        .count() as f32 / query.len().max(1) as f32;
+    let exact = if !query.is_empty() && title_tokens == query { 0.5 } else { 0.0 };
+
+    matched + exact + 0.25 / (position + 1) as f32
+}
+
+/// Scores every result of one provider and appends it to `out` with a
+/// provider-qualified id.
+pub fn rank(provider: &'static str, query: &str, results: SearchResults, out: &mut Vec<RankedItem>) {
+    let query = tokens(query);
+    let mut push = |id: &str, kind: SearchKind, score: f32, item: SearchItem| {
+        out.push(RankedItem {
+            id: QualifiedId::new(provider, id).to_string(),
+            provider,
+            kind,
+            score,
+            item,
+        });
+    };
+
+    for (i, track) in results.tracks.into_iter().enumerate() {
+        let score = score(&query, &track.title, &artist_names(&track.artists), i);
+        push(&track.id.clone(), SearchKind::Track, score, SearchItem::Track(track));
+    }
+    for (i, album) in results.albums.into_iter().enumerate() {
+        let score = score(&query, &album.title, &artist_names(&album.artists), i);
+        push(&album.id.clone(), SearchKind::Album, score, SearchItem::Album(album));
+    }
+    for (i, artist) in results.artists.into_iter().enumerate() {
+        let score = score(&query, &artist.name, "", i);
+        push(&artist.id.clone(), SearchKind::Artist, score, SearchItem::Artist(artist));
+    }
+    for (i, playlist) in results.playlists.into_iter().enumerate() {
+        let owner = playlist.owner.as_ref().map(|o| o.name.clone()).unwrap_or_default();
+        let score = score(&query, &playlist.title, &owner, i);
+        push(&playlist.id.clone(), SearchKind::Playlist, score, SearchItem::Playlist(playlist));
+    }
+}
+
+/// Best first, `limit` results from `offset` on.
+pub fn page(mut results: Vec<RankedItem>, offset: u32, limit: u32) -> Vec<RankedItem> {
+    results.sort_by(|a, b| b.score.total_cmp(&a.score));
+    results.into_iter().skip(offset as usize).take(limit as usize).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn artist(name: &str) -> Artist {
+        Artist { id: name.to_lowercase(), name: name.to_owned(), picture: None }
+    }
+
+    fn track(id: &str, title: &str, artist_name: &str) -> Track {
+        Track {
+            id: id.to_owned(),
+            title: title.to_owned(),
+            artists: vec![artist(artist_name)],
+            album_id: None,
+            album_title: None,
+            duration_ms: 0,
+            artwork: None,
+        }
+    }
+
+    fn ranked(provider: &'static str, query: &str, tracks: Vec<Track>) -> Vec<RankedItem> {
+        let mut out = Vec::new();
+        rank(provider, query, SearchResults { tracks, ..SearchResults::default() }, &mut out);
+        out
+    }
+
+    fn ids(results: &[RankedItem]) -> Vec<&str> {
+        results.iter().map(|item| item.id.as_str()).collect()
+    }
+
+    #[test]
+    fn tokens_ignore_case_and_punctuation() {
+        assert_eq!(tokens("Don't Stop—Me NOW!"), ["don", "t", "stop", "me", "now"]);
+        assert!(tokens(" -- ").is_empty());
+    }
+
+    #[test]
+    fn query_tokens_match_title_or_credits() {
+        let query = tokens("daft punk around");
+        // All tokens, one from the title and two from the credits.
+        let all = score(&query, "Around the World", "Daft Punk", 1000);
+        let some = score(&query, "Around the World", "Someone Else", 1000);
+        let none = score(&query, "Something", "Someone Else", 1000);
+
+        assert!((all - 1.0).abs() < 0.001, "{}", all);
+        assert!((some - 1.0 / 3.0).abs() < 0.001, "{}", some);
+        assert!(none < 0.001, "{}", none);
+    }
+
+    #[test]
+    fn exact_titles_get_a_bonus() {
+        let query = tokens("one more time");
+        let exact = score(&query, "One More Time", "", 0);
+        let longer = score(&query, "One More Time (Radio Edit)", "", 0);
+
+        assert!((exact - longer - 0.5).abs() < 0.001, "{} vs {}", exact, longer);
+        // An empty query matches no title exactly.
+        assert_eq!(score(&[], "", "", 0), 0.25);
+    }
+
+    #[test]
+    fn earlier_provider_results_rank_higher_on_ties() {
+        assert!(score(&[], "a", "", 0) > score(&[], "a", "", 1));
+        assert!(score(&[], "a", "", 1) > score(&[], "a", "", 10));
+        // The bias never outweighs a matched token.
+        let query = tokens("intro");
+        assert!(score(&query, "Intro", "", 50) > score(&query, "Outro", "", 0));
+    }
+
+    #[test]
+    fn rank_qualifies_ids() {
+        let results = ranked("deezer", "intro", vec![track("1", "Intro", "xx")]);
+        assert_eq!(ids(&results), ["deezer:1"]);
+        assert_eq!(results[0].provider, "deezer");
+    }
+
+    /// Four exact matches from one provider, four partial ones from the other.
+    fn merged() -> Vec<RankedItem> {
+        let tracks = |title: &str| (0..4).map(|i| track(&i.to_string(), title, "x")).collect();
+        let mut results = ranked("deezer", "song", tracks("Song"));
+        results.extend(ranked("soundcloud", "song", tracks("Song Remix")));
+        results
+    }
+
+    #[test]
+    fn pages_follow_the_merged_ranking() {
+        let all: Vec<String> = ids(&page(merged(), 0, 8)).into_iter().map(str::to_owned).collect();
+        assert_eq!(all, [
+            "deezer:0", "deezer:1", "deezer:2", "deezer:3",
+            "soundcloud:0", "soundcloud:1", "soundcloud:2", "soundcloud:3",
+        ]);
+
+        // Consecutive pages neither skip nor repeat results.
+        let paged: Vec<String> = [0, 3, 6].into_iter()
+            .flat_map(|offset| ids(&page(merged(), offset, 3)).into_iter().map(str::to_owned).collect::<Vec<_>>())
+            .collect();
+        assert_eq!(paged, all);
+        assert!(page(merged(), 8, 3).is_empty());
+    }
+}
@@ -0,0 +1,97 @@
+use axum::extract::{Path, Query, RawQuery, State};
+use axum::http::StatusCode;
+use axum::Json;
+use axum::response::{IntoResponse, Redirect};
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use music_core::{QualifiedId, SearchKind, SearchQuery};
+use crate::GatewayState;
+use crate::ranking::{page, rank, RankedItem};
+
+fn default_limit() -> u32 {
+    20
+}
+
+/// Every provider is asked for results up to the end of the requested page,
+/// this many at most, which is as far as the providers page in one request.
+const MAX_SEARCH_WINDOW: u32 = 100;
+
+#[derive(Deserialize, Debug)]
+pub struct SearchParams {
+    q: String,
+    #[serde(rename = "type")]
+    kind: Option<SearchKind>,
+    #[serde(default = "default_limit")]
+    limit: u32,
+    #[serde(default)]
+    offset: u32,
+}
+
+#[derive(Serialize)]
+pub struct ProviderFailure {
+    provider: &'static str,
+    error: String,
+}
+
+#[derive(Serialize)]
+pub struct MergedSearchResponse {
+    results: Vec<RankedItem>,
+    errors: Vec<ProviderFailure>,
+}
+
+/// Merges the results of every provider. The pages are cut from the merged
+/// ranking, so each provider is searched from its first result on.
+pub async fn search(Query(params): Query<SearchParams>, State(state): State<GatewayState>) -> Result<impl IntoResponse, StatusCode> {
+    let window = params.offset.checked_add(params.limit)
+        .filter(|window| *window <= MAX_SEARCH_WINDOW)
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    let query = SearchQuery {
+        q: params.q,
+        kind: params.kind,
+        limit: window,
+        offset: 0,
+    };
+
+    let responses = join_all(state.providers.iter().map(|provider| {
+        let query = &query;
+        async move { (provider.name(), provider.search(query).await) }
+    })).await;
+
+    let mut results = Vec::new();
+    let mut errors = Vec::new();
+    for (provider, response) in responses {
+        match response {
+            Ok(res) => rank(provider, &query.q, res, &mut results),
+            Err(e) => {
+                eprintln!("Search on {} failed: {}", provider, e);
+                errors.push(ProviderFailure { provider, error: e.to_string() });
+            }
+        }
+    }
+
+    if results.is_empty() && errors.len() == state.providers.len() {
+        return Err(StatusCode::BAD_GATEWAY);
+    }
+
+    let results = page(results, params.offset, params.limit);
+    Ok(Json(MergedSearchResponse { results, errors }))
+}
+
+/// Redirects `/stream/deezer:123` to the provider's own `/deezer/stream/123`,
+/// so caching and listen recording stay in one place.
+pub async fn stream(
+    Path(id): Path<String>,
+    RawQuery(query): RawQuery,
+    State(state): State<GatewayState>,
+) -> Result<Redirect, StatusCode> {
+    let id: QualifiedId = id.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+    let provider = state.provider(&id.provider).ok_or(StatusCode::NOT_FOUND)?;
+
+    let mut location = format!("/{}/stream/{}", provider.name(), id.id);
+    if let Some(query) = query {
+        location.push('?');
+        location.push_str(&query);
+    }
+
+    Ok(Redirect::temporary(&location))
+}
@@ -0,0 +1,88 @@
+use std::sync::Arc;
+use regex::Regex;
+use reqwest::{Client, Url};
+use tokio::sync::{Mutex, RwLock};
+use crate::soundcloud_api::ApiError;
+
+/// Finds the public client_id the soundcloud.com web app uses and keeps it
+/// until SoundCloud rejects it.
+#[derive(Clone)]
+pub struct ClientIdProvider {
+    client: Client,
+    web_url: Url,
+    client_id: Arc<RwLock<Option<String>>>,
+    update_lock: Arc<Mutex<()>>,
+    script_re: Regex,
+    client_id_re: Regex,
+}
+
+impl ClientIdProvider {
+    pub fn new(client: Client, web_url: Url) -> Self {
+        Self {
+            client,
+            web_url,
+            client_id: Arc::new(RwLock::new(None)),
+            update_lock: Arc::new(Mutex::new(())),
+            script_re: Regex::new(r#"<script[^>]+src="([^"]+\.js)""#).unwrap(),
+            client_id_re: Regex::new(r#"client_id\s*[:=]\s*"([0-9A-Za-z]{32})""#).unwrap(),
+        }
+    }
+
+    /// Starts with a known client_id, it's still replaced once rejected.
+    pub fn with_client_id(self, client_id: &str) -> Self {
+        Self {
+            client_id: Arc::new(RwLock::new(Some(client_id.to_owned()))),
+            ..self
+        }
+    }
+
+    pub async fn get(&self) -> Result<String, ApiError> {
+        if let Some(client_id) = self.client_id.read().await.clone() {
+            return Ok(client_id);
+        }
+
+        self.refresh(None).await
+    }
+
+    /// Discovers a new client_id unless another request already replaced
+    /// `stale` in the meantime.
+    pub async fn refresh(&self, stale: Option<&str>) -> Result<String, ApiError> {
+        let _lock_guard = self.update_lock.lock().await;
+
+        if let Some(current) = self.client_id.read().await.clone()
+            && Some(current.as_str()) != stale {
+            println!("SoundCloud client_id was already updated by another request.");
+            return Ok(current);
+        }
+
+        let client_id = self.discover().await?;
+        println!("Discovered new SoundCloud client_id.");
+        *self.client_id.write().await = Some(client_id.clone());
+
+        Ok(client_id)
+    }
+
+    /// The id is set in one of the app's JS bundles, usually one of the last.
+    async fn discover(&self) -> Result<String, ApiError> {
+        let page = self.client.get(self.web_url.clone()).send().await?.error_for_status()?.text().await?;
+        let scripts: Vec<Url> = self.script_re.captures_iter(&page)
+            .filter_map(|c| self.web_url.join(&c[1]).ok())
+            .collect();
+
+        for script in scripts.into_iter().rev() {
+            let bundle = match self.client.get(script.clone()).send().await.and_then(|r| r.error_for_status()) {
+                Ok(res) => res.text().await?,
+                Err(e) => {
+                    eprintln!("Failed to load {}: {}", script, e);
+                    continue;
+                }
+            };
+
+            if let Some(captures) = self.client_id_re.captures(&bundle) {
+                return Ok(captures[1].to_owned());
+            }
+        }
+
+        Err(ApiError::ClientIdUnavailable(format!("No client_id found in the scripts of {}", self.web_url)))
+    }
+}
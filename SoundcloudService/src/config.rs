@@ -0,0 +1,48 @@
+use std::net::SocketAddr;
+use serde::Deserialize;
+use music_core::config::{self, ConfigError, S3Config, Secret, SessionConfig};
+
+fn default_bind_addr() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 3000))
+}
+
+/// SoundcloudService settings, read from `SOUNDCLOUD_*` env vars and the
+/// optional TOML file in `SOUNDCLOUD_CONFIG`.
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    /// Skips client_id discovery until SoundCloud rejects this one.
+    pub client_id: Option<String>,
+    pub database_url: Secret,
+    #[serde(flatten)]
+    pub s3: S3Config,
+    #[serde(flatten)]
+    pub sessions: SessionConfig,
+    #[serde(default = "default_bind_addr")]
+    pub bind_addr: SocketAddr,
+}
+
+impl Config {
+    pub const ENV_PREFIX: &'static str = "SOUNDCLOUD_";
+
+    pub fn load() -> Result<Self, ConfigError> {
+        let config: Config = config::load(Self::ENV_PREFIX)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(client_id) = &self.client_id {
+            validate_client_id(client_id)?;
+        }
+        config::validate_database_url(&self.database_url)?;
+        self.s3.validate()?;
+        self.sessions.validate()
+    }
+}
+
+pub fn validate_client_id(client_id: &str) -> Result<(), ConfigError> {
+    if client_id.len() != 32 || !client_id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(ConfigError::invalid("client_id", "must be 32 alphanumeric characters"));
+    }
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use std::sync::Arc;
+use axum::extract::{Path, Query, State};
+use axum::Json;
+use axum::response::IntoResponse;
+use music_core::history::{ActivityParams, Page, PageParams, Scope, Window};
+use music_core::users::AuthUser;
+use crate::SharedState;
+use crate::soundcloud_api::ApiError;
+
+pub async fn get_recent(
+    Query(scope): Query<Scope>,
+    Query(window): Query<Window>,
+    Query(page): Query<PageParams>,
+    user: Option<AuthUser>,
+    State(state): State<Arc<SharedState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let user_id = scope.user_id(user.as_ref())?;
+    let listens = state.postgres_db.recent_listens(user_id, window, page).await?;
+
+    Ok(Json(Page::new(listens, page)))
+}
+
+pub async fn get_top_tracks(
+    Query(scope): Query<Scope>,
+    Query(window): Query<Window>,
+    Query(page): Query<PageParams>,
+    user: Option<AuthUser>,
+    State(state): State<Arc<SharedState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let user_id = scope.user_id(user.as_ref())?;
+    let tracks = state.postgres_db.top_tracks(user_id, window, page).await?;
+
+    Ok(Json(Page::new(tracks, page)))
+}
+
+pub async fn get_top_artists(
+    Query(scope): Query<Scope>,
+    Query(window): Query<Window>,
+    Query(page): Query<PageParams>,
+    user: Option<AuthUser>,
+    State(state): State<Arc<SharedState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let user_id = scope.user_id(user.as_ref())?;
+    let authors = state.postgres_db.top_authors(user_id, window, page).await?;
+
+    Ok(Json(Page::new(authors, page)))
+}
+
+pub async fn get_track_listens(
+    Path(id): Path<i32>,
+    Query(scope): Query<Scope>,
+    user: Option<AuthUser>,
+    State(state): State<Arc<SharedState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let user_id = scope.user_id(user.as_ref())?;
+    let count = state.postgres_db.track_listen_count(id, user_id).await?
+        .ok_or_else(|| ApiError::NotFound(format!("track {}", id)))?;
+
+    Ok(Json(count))
+}
+
+pub async fn get_activity(
+    Query(scope): Query<Scope>,
+    Query(params): Query<ActivityParams>,
+    Query(window): Query<Window>,
+    user: Option<AuthUser>,
+    State(state): State<Arc<SharedState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let user_id = scope.user_id(user.as_ref())?;
+
+    Ok(Json(state.postgres_db.listen_activity(user_id, params, window).await?))
+}
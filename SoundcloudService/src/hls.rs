@@ -0,0 +1,451 @@
+//! Just enough of RFC 8216 to play SoundCloud's HLS transcodings: media
+//! playlists with segment durations, byte ranges, `EXT-X-MAP` init sections
+//! and AES-128 encrypted segments.
+
+use aes::Aes128;
+use cbc::cipher::block_padding::Pkcs7;
+use cbc::cipher::{BlockDecryptMut, KeyIvInit};
+use music_core::range::ByteRange;
+use reqwest::Url;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum HlsError {
+    #[error("Playlist doesn't start with #EXTM3U")]
+    NotAPlaylist,
+
+    #[error("Master playlists are not supported")]
+    MasterPlaylist,
+
+    #[error("Invalid {tag} on line {line}")]
+    InvalidTag { tag: &'static str, line: usize },
+
+    #[error("Segment on line {0} has no #EXTINF")]
+    MissingDuration(usize),
+
+    #[error("Unsupported encryption method {0}")]
+    UnsupportedEncryption(String),
+
+    #[error("Invalid URI '{0}'")]
+    InvalidUri(String),
+
+    #[error("Key must be 16 bytes, got {0}")]
+    InvalidKey(usize),
+
+    #[error("Failed to decrypt segment")]
+    Decryption,
+}
+
+/// `<length>@<offset>` sub-range of a resource.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubRange {
+    pub length: u64,
+    pub offset: u64,
+}
+
+impl SubRange {
+    fn parse(value: &str, previous_end: Option<u64>) -> Option<Self> {
+        let (length, offset) = match value.split_once('@') {
+            Some((length, offset)) => (length.parse().ok()?, offset.parse().ok()?),
+            // Without an offset the range continues the previous one.
+            None => (value.parse().ok()?, previous_end?),
+        };
+
+        (length > 0).then_some(Self { length, offset })
+    }
+
+    fn end(&self) -> u64 {
+        self.offset + self.length
+    }
+
+    pub fn to_range(&self) -> ByteRange {
+        ByteRange::FromTo(self.offset, self.end() - 1)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Aes128Key {
+    pub uri: Url,
+    pub iv: Option<[u8; 16]>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InitSection {
+    pub uri: Url,
+    pub range: Option<SubRange>,
+    pub key: Option<Aes128Key>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub uri: Url,
+    pub duration: f64,
+    pub range: Option<SubRange>,
+    pub key: Option<Aes128Key>,
+    pub init: Option<InitSection>,
+    pub sequence: u64,
+}
+
+impl Segment {
+    /// Explicit IV of the key, or the media sequence number as a 128-bit
+    /// big-endian integer.
+    pub fn iv(&self) -> Option<[u8; 16]> {
+        let key = self.key.as_ref()?;
+        Some(key.iv.unwrap_or_else(|| sequence_iv(self.sequence)))
+    }
+}
+
+fn sequence_iv(sequence: u64) -> [u8; 16] {
+    let mut iv = [0; 16];
+    iv[8..].copy_from_slice(&sequence.to_be_bytes());
+    iv
+}
+
+/// A piece of the output stream: what to fetch and how to decrypt it.
+#[derive(Debug, Clone)]
+pub struct Resource {
+    pub uri: Url,
+    pub range: Option<SubRange>,
+    pub key: Option<(Url, [u8; 16])>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MediaPlaylist {
+    pub target_duration: Option<u64>,
+    pub segments: Vec<Segment>,
+    pub ended: bool,
+}
+
+/// Splits an attribute list like `METHOD=AES-128,URI="https://..."`, keeping
+/// commas inside quoted strings.
+fn attributes(list: &str) -> Vec<(&str, &str)> {
+    let mut attrs = Vec::new();
+    let mut rest = list.trim();
+
+    while let Some((name, value)) = rest.split_once('=') {
+        let (value, tail) = match value.strip_prefix('"') {
+            Some(quoted) => match quoted.split_once('"') {
+                Some((value, tail)) => (value, tail),
+                None => (quoted, ""),
+            },
+            None => value.split_once(',').map_or((value, ""), |(value, tail)| (value, tail)),
+        };
+
+        attrs.push((name.trim(), value));
+        rest = tail.trim_start_matches(',').trim_start();
+    }
+
+    attrs
+}
+
+fn attribute<'a>(attrs: &[(&str, &'a str)], name: &str) -> Option<&'a str> {
+    attrs.iter().find(|(n, _)| *n == name).map(|(_, v)| *v)
+}
+
+fn parse_iv(value: &str) -> Option<[u8; 16]> {
+    let hex = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X"))?;
+    let value = u128::from_str_radix(hex, 16).ok()?;
+    Some(value.to_be_bytes())
+}
+
+fn resolve(base: &Url, uri: &str) -> Result<Url, HlsError> {
+    base.join(uri).map_err(|_| HlsError::InvalidUri(uri.to_owned()))
+}
+
+impl MediaPlaylist {
+    /// Parses a media playlist, relative URIs are resolved against `base`.
+    pub fn parse(base: &Url, text: &str) -> Result<Self, HlsError> {
+        let mut lines = text.lines().enumerate()
+            .map(|(i, line)| (i + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty());
+
+        if lines.next().map(|(_, line)| line) != Some("#EXTM3U") {
+            return Err(HlsError::NotAPlaylist);
+        }
+
+        let mut playlist = MediaPlaylist::default();
+        let mut media_sequence = 0;
+        let mut duration = None;
+        let mut range_value = None;
+        let mut key = None;
+        let mut init = None;
+        // End of the last sub-range per URI, for `EXT-X-BYTERANGE` without offset.
+        let mut previous_end: Option<(Url, u64)> = None;
+
+        for (line_no, line) in lines {
+            let invalid = |tag| HlsError::InvalidTag { tag, line: line_no };
+
+            if let Some(value) = line.strip_prefix("#EXTINF:") {
+                let value = value.split_once(',').map_or(value, |(d, _)| d);
+                duration = Some(value.trim().parse::<f64>().map_err(|_| invalid("EXTINF"))?);
+            } else if let Some(value) = line.strip_prefix("#EXT-X-BYTERANGE:") {
+                range_value = Some(value.to_owned());
+            } else if let Some(value) = line.strip_prefix("#EXT-X-TARGETDURATION:") {
+                playlist.target_duration = Some(value.parse().map_err(|_| invalid("EXT-X-TARGETDURATION"))?);
+            } else if let Some(value) = line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
+                media_sequence = value.parse().map_err(|_| invalid("EXT-X-MEDIA-SEQUENCE"))?;
+            } else if let Some(value) = line.strip_prefix("#EXT-X-KEY:") {
+                let attrs = attributes(value);
+                key = match attribute(&attrs, "METHOD") {
+                    Some("NONE") => None,
+                    Some("AES-128") => {
+                        let uri = attribute(&attrs, "URI").ok_or_else(|| invalid("EXT-X-KEY"))?;
+                        let iv = match attribute(&attrs, "IV") {
+                            Some(iv) => Some(parse_iv(iv).ok_or_else(|| invalid("EXT-X-KEY"))?),
+                            None => None,
+                        };
+                        Some(Aes128Key { uri: resolve(base, uri)?, iv })
+                    }
+                    Some(method) => return Err(HlsError::UnsupportedEncryption(method.to_owned())),
+                    None => return Err(invalid("EXT-X-KEY")),
+                };
+            } else if let Some(value) = line.strip_prefix("#EXT-X-MAP:") {
+                let attrs = attributes(value);
+                let uri = resolve(base, attribute(&attrs, "URI").ok_or_else(|| invalid("EXT-X-MAP"))?)?;
+                let range = match attribute(&attrs, "BYTERANGE") {
+                    Some(value) => Some(SubRange::parse(value, None).ok_or_else(|| invalid("EXT-X-MAP"))?),
+                    None => None,
+                };
+                init = Some(InitSection { uri, range, key: key.clone() });
+            } else if line == "#EXT-X-ENDLIST" {
+                playlist.ended = true;
+            } else if line.starts_with("#EXT-X-STREAM-INF") {
+                return Err(HlsError::MasterPlaylist);
+            } else if !line.starts_with('#') {
+                let uri = resolve(base, line)?;
+                let duration = duration.take().ok_or(HlsError::MissingDuration(line_no))?;
+
+                let range = match range_value.take() {
+                    Some(value) => {
+                        let previous = previous_end.as_ref()
+                            .filter(|(prev_uri, _)| *prev_uri == uri)
+                            .map(|(_, end)| *end);
+                        let range = SubRange::parse(&value, previous).ok_or_else(|| invalid("EXT-X-BYTERANGE"))?;
+                        previous_end = Some((uri.clone(), range.end()));
+                        Some(range)
+                    }
+                    None => {
+                        previous_end = None;
+                        None
+                    }
+                };
+
+                let sequence = media_sequence + playlist.segments.len() as u64;
+                playlist.segments.push(Segment { uri, duration, range, key: key.clone(), init: init.clone(), sequence });
+            }
+        }
+
+        Ok(playlist)
+    }
+
+    /// URIs of all keys the playlist needs, without duplicates.
+    pub fn key_uris(&self) -> Vec<Url> {
+        let mut uris: Vec<Url> = Vec::new();
+        let keys = self.segments.iter()
+            .flat_map(|s| [s.key.as_ref(), s.init.as_ref().and_then(|i| i.key.as_ref())])
+            .flatten();
+
+        for key in keys {
+            if !uris.contains(&key.uri) {
+                uris.push(key.uri.clone());
+            }
+        }
+
+        uris
+    }
+
+    /// Everything to fetch, in order, to get one continuous file: each init
+    /// section once before the first segment that uses it, then the segments.
+    pub fn resources(&self) -> Vec<Resource> {
+        let mut resources = Vec::with_capacity(self.segments.len() + 1);
+        let mut current_init: Option<&InitSection> = None;
+
+        for segment in &self.segments {
+            if let Some(init) = &segment.init
+                && current_init != Some(init) {
+                // An init section without its own IV uses the sequence number
+                // of the segment it precedes.
+                let key = init.key.as_ref()
+                    .map(|k| (k.uri.clone(), k.iv.unwrap_or_else(|| sequence_iv(segment.sequence))));
+                resources.push(Resource { uri: init.uri.clone(), range: init.range, key });
+                current_init = Some(init);
+            }
+
+            let key = segment.key.as_ref().zip(segment.iv()).map(|(k, iv)| (k.uri.clone(), iv));
+            resources.push(Resource { uri: segment.uri.clone(), range: segment.range, key });
+        }
+
+        resources
+    }
+}
+
+pub fn parse_key(bytes: &[u8]) -> Result<[u8; 16], HlsError> {
+    bytes.try_into().map_err(|_| HlsError::InvalidKey(bytes.len()))
+}
+
+/// AES-128-CBC with PKCS#7 padding, as used by `METHOD=AES-128`.
+pub fn decrypt(key: &[u8; 16], iv: &[u8; 16], data: &[u8]) -> Result<Vec<u8>, HlsError> {
+    cbc::Decryptor::<Aes128>::new(key.into(), iv.into())
+        .decrypt_padded_vec_mut::<Pkcs7>(data)
+        .map_err(|_| HlsError::Decryption)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cbc::cipher::BlockEncryptMut;
+
+    fn base() -> Url {
+        Url::parse("https://cf-hls-media.sndcdn.com/media/track/playlist.m3u8").unwrap()
+    }
+
+    fn hex(value: &str) -> Vec<u8> {
+        (0..value.len()).step_by(2).map(|i| u8::from_str_radix(&value[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn encrypt(key: &[u8; 16], iv: &[u8; 16], data: &[u8]) -> Vec<u8> {
+        cbc::Encryptor::<Aes128>::new(key.into(), iv.into()).encrypt_padded_vec_mut::<Pkcs7>(data)
+    }
+
+    #[test]
+    fn parses_segments_with_sequence_and_relative_uris() {
+        let playlist = MediaPlaylist::parse(&base(), "#EXTM3U\n\
+            #EXT-X-TARGETDURATION:10\n\
+            #EXT-X-MEDIA-SEQUENCE:7\n\
+            #EXTINF:9.5,\n\
+            0.mp3\n\
+            \n\
+            #EXTINF:3,title\n\
+            https://other.example/1.mp3\n\
+            #EXT-X-ENDLIST\n").unwrap();
+
+        assert_eq!(playlist.target_duration, Some(10));
+        assert!(playlist.ended);
+        assert_eq!(playlist.segments.len(), 2);
+        assert_eq!(playlist.segments[0].uri.as_str(), "https://cf-hls-media.sndcdn.com/media/track/0.mp3");
+        assert_eq!(playlist.segments[0].duration, 9.5);
+        assert_eq!(playlist.segments[0].sequence, 7);
+        assert_eq!(playlist.segments[1].uri.as_str(), "https://other.example/1.mp3");
+        assert_eq!(playlist.segments[1].sequence, 8);
+        assert!(playlist.segments.iter().all(|s| s.key.is_none() && s.range.is_none()));
+    }
+
+    #[test]
+    fn byte_ranges_continue_the_previous_one_of_the_same_uri() {
+        let playlist = MediaPlaylist::parse(&base(), "#EXTM3U\n\
+            #EXTINF:1,\n#EXT-X-BYTERANGE:100@0\nfile.mp3\n\
+            #EXTINF:1,\n#EXT-X-BYTERANGE:50\nfile.mp3\n\
+            #EXTINF:1,\n#EXT-X-BYTERANGE:10@500\nfile.mp3\n").unwrap();
+
+        let ranges: Vec<_> = playlist.segments.iter().map(|s| s.range.unwrap()).collect();
+        assert_eq!(ranges, [
+            SubRange { length: 100, offset: 0 },
+            SubRange { length: 50, offset: 100 },
+            SubRange { length: 10, offset: 500 },
+        ]);
+        // Inclusive at both ends.
+        assert_eq!(ranges[1].to_range(), ByteRange::FromTo(100, 149));
+
+        let err = MediaPlaylist::parse(&base(), "#EXTM3U\n\
+            #EXTINF:1,\n#EXT-X-BYTERANGE:100@0\na.mp3\n\
+            #EXTINF:1,\n#EXT-X-BYTERANGE:50\nb.mp3\n").unwrap_err();
+        assert!(matches!(err, HlsError::InvalidTag { tag: "EXT-X-BYTERANGE", line: 7 }));
+    }
+
+    #[test]
+    fn keys_apply_until_replaced() {
+        let playlist = MediaPlaylist::parse(&base(), "#EXTM3U\n\
+            #EXT-X-MEDIA-SEQUENCE:1\n\
+            #EXT-X-KEY:METHOD=AES-128,URI=\"keys/a?x=1,y=2\",IV=0x000102030405060708090A0B0C0D0E0F\n\
+            #EXTINF:1,\n0.mp3\n\
+            #EXT-X-KEY:METHOD=AES-128,URI=\"keys/b\"\n\
+            #EXTINF:1,\n1.mp3\n\
+            #EXTINF:1,\n2.mp3\n\
+            #EXT-X-KEY:METHOD=NONE\n\
+            #EXTINF:1,\n3.mp3\n").unwrap();
+
+        let segments = &playlist.segments;
+        let key_a = segments[0].key.as_ref().unwrap();
+        assert_eq!(key_a.uri.as_str(), "https://cf-hls-media.sndcdn.com/media/track/keys/a?x=1,y=2");
+        assert_eq!(segments[0].iv(), Some(core::array::from_fn(|i| i as u8)));
+
+        // Without an IV the sequence number is used, big-endian.
+        let mut iv = [0; 16];
+        iv[15] = 2;
+        assert_eq!(segments[1].iv(), Some(iv));
+        iv[15] = 3;
+        assert_eq!(segments[2].iv(), Some(iv));
+        assert_eq!(segments[3].key, None);
+        assert_eq!(segments[3].iv(), None);
+
+        let uris: Vec<_> = playlist.key_uris().iter().map(|u| u.path().to_owned()).collect();
+        assert_eq!(uris, ["/media/track/keys/a", "/media/track/keys/b"]);
+    }
+
+    #[test]
+    fn init_sections_come_once_before_the_segments_using_them() {
+        let playlist = MediaPlaylist::parse(&base(), "#EXTM3U\n\
+            #EXT-X-MEDIA-SEQUENCE:4\n\
+            #EXT-X-KEY:METHOD=AES-128,URI=\"key\"\n\
+            #EXT-X-MAP:URI=\"init.mp4\",BYTERANGE=\"720@0\"\n\
+            #EXTINF:1,\n0.m4s\n\
+            #EXTINF:1,\n1.m4s\n\
+            #EXT-X-MAP:URI=\"init2.mp4\"\n\
+            #EXTINF:1,\n2.m4s\n").unwrap();
+
+        let resources = playlist.resources();
+        let uris: Vec<_> = resources.iter().map(|r| r.uri.path().rsplit('/').next().unwrap().to_owned()).collect();
+        assert_eq!(uris, ["init.mp4", "0.m4s", "1.m4s", "init2.mp4", "2.m4s"]);
+        assert_eq!(resources[0].range, Some(SubRange { length: 720, offset: 0 }));
+
+        // Init sections without an IV take the one of the segment they precede.
+        let ivs: Vec<u8> = resources.iter().map(|r| r.key.as_ref().unwrap().1[15]).collect();
+        assert_eq!(ivs, [4, 4, 5, 6, 6]);
+    }
+
+    #[test]
+    fn rejects_what_it_cant_play() {
+        let parse = |text: &str| MediaPlaylist::parse(&base(), text).unwrap_err();
+
+        assert!(matches!(parse("#EXTINF:1,\n0.mp3"), HlsError::NotAPlaylist));
+        assert!(matches!(parse("#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=1\nlow.m3u8"), HlsError::MasterPlaylist));
+        assert!(matches!(parse("#EXTM3U\n0.mp3"), HlsError::MissingDuration(2)));
+        assert!(matches!(parse("#EXTM3U\n#EXTINF:x,\n0.mp3"), HlsError::InvalidTag { tag: "EXTINF", line: 2 }));
+        assert!(matches!(
+            parse("#EXTM3U\n#EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"k\"\n"),
+            HlsError::UnsupportedEncryption(method) if method == "SAMPLE-AES"
+        ));
+        assert!(matches!(parse("#EXTM3U\n#EXT-X-KEY:METHOD=AES-128\n"), HlsError::InvalidTag { tag: "EXT-X-KEY", .. }));
+    }
+
+    #[test]
+    fn decrypts_aes_128_cbc() {
+        // NIST SP 800-38A F.2.1, its first block.
+        let key = parse_key(&hex("2b7e151628aed2a6abf7158809cf4f3c")).unwrap();
+        let iv: [u8; 16] = hex("000102030405060708090a0b0c0d0e0f").try_into().unwrap();
+        let plain = hex("6bc1bee22e409f96e93d7e117393172a");
+
+        let encrypted = encrypt(&key, &iv, &plain);
+        assert_eq!(encrypted[..16], hex("7649abac8119b246cee98e9b12e9197d"));
+        // A whole block of padding follows a full block.
+        assert_eq!(encrypted.len(), 32);
+        assert_eq!(decrypt(&key, &iv, &encrypted).unwrap(), plain);
+
+        for len in [0, 1, 15, 17, 1000] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            assert_eq!(decrypt(&key, &iv, &encrypt(&key, &iv, &data)).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_keys_and_padding() {
+        assert!(matches!(parse_key(&[0; 15]), Err(HlsError::InvalidKey(15))));
+
+        let key = [7; 16];
+        let iv = [9; 16];
+        let mut encrypted = encrypt(&key, &iv, b"segment");
+        assert!(matches!(decrypt(&key, &iv, &encrypted[..8]), Err(HlsError::Decryption)));
+        *encrypted.last_mut().unwrap() ^= 0xff;
+        assert!(matches!(decrypt(&key, &iv, &encrypted), Err(HlsError::Decryption)));
+    }
+}
@@ -0,0 +1,64 @@
+pub mod soundcloud_api;
+pub mod hls;
+pub mod client_id;
+pub mod config;
+mod routs;
+mod history_routs;
+pub mod postgres_service;
+
+use std::sync::Arc;
+use axum::Router;
+use axum::routing::get;
+use crate::postgres_service::PostgresDb;
+use crate::history_routs::{get_activity, get_recent, get_top_artists, get_top_tracks, get_track_listens};
+use crate::routs::{get_stream, get_tracks_data, search};
+use crate::soundcloud_api::SoundCloudApi;
+use aws_sdk_s3::Client as S3Client;
+use music_core::sessions::{SessionStore, SessionStoreState};
+use music_core::users::{self, UserStore, UserStoreState};
+
+pub struct SharedState {
+    soundcloud_api: Arc<SoundCloudApi>,
+    postgres_db: Arc<PostgresDb>,
+    s3_client: S3Client,
+    users: Arc<UserStore>,
+    sessions: Arc<dyn SessionStore>,
+}
+
+impl SharedState {
+    pub fn new(
+        soundcloud_api: Arc<SoundCloudApi>,
+        postgres_db: Arc<PostgresDb>,
+        s3_client: S3Client,
+        users: Arc<UserStore>,
+        sessions: Arc<dyn SessionStore>,
+    ) -> Self {
+        Self { soundcloud_api, postgres_db, s3_client, users, sessions }
+    }
+}
+
+impl UserStoreState for SharedState {
+    fn user_store(&self) -> &UserStore {
+        &self.users
+    }
+}
+
+impl SessionStoreState for SharedState {
+    fn session_store(&self) -> &dyn SessionStore {
+        self.sessions.as_ref()
+    }
+}
+
+pub fn router(shared_state: Arc<SharedState>) -> Router {
+    Router::new()
+        .route("/track_data/{ids}", get(get_tracks_data))
+        .route("/search", get(search))
+        .route("/stream/{id}", get(get_stream))
+        .route("/history/recent", get(get_recent))
+        .route("/history/top/tracks", get(get_top_tracks))
+        .route("/history/top/artists", get(get_top_artists))
+        .route("/history/tracks/{id}", get(get_track_listens))
+        .route("/history/activity", get(get_activity))
+        .nest("/users", users::router())
+        .with_state(shared_state)
+}
@@ -1,11 +1,12 @@
+use music_core::history::{ActivityParams, BucketCount, PageParams, Window};
+use serde::Serialize;
 use sqlx::postgres::{PgHasArrayType, PgPoolOptions, PgTypeInfo};
 use sqlx::{pool, Postgres};
-use std::error::Error;
 use crate::soundcloud_api::TrackData;
 
 // Types:
 #[derive(Debug, sqlx::Type)]
-#[sqlx(type_name = "author_input")] // Links this struct to the PG type
+#[sqlx(type_name = "author_input_soundcloud")] // Links this struct to the PG type
 pub struct AuthorInput {
     id: i32,
     title: String,
@@ -23,7 +24,7 @@ pub struct AlbumInput {
 }
 
 #[derive(Debug, sqlx::Type)]
-#[sqlx(type_name = "track_input")]
+#[sqlx(type_name = "track_input_soundcloud")]
 pub struct TrackInput {
     id: i32,
     title: String,
@@ -58,11 +59,11 @@ impl From<&TrackData> for TrackInput {
 // Tables:
 #[derive(Debug, sqlx::FromRow)]
 pub struct TrackTblEntry {
-    id: i32,
-    title: String,
-    duration: i32,
-    img: Option<String>,
-    author_id: Option<i32>,
+    pub id: i32,
+    pub title: String,
+    pub duration: i32,
+    pub img: Option<String>,
+    pub author_id: Option<i32>,
 }
 
 impl From<TrackData> for TrackTblEntry {
@@ -77,15 +78,61 @@ impl From<TrackData> for TrackTblEntry {
     }
 }
 
-#[derive(Debug, sqlx::FromRow)]
-pub struct OptionalStr(Option<String>);
-
 impl PgHasArrayType for TrackInput {
     fn array_type_info() -> PgTypeInfo {
-        PgTypeInfo::with_name("_tracks_input")
+        PgTypeInfo::with_name("_track_input_soundcloud")
     }
 }
 
+// History:
+#[derive(Debug, sqlx::FromRow, Serialize)]
+pub struct RecentListen {
+    /// Unix time in seconds.
+    pub listened_at: i64,
+    pub track_id: i32,
+    pub title: String,
+    pub duration: i32,
+    pub img: Option<String>,
+    pub author_id: i32,
+    pub author_title: String,
+}
+
+#[derive(Debug, sqlx::FromRow, Serialize)]
+pub struct TopTrack {
+    pub track_id: i32,
+    pub title: String,
+    pub img: Option<String>,
+    pub author_title: String,
+    pub listen_count: i64,
+}
+
+#[derive(Debug, sqlx::FromRow, Serialize)]
+pub struct TopAuthor {
+    pub author_id: i32,
+    pub title: String,
+    pub img: Option<String>,
+    pub listen_count: i64,
+}
+
+#[derive(Debug, sqlx::FromRow, Serialize)]
+pub struct TrackListenCount {
+    pub track_id: i32,
+    pub title: String,
+    pub listen_count: i64,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct BucketRow {
+    start: i64,
+    listen_count: i64,
+}
+
+/// `$1` is the user whose listens to count, `$2`/`$3` the window bounds in
+/// unix seconds. `NULL` means everyone or an open side respectively.
+const HISTORY_FILTER: &str = "($1::BIGINT IS NULL OR l.user_id = $1) \
+    AND ($2::BIGINT IS NULL OR l.listened_at >= to_timestamp($2)) \
+    AND ($3::BIGINT IS NULL OR l.listened_at < to_timestamp($3))";
+
 pub struct PostgresDb {
     pool: pool::Pool<Postgres>,
 }
@@ -93,16 +140,21 @@ pub struct PostgresDb {
 impl PostgresDb {
     pub async fn new(url: &str) -> Self {
         Self {
-            pool: PgPoolOptions::new().connect(&url).await.unwrap(),
+            pool: PgPoolOptions::new().connect(url).await.unwrap(),
         }
     }
 
+    /// For other stores sharing the database, like `music_core::users::UserStore`.
+    pub fn pool(&self) -> &pool::Pool<Postgres> {
+        &self.pool
+    }
+
     pub async fn add_track(
         &self,
         track: &TrackInput,
         author: &AuthorInput,
-    ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        sqlx::query("CALL add_track($1, $2)")
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("CALL add_track_soundcloud($1, $2)")
             .bind(track)
             .bind(author)
             .execute(&self.pool)
@@ -111,28 +163,113 @@ impl PostgresDb {
         Ok(())
     }
 
-    pub async fn get_tracks(&self, id: &str) -> Result<TrackTblEntry, Box<dyn Error + Send + Sync>> {
-        let mut track: TrackTblEntry = sqlx::query_as("SELECT * FROM tracks WHERE id=$1")
+    pub async fn get_tracks(&self, id: i32) -> Result<TrackTblEntry, sqlx::Error> {
+        sqlx::query_as("SELECT * FROM tracks_soundcloud WHERE id=$1")
             .bind(id)
             .fetch_one(&self.pool)
-            .await?;
-        if track.img.is_none() {
-            let img: OptionalStr = sqlx::query_as("SELECT img FROM albums WHERE id=$1")
-                .bind(id)
-                .fetch_one(&self.pool)
-                .await?;
-            track.img = img.0;
-        }
-
-        Ok(track)
+            .await
     }
     
-    pub async fn record_listening(&self, id: i32) -> Result<bool, Box<dyn Error + Send + Sync>> {
-        let was_inserted: bool = sqlx::query_scalar("SELECT record_listen_deezer($1)")
+    pub async fn record_listening(&self, id: i32, user_id: Option<i64>) -> Result<bool, sqlx::Error> {
+        let was_inserted: bool = sqlx::query_scalar("SELECT record_listen_soundcloud($1, $2)")
             .bind(id)       // Привязываем track_id к параметру $1
+            .bind(user_id)
             .fetch_one(&self.pool)      // Выполняем запрос и ожидаем ровно одну строку
             .await?;              // Ожидаем завершения и обрабатываем возможные ошибки I/O
 
         Ok(was_inserted)
     }
-}
\ No newline at end of file
+
+    /// Newest listens first.
+    pub async fn recent_listens(&self, user_id: Option<i64>, window: Window, page: PageParams) -> Result<Vec<RecentListen>, sqlx::Error> {
+        sqlx::query_as(&format!(
+            "SELECT EXTRACT(EPOCH FROM l.listened_at)::BIGINT AS listened_at,
+               t.id AS track_id, t.title, t.duration, t.img,
+               a.id AS author_id, a.title AS author_title
+             FROM listenings_soundcloud l
+             JOIN tracks_soundcloud t ON t.id = l.track_id
+             JOIN authors_soundcloud a ON a.id = t.author_id
+             WHERE {HISTORY_FILTER}
+             ORDER BY l.listened_at DESC, l.id DESC
+             LIMIT $4 OFFSET $5"))
+            .bind(user_id)
+            .bind(window.since)
+            .bind(window.until)
+            .bind(page.fetch_limit())
+            .bind(page.offset as i64)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    pub async fn top_tracks(&self, user_id: Option<i64>, window: Window, page: PageParams) -> Result<Vec<TopTrack>, sqlx::Error> {
+        sqlx::query_as(&format!(
+            "SELECT t.id AS track_id, t.title, t.img, a.title AS author_title, COUNT(*) AS listen_count
+             FROM listenings_soundcloud l
+             JOIN tracks_soundcloud t ON t.id = l.track_id
+             JOIN authors_soundcloud a ON a.id = t.author_id
+             WHERE {HISTORY_FILTER}
+             GROUP BY t.id, a.title
+             ORDER BY listen_count DESC, t.id
+             LIMIT $4 OFFSET $5"))
+            .bind(user_id)
+            .bind(window.since)
+            .bind(window.until)
+            .bind(page.fetch_limit())
+            .bind(page.offset as i64)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    pub async fn top_authors(&self, user_id: Option<i64>, window: Window, page: PageParams) -> Result<Vec<TopAuthor>, sqlx::Error> {
+        sqlx::query_as(&format!(
+            "SELECT a.id AS author_id, a.title, a.img, COUNT(*) AS listen_count
+             FROM listenings_soundcloud l
+             JOIN tracks_soundcloud t ON t.id = l.track_id
+             JOIN authors_soundcloud a ON a.id = t.author_id
+             WHERE {HISTORY_FILTER}
+             GROUP BY a.id
+             ORDER BY listen_count DESC, a.id
+             LIMIT $4 OFFSET $5"))
+            .bind(user_id)
+            .bind(window.since)
+            .bind(window.until)
+            .bind(page.fetch_limit())
+            .bind(page.offset as i64)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    /// All-time listens of a track, like `track_stats_soundcloud` but optionally
+    /// of one user only. `None` when the track isn't known.
+    pub async fn track_listen_count(&self, id: i32, user_id: Option<i64>) -> Result<Option<TrackListenCount>, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT t.id AS track_id, t.title, COUNT(l.id) AS listen_count
+             FROM tracks_soundcloud t
+             LEFT JOIN listenings_soundcloud l ON l.track_id = t.id AND ($2::BIGINT IS NULL OR l.user_id = $2)
+             WHERE t.id = $1
+             GROUP BY t.id")
+            .bind(id)
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    /// Listens per day or week, buckets without any are left out.
+    pub async fn listen_activity(&self, user_id: Option<i64>, params: ActivityParams, window: Window) -> Result<Vec<BucketCount>, sqlx::Error> {
+        let rows: Vec<BucketRow> = sqlx::query_as(&format!(
+            "SELECT EXTRACT(EPOCH FROM date_trunc($4, l.listened_at))::BIGINT AS start, COUNT(*) AS listen_count
+             FROM listenings_soundcloud l
+             WHERE {HISTORY_FILTER} AND ($5::INT IS NULL OR l.track_id = $5)
+             GROUP BY 1
+             ORDER BY 1"))
+            .bind(user_id)
+            .bind(window.since)
+            .bind(window.until)
+            .bind(params.bucket.pg_unit())
+            .bind(params.track_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.into_iter().map(|r| BucketCount { start: r.start, listen_count: r.listen_count }).collect())
+    }
+}
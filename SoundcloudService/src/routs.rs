@@ -1,22 +1,21 @@
-use std::io;
-use std::pin::Pin;
 use std::sync::Arc;
-use aws_sdk_s3::primitives::ByteStream;
-use aws_smithy_types::body::SdkBody;
+use async_trait::async_trait;
 use axum::body::Body;
 use axum::extract::{Path, Query, State};
-use axum::http::{header, Response, StatusCode};
+use axum::http::{header, HeaderMap, HeaderValue, Response, StatusCode};
 use axum::Json;
 use axum::response::IntoResponse;
-use bytes::Bytes;
-use futures::{stream, TryFutureExt, TryStream};
-use futures::TryStreamExt;
-use futures::StreamExt;
+use music_core::{AudioFormat, ProviderError};
+use music_core::error::ErrorBody;
+use music_core::library::TrackCatalog;
+use music_core::range::ByteRange;
+use music_core::s3::{get_cached, tee_to_s3, UploadTarget};
+use music_core::tags::{tag_stream, Tagger};
+use music_core::sessions::Authenticated;
 use serde::Deserialize;
-use tokio::sync::mpsc;
-use tokio_stream::wrappers::ReceiverStream;
 use crate::{SharedState};
 use crate::postgres_service::{AuthorInput, TrackInput};
+use crate::soundcloud_api::{ApiError, TranscodingPreference};
 use tokio_util::io::ReaderStream;
 
 #[derive(Deserialize, Debug)]
@@ -27,162 +26,185 @@ pub struct SearchParams {
 }
 
 #[axum::debug_handler]
-pub async fn search(Query(params): Query<SearchParams>, State(state): State<Arc<SharedState>>) -> Result<impl IntoResponse, StatusCode> {
+pub async fn search(Query(params): Query<SearchParams>, State(state): State<Arc<SharedState>>) -> Result<impl IntoResponse, ApiError> {
     let soundcloud = state.soundcloud_api.clone();
 
-    let search_res = soundcloud.search(&params.q, &params.offset, &params.limit).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let search_res = soundcloud.search(&params.q, &params.offset, &params.limit).await?;
     Ok(Json(search_res))
 }
 
 #[axum::debug_handler]
-pub async fn get_tracks_data(Path(ids): Path<String>, State(state): State<Arc<SharedState>>) -> Result<impl IntoResponse, StatusCode> {
+pub async fn get_tracks_data(Path(ids): Path<String>, State(state): State<Arc<SharedState>>) -> Result<impl IntoResponse, ApiError> {
     let soundcloud = state.soundcloud_api.clone();
     let postgre = state.postgres_db.clone();
 
     // The original `get_track_data` is fine
-    let tracks_data = soundcloud.get_track_data(ids.as_str()).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let tracks_data = soundcloud.get_track_data(ids.as_str()).await?;
 
     for track in &tracks_data {
         let ti = TrackInput::from(track);
         let ai = AuthorInput::from(track);
 
-        // This call remains the same
-        postgre.add_track(&ti, &ai).await.expect("failed to add track");
+        if let Err(e) = postgre.add_track(&ti, &ai).await {
+            eprintln!("Failed to add track {}: {}", track.id, e);
+        }
     }
 
     Ok(Json(tracks_data))
 }
 
-#[axum::debug_handler]
-pub async fn get_stream(
-    Path(id): Path<String>,
-    State(state): State<Arc<SharedState>>
-) -> Result<Response<Body>, StatusCode> {
-    let s3 = state.s3_client.clone();
+#[async_trait]
+impl TrackCatalog for SharedState {
+    fn provider(&self) -> &'static str {
+        "soundcloud"
+    }
 
+    async fn add_track(&self, id: i32) -> Result<(), ProviderError> {
+        let tracks_data = self.soundcloud_api.get_track_data(&id.to_string()).await?;
+        let track = tracks_data.first().ok_or_else(|| ProviderError::NotFound(format!("track {}", id)))?;
 
-    let key = format!("tracks/{}.m3u8", id);
-    if let Ok(mut file) = s3.get_object().bucket("soundcloud").key(key).send().await {
-        let async_read = file.body.into_async_read();
-        let stream = ReaderStream::new(async_read);
-        
-        let body = Body::from_stream(stream);
-        let response = Response::builder()
-          .status(StatusCode::OK)
-          .header(header::CONTENT_TYPE, "application/octet-stream")
-          .header(
-              header::CONTENT_DISPOSITION,
-              "attachment; filename=\"combined_data.m3u8\"",
-          )
-          .body(body)
-          .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-        println!("response from s3");
+        if let Err(e) = self.postgres_db.add_track(&TrackInput::from(track), &AuthorInput::from(track)).await {
+            eprintln!("Failed to add track {}: {}", track.id, e);
+        }
 
-        return Ok(response);
+        Ok(())
     }
+}
 
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response<Body> {
+        if let ApiError::UserError(e) = self {
+            return e.into_response();
+        }
+        eprintln!("{}", self);
+
+        let (status, code) = match &self {
+            ApiError::NotFound(_) => (StatusCode::NOT_FOUND, "not_found"),
+            ApiError::NoTranscoding => (StatusCode::NOT_FOUND, "no_transcoding"),
+            ApiError::GeoBlocked => (StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS, "geo_blocked"),
+            ApiError::RateLimited { .. } => (StatusCode::TOO_MANY_REQUESTS, "rate_limited"),
+            ApiError::ClientIdExpired | ApiError::ClientIdUnavailable(_) => (StatusCode::SERVICE_UNAVAILABLE, "client_id_expired"),
+            ApiError::DatabaseError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "internal_error"),
+            ApiError::RangeNotSatisfiable => (StatusCode::RANGE_NOT_SATISFIABLE, "range_not_satisfiable"),
+            ApiError::StorageError(_) => (StatusCode::BAD_GATEWAY, "storage_error"),
+            _ => (StatusCode::BAD_GATEWAY, "upstream_error"),
+        };
+
+        let mut response = (status, Json(ErrorBody::new(code, self.to_string()))).into_response();
+        if let ApiError::RateLimited { retry_after: Some(secs) } = self {
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
 
-    let soundcloud = state.soundcloud_api.clone();
-    let postgre = state.postgres_db.clone();
+        response
+    }
+}
 
+/// `accept_ranges` is false while the track is streamed into the cache, only
+/// the cached copy can be served in parts. The ETag names the transcoding
+/// (weakly, the tags can come out differently when it's cached again), the
+/// same for both.
+fn audio_response(body: Body, id: &str, cache_name: &str, mime_type: &str, format: AudioFormat, accept_ranges: bool) -> Response<Body> {
+    let mut response = Response::new(body);
+    let headers = response.headers_mut();
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static(if accept_ranges { "bytes" } else { "none" }));
+    if let Ok(etag) = HeaderValue::from_str(&format!("W/\"{}-{}\"", id, cache_name)) {
+        headers.insert(header::ETAG, etag);
+    }
+    if let Ok(mime_type) = HeaderValue::from_str(mime_type) {
+        headers.insert(header::CONTENT_TYPE, mime_type);
+    }
+    if let Ok(disposition) = HeaderValue::from_str(&format!("attachment; filename=\"{}.{}\"", id, format.extension())) {
+        headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
 
-    
-    let track_data = soundcloud
-        .get_track_data(&id).await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    response
+}
 
-    
-    
-    let track = track_data.first().ok_or(StatusCode::BAD_REQUEST)?;
-    
-    let media_data = track.media.transcodings.first().ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+#[axum::debug_handler]
+pub async fn get_stream(
+    Path(id): Path<String>,
+    Query(preference): Query<TranscodingPreference>,
+    headers: HeaderMap,
+    user: Authenticated,
+    State(state): State<Arc<SharedState>>
+) -> Result<Response<Body>, ApiError> {
+    let s3 = state.s3_client.clone();
+    let soundcloud = state.soundcloud_api.clone();
+    let postgre = state.postgres_db.clone();
+    let range = headers.get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(ByteRange::parse);
+
+    let track_data = soundcloud.get_track_data(&id).await?;
+    let track = track_data.first().ok_or_else(|| ApiError::NotFound(format!("track {}", id)))?;
+    if track.is_blocked() {
+        return Err(ApiError::GeoBlocked);
+    }
+    let media_data = preference.select(&track.media.transcodings).ok_or(ApiError::NoTranscoding)?;
+    let format = media_data.format.audio_format();
+    let mime_type = media_data.format.mime_type.as_str();
 
     let ti = &TrackInput::from(track);
     let ai = &AuthorInput::from(track);
-    let (url_with_chunks, added_track) = tokio::join!(
-        soundcloud.get_url_to_chunks(&media_data.url, &track.track_authorization),
-        postgre.add_track(ti, ai)
-    );
-    
-    let url_with_chunks = &url_with_chunks.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    
-    
-    // If track added to db run in concurent
-    let (chunk_tokens, _) = tokio::join!(
-        soundcloud.get_chunks(&url_with_chunks),
-        // Выполняем запись прослушивания только если добавление трека было успешным
-        async {
-            if added_track.is_ok() {
-                postgre.record_listening(track.id).await
-            } else {
-                // Если трек не был добавлен, просто возвращаем Ok, чтобы не вызывать ошибку в join!
-                Ok(false) 
-            }
+    // The listening can only be recorded once the track is in the db.
+    let record = async {
+        postgre.add_track(ti, ai).await?;
+        postgre.record_listening(track.id, Some(user.user_id)).await
+    };
+
+    // Every transcoding gets its own object so they don't overwrite each other.
+    let key = format!("tracks/{}/{}.{}", id, media_data.cache_name(), format.extension());
+    if let Some(file) = get_cached(&s3, "soundcloud", &key, range).await? {
+        if let Err(e) = record.await {
+            eprintln!("Failed to record listening: {}", e);
         }
-    );
 
-    let chunk_tokens: Vec<String> = chunk_tokens.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    
-    let chunk_futures = futures::stream::iter(chunk_tokens.into_iter())
-        .map(move |token| {
-            let sc = soundcloud.clone();
-            async move { sc.stream_chunk(token).await }
-        });
-
-
-    let (tx, rx) = mpsc::channel::<Bytes>(8);
-
-    let key = format!("tracks/{}.m3u8", id);
-    tokio::spawn(async move {
-        
-        let stream = ReceiverStream::from(rx);
-        let collected: Vec<u8> = stream
-            .fold(Vec::new(), |mut acc, chunk| async move {
-                acc.extend_from_slice(&chunk);
-                acc
-            })
-            .await;
-
-        
-        let byte_stream = ByteStream::from(collected);
-        // PutObject can take a streaming body:
-        if let Err(e) = s3.put_object()
-            .bucket("soundcloud")
-            .key(key)
-            .body(byte_stream)
-            .send()
-            .await
-        {
-            tracing::error!("S3 upload failed: {}", e);
+        let content_range = file.content_range().map(str::to_owned);
+        let content_length = file.content_length();
+        let async_read = file.body.into_async_read();
+        let stream = ReaderStream::new(async_read);
+
+        let mut response = audio_response(Body::from_stream(stream), &id, &media_data.cache_name(), mime_type, format, true);
+
+        let headers = response.headers_mut();
+        if let Some(content_length) = content_length {
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(content_length));
         }
-    });
-
-
-    let max_concurrency = 1;
-    let tee_stream = chunk_futures
-        .buffered(max_concurrency)
-        .flatten()
-        .map(move |chunk_result| {
-            if let Some(bytes) = chunk_result.as_ref().ok() {
-                let s3_copy = bytes.clone();
-                let _ = tx.try_send(s3_copy);
-            }
-            
-            chunk_result
-        });
-
-    let body = Body::from_stream(tee_stream);
-    let response = Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, "application/octet-stream")
-        .header(
-            header::CONTENT_DISPOSITION,
-            "attachment; filename=\"combined_data.m3u8\"",
-        )
-        .body(body)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    Ok(response)
+        if let Some(content_range) = content_range.and_then(|v| HeaderValue::from_str(&v).ok()) {
+            headers.insert(header::CONTENT_RANGE, content_range);
+            *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+        }
+
+        return Ok(response);
+    }
+
+    let (stream, record) = tokio::join!(
+        soundcloud.stream_transcoding(media_data, &track.track_authorization),
+        record
+    );
+    if let Err(e) = record {
+        eprintln!("Failed to record listening: {}", e);
+    }
+
+    let stream = stream?;
+    let tags = soundcloud.track_tags(track).await;
+
+    // Chunks are uploaded while they're streamed, the object only shows up in
+    // the bucket once the whole track went through.
+    let tee_stream = tee_to_s3(UploadTarget {
+        client: s3,
+        bucket: "soundcloud".to_owned(),
+        key,
+        content_type: mime_type.to_owned(),
+        expected_len: None,
+        tagger: Tagger::new(format, &tags),
+    }, stream);
+
+    // Served tagged like the cached copy, so the URL has one byte layout.
+    let tee_stream = match Tagger::new(format, &tags) {
+        Some(tagger) => tag_stream(tagger, tee_stream),
+        None => tee_stream,
+    };
+
+    Ok(audio_response(Body::from_stream(tee_stream), &id, &media_data.cache_name(), mime_type, format, false))
 }
@@ -1,12 +1,21 @@
-use std::error::Error;
-use std::io::ErrorKind;
-use std::pin::Pin;
-use reqwest::{Client, Url};
+use std::collections::HashMap;
+use std::sync::Arc;
+use bytes::Bytes;
+use reqwest::{Client, Response, StatusCode, Url};
 use serde::{Deserialize, Serialize};
-use regex::Regex;
-use futures::{Stream, StreamExt, TryStreamExt};
+use futures::{StreamExt, TryStreamExt};
+use async_trait::async_trait;
+use reqwest::header::{CONTENT_TYPE, RANGE, RETRY_AFTER};
+use thiserror::Error;
+use crate::client_id::ClientIdProvider;
+use crate::hls::{self, HlsError, MediaPlaylist, Resource};
+use music_core::s3::CacheError;
+use music_core::tags::{Picture, TrackTags};
+use music_core::users::UserError;
+use music_core::{self as core, AudioFormat, AudioStream, ByteStream, MusicProvider, ProviderError, SearchKind, SearchQuery, SearchResults};
 
 const BASE_URL: &str = "https://api-v2.soundcloud.com";
+const WEB_URL: &str = "https://soundcloud.com";
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct FormatData {
@@ -14,6 +23,20 @@ pub struct FormatData {
     pub mime_type: String
 }
 
+impl FormatData {
+    /// Container the transcoding ends up in once its segments are joined:
+    /// Ogg for Opus, fragmented MP4 for AAC, plain MP3 otherwise.
+    pub fn audio_format(&self) -> AudioFormat {
+        if self.mime_type.contains("opus") {
+            AudioFormat::Opus
+        } else if self.mime_type.contains("mp4") || self.mime_type.contains("aac") {
+            AudioFormat::Aac
+        } else {
+            AudioFormat::Mp3
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Clone)]
 pub struct EncodingData {
     pub url: String,
@@ -25,6 +48,102 @@ pub struct EncodingData {
     pub is_legacy_transcoding: Option<bool>,
 }
 
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Progressive,
+    Hls,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Mp3,
+    Aac,
+    Opus,
+}
+
+impl Codec {
+    fn audio_format(&self) -> AudioFormat {
+        match self {
+            Codec::Mp3 => AudioFormat::Mp3,
+            Codec::Aac => AudioFormat::Aac,
+            Codec::Opus => AudioFormat::Opus,
+        }
+    }
+}
+
+impl EncodingData {
+    /// `None` for protocols we can't play, like the DRM `*-encrypted-hls` ones.
+    pub fn protocol(&self) -> Option<Protocol> {
+        match self.format.protocol.as_str() {
+            "progressive" => Some(Protocol::Progressive),
+            "hls" => Some(Protocol::Hls),
+            _ => None,
+        }
+    }
+
+    /// Identifies the transcoding within a track, e.g. `hls_opus_0_0_sq`.
+    pub fn cache_name(&self) -> String {
+        let preset = self.preset.as_deref().unwrap_or(self.format.audio_format().extension());
+        format!("{}_{}_{}", self.format.protocol, preset, self.quality)
+    }
+}
+
+fn default_fallback() -> bool {
+    true
+}
+
+/// Which transcoding to stream. Unset fields don't matter, set ones are
+/// preferences unless `fallback` is off, then they're requirements.
+#[derive(Deserialize, Debug)]
+pub struct TranscodingPreference {
+    pub protocol: Option<Protocol>,
+    pub codec: Option<Codec>,
+    /// `sq` or `hq`.
+    pub quality: Option<String>,
+    #[serde(default = "default_fallback")]
+    pub fallback: bool,
+}
+
+impl Default for TranscodingPreference {
+    fn default() -> Self {
+        Self { protocol: None, codec: None, quality: None, fallback: true }
+    }
+}
+
+impl TranscodingPreference {
+    /// Best playable, non-snipped transcoding. Codec matters most, then
+    /// protocol, then quality; ties keep SoundCloud's order.
+    pub fn select<'a>(&self, transcodings: &'a [EncodingData]) -> Option<&'a EncodingData> {
+        let mut best: Option<(&EncodingData, u8)> = None;
+
+        for transcoding in transcodings {
+            if transcoding.snipped {
+                continue;
+            }
+            let Some(protocol) = transcoding.protocol() else {
+                continue;
+            };
+
+            let codec_ok = self.codec.is_none_or(|c| c.audio_format() == transcoding.format.audio_format());
+            let protocol_ok = self.protocol.is_none_or(|p| p == protocol);
+            let quality_ok = self.quality.as_ref().is_none_or(|q| q.eq_ignore_ascii_case(&transcoding.quality));
+            let matches = codec_ok && protocol_ok && quality_ok;
+            if !matches && !self.fallback {
+                continue;
+            }
+
+            let score = (codec_ok as u8) << 2 | (protocol_ok as u8) << 1 | quality_ok as u8;
+            if best.is_none_or(|(_, best_score)| score > best_score) {
+                best = Some((transcoding, score));
+            }
+        }
+
+        best.map(|(transcoding, _)| transcoding)
+    }
+}
+
 #[derive(Deserialize, Serialize, Clone)]
 pub struct Media {
     pub transcodings: Vec<EncodingData>,
@@ -45,7 +164,16 @@ pub struct TrackData {
     pub duration: i32,
     pub media: Media,
     pub track_authorization: String,
-    pub user: User
+    pub user: User,
+    /// `ALLOW`, `MONETIZE`, `SNIP` or `BLOCK` for the requesting region.
+    #[serde(default)]
+    pub policy: Option<String>,
+}
+
+impl TrackData {
+    pub fn is_blocked(&self) -> bool {
+        self.policy.as_deref() == Some("BLOCK")
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -66,6 +194,7 @@ pub struct PlaylistData {
 }
 
 #[derive(Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
 pub enum SearchItem {
     Playlist(PlaylistData),
     Track(TrackData),
@@ -83,79 +212,453 @@ pub struct ChunkUrl {
     pub url: String,
 }
 
+#[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
+pub enum ApiError {
+    #[error("Request failed: {0}")]
+    RequestError(#[from] reqwest::Error),
+
+    #[error("Failed to parse URL: {0}")]
+    UrlParseError(#[from] url::ParseError),
+
+    #[error("Failed to parse json: {0}")]
+    JsonParseError(#[from] serde_json::Error),
+
+    #[error("Invalid HLS playlist: {0}")]
+    HlsError(#[from] HlsError),
+
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    #[error("No playable transcoding matches the request")]
+    NoTranscoding,
+
+    #[error("Unsupported protocol '{0}'")]
+    UnsupportedProtocol(String),
+
+    #[error("SoundCloud rejected the client_id")]
+    ClientIdExpired,
+
+    #[error("Failed to discover a client_id: {0}")]
+    ClientIdUnavailable(String),
+
+    #[error("Rate limited by SoundCloud")]
+    RateLimited { retry_after: Option<u64> },
+
+    #[error("Not available in this region")]
+    GeoBlocked,
+
+    #[error("SoundCloud returned {0}")]
+    UpstreamStatus(StatusCode),
+
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] sqlx::Error),
+
+    #[error(transparent)]
+    UserError(#[from] UserError),
+
+    #[error("Requested range is not satisfiable")]
+    RangeNotSatisfiable,
+
+    #[error("Storage error: {0}")]
+    StorageError(String),
+}
+
+impl From<CacheError> for ApiError {
+    fn from(e: CacheError) -> Self {
+        match e {
+            CacheError::RangeNotSatisfiable => ApiError::RangeNotSatisfiable,
+            CacheError::S3(e) => ApiError::StorageError(e),
+        }
+    }
+}
+
+/// Turns an unsuccessful response into the matching error. Only a 401 is about
+/// the client_id, a 403 means the resource itself is off limits.
+fn check_status(res: Response, url: &str) -> Result<Response, ApiError> {
+    match res.status() {
+        status if status.is_success() => Ok(res),
+        StatusCode::UNAUTHORIZED => Err(ApiError::ClientIdExpired),
+        StatusCode::FORBIDDEN => Err(ApiError::GeoBlocked),
+        StatusCode::NOT_FOUND => Err(ApiError::NotFound(url.to_owned())),
+        StatusCode::TOO_MANY_REQUESTS => Err(ApiError::RateLimited {
+            retry_after: res.headers().get(RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok()),
+        }),
+        status => Err(ApiError::UpstreamStatus(status)),
+    }
+}
+
+#[derive(Clone)]
 pub struct SoundCloudApi {
     client: Client,
-    client_id: String,
-    url_re: Regex,
+    api_url: String,
+    client_id: ClientIdProvider,
 }
 
-
-pub type ByteStream = Pin<Box<dyn Stream<Item = Result<bytes::Bytes, std::io::Error>> + Send>>;
+impl Default for SoundCloudApi {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl SoundCloudApi {
-    pub fn new(client_id: &str) -> Self {
+    pub fn new() -> Self {
+        Self::with_urls(BASE_URL, Url::parse(WEB_URL).unwrap())
+    }
+
+    /// Talks to another API and web app, e.g. a local stub server.
+    pub fn with_urls(api_url: &str, web_url: Url) -> Self {
+        let client = Client::new();
         Self {
-            client: Client::new(),
-            client_id: String::from(client_id),
-            url_re: Regex::new(r#"https:?:[^\s"]+"#).unwrap()
+            client_id: ClientIdProvider::new(client.clone(), web_url),
+            client,
+            api_url: api_url.trim_end_matches('/').to_owned(),
         }
     }
 
-    pub async fn search(&self, query: &str, offset: &str, limit: &str) -> Result<SearchResponse, Box<dyn Error>> {
-        let url = Url::parse_with_params(format!("{}/search", BASE_URL).as_str(), &[
-            ("q", query), ("client_id", self.client_id.as_str()),
-            ("limit", limit), ("offset", offset)
-        ])?;
+    /// Skips discovery until SoundCloud rejects `client_id`.
+    pub fn with_client_id(self, client_id: &str) -> Self {
+        Self {
+            client_id: self.client_id.with_client_id(client_id),
+            ..self
+        }
+    }
+
+    async fn send(&self, url: &str, params: &[(&str, &str)], client_id: &str) -> reqwest::Result<Response> {
+        self.client.get(url)
+            .query(params)
+            .query(&[("client_id", client_id)])
+            .send()
+            .await
+    }
+
+    /// Sends a GET with the current client_id, a rejected one is replaced and
+    /// the request retried once. Geo-blocked tracks answer with a 403, which
+    /// a new client_id wouldn't change, so those don't cause a scrape.
+    async fn call(&self, url: &str, params: &[(&str, &str)]) -> Result<String, ApiError> {
+        let client_id = self.client_id.get().await?;
+        let mut res = self.send(url, params, &client_id).await?;
+
+        if res.status() == StatusCode::UNAUTHORIZED {
+            println!("SoundCloud client_id is invalid or expired. Attempting to refresh.");
+            let client_id = self.client_id.refresh(Some(&client_id)).await?;
+            println!("client_id refreshed successfully. Retrying '{}'.", url);
+            res = self.send(url, params, &client_id).await?;
+        }
+
+        Ok(check_status(res, url)?.text().await?)
+    }
 
-        let req = self.client.get(url).build()?;
-        let res = self.client.execute(req).await?.text().await?;
+    pub async fn search(&self, query: &str, offset: &str, limit: &str) -> Result<SearchResponse, ApiError> {
+        let res = self.call(&format!("{}/search", self.api_url), &[
+            ("q", query), ("limit", limit), ("offset", offset)
+        ]).await?;
 
         let search_res: SearchResponse = serde_json::from_str(&res)?;
         Ok(search_res)
     }
 
-    pub async fn get_track_data(&self, ids: &str) -> Result<Vec<TrackData>, Box<dyn Error>> {
-        let url = Url::parse_with_params(format!("{}/tracks", BASE_URL).as_str(), &[
-            ("ids", ids), ("client_id", self.client_id.as_str()),
-        ])?;
-        let req = self.client.get(url).build()?;
-        let res = self.client.execute(req).await?.text().await?;
+    pub async fn get_track_data(&self, ids: &str) -> Result<Vec<TrackData>, ApiError> {
+        let res = self.call(&format!("{}/tracks", self.api_url), &[("ids", ids)]).await?;
         let track: Vec<TrackData> = serde_json::from_str(&res)?;
 
         Ok(track)
     }
 
-    pub async fn get_url_to_chunks(&self, url: &str, track_authorization: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
-        let url = Url::parse_with_params(url, &[
-            ("client_id", self.client_id.as_str()), ("track_authorization", track_authorization),
-        ])?;
-        let req = self.client.get(url).build()?;
-        let res = self.client.execute(req).await?.text().await?;
+    pub async fn get_url_to_chunks(&self, url: &str, track_authorization: &str) -> Result<String, ApiError> {
+        let res = self.call(url, &[("track_authorization", track_authorization)]).await?;
         let urls: ChunkUrl = serde_json::from_str(&res)?;
 
         Ok(urls.url)
     }
 
-    pub async fn get_chunks(&self, url: &str) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
-        let req = self.client.get(url).build()?;
-        let res = self.client.execute(req).await?.text().await?;
-        let urls: Vec<String> = self.url_re.find_iter(res.as_str()).map(|m| m.as_str().to_string()).collect();
-        
-        Ok(urls)
+    /// Tags for a track: the uploader is its artist, `artwork_url` its cover.
+    pub async fn track_tags(&self, track: &TrackData) -> TrackTags {
+        TrackTags {
+            title: track.title.clone(),
+            artists: vec![track.user.username.clone()],
+            duration_ms: Some(track.duration.max(0) as u64),
+            cover: self.get_artwork(track.artwork_url.as_deref()).await,
+            ..TrackTags::default()
+        }
     }
 
-    pub async fn stream_chunk(&self, url: String) -> ByteStream {
-        let response = self.client
-            .get(url)
-            .send()
-            .await.unwrap();
+    async fn get_artwork(&self, artwork_url: Option<&str>) -> Option<Picture> {
+        // `artwork_url` points at the 100x100 version.
+        let url = artwork_url?.replace("-large.", "-t500x500.");
+        let res = async {
+            let res = check_status(self.client.get(&url).send().await?, &url)?;
+            let mime_type = res.headers().get(CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("image/jpeg")
+                .to_owned();
+            Ok::<_, ApiError>(Picture { mime_type, data: res.bytes().await? })
+        }.await;
+
+        match res {
+            Ok(picture) => Some(picture),
+            Err(e) => {
+                eprintln!("Failed to fetch artwork {}: {}", url, e);
+                None
+            }
+        }
+    }
+
+    /// Resolves a transcoding and streams it as one continuous audio file,
+    /// HLS playlists are flattened into their (decrypted) segments.
+    pub async fn stream_transcoding(&self, transcoding: &EncodingData, track_authorization: &str) -> Result<ByteStream, ApiError> {
+        let url = self.get_url_to_chunks(&transcoding.url, track_authorization).await?;
+
+        match transcoding.format.protocol.as_str() {
+            "progressive" => Ok(self.fetch_resource(Resource { uri: Url::parse(&url)?, range: None, key: None }, Arc::default()).await),
+            "hls" => self.stream_hls(&url).await,
+            protocol => Err(ApiError::UnsupportedProtocol(protocol.to_owned())),
+        }
+    }
+
+    async fn stream_hls(&self, url: &str) -> Result<ByteStream, ApiError> {
+        let url = Url::parse(url)?;
+        let text = check_status(self.client.get(url.clone()).send().await?, url.as_str())?.text().await?;
+        let playlist = MediaPlaylist::parse(&url, &text)?;
 
-        
-        let stream = response
-            .bytes_stream()
-            .map_err(|e| std::io::Error::new(ErrorKind::Other, e)) // A better mapping
+        let mut keys = HashMap::new();
+        for key_uri in playlist.key_uris() {
+            let key = check_status(self.client.get(key_uri.clone()).send().await?, key_uri.as_str())?.bytes().await?;
+            keys.insert(key_uri, hls::parse_key(&key)?);
+        }
+        let keys = Arc::new(keys);
+
+        let client = self.clone();
+        let stream = futures::stream::iter(playlist.resources())
+            .map(move |resource| {
+                let sc = client.clone();
+                let keys = keys.clone();
+                async move { sc.fetch_resource(resource, keys).await }
+            })
+            .buffered(1)
+            .flatten()
             .boxed();
-        
-        stream
+
+        Ok(stream)
+    }
+
+    /// Streams one segment, init section or progressive file. Encrypted
+    /// segments have to be read whole before they can be decrypted.
+    async fn fetch_resource(&self, resource: Resource, keys: Arc<HashMap<Url, [u8; 16]>>) -> ByteStream {
+        let mut req = self.client.get(resource.uri.clone());
+        if let Some(range) = resource.range {
+            req = req.header(RANGE, range.to_range().to_header());
+        }
+
+        let url = resource.uri.to_string();
+        let response = match req.send().await.map_err(ApiError::from).and_then(|res| check_status(res, &url)) {
+            Ok(response) => response,
+            Err(e) => return futures::stream::once(async move { Err(std::io::Error::other(e)) }).boxed(),
+        };
+
+        let Some((key_uri, iv)) = resource.key else {
+            return response.bytes_stream().map_err(std::io::Error::other).boxed();
+        };
+
+        let decrypted = async move {
+            let key = keys.get(&key_uri).ok_or_else(|| std::io::Error::other(format!("Missing key {}", key_uri)))?;
+            let data = response.bytes().await.map_err(std::io::Error::other)?;
+            let plain = hls::decrypt(key, &iv, &data).map_err(std::io::Error::other)?;
+            Ok(Bytes::from(plain))
+        };
+
+        futures::stream::once(decrypted).boxed()
+    }
+}
+
+impl From<&User> for core::Artist {
+    fn from(value: &User) -> Self {
+        Self {
+            id: value.id.to_string(),
+            name: value.username.clone(),
+            picture: Some(value.avatar_url.clone()),
+        }
+    }
+}
+
+impl From<&TrackData> for core::Track {
+    fn from(value: &TrackData) -> Self {
+        Self {
+            id: value.id.to_string(),
+            title: value.title.clone(),
+            artists: vec![core::Artist::from(&value.user)],
+            album_id: None,
+            album_title: None,
+            duration_ms: value.duration as u64,
+            artwork: value.artwork_url.clone(),
+        }
+    }
+}
+
+impl From<&PlaylistData> for core::Playlist {
+    fn from(value: &PlaylistData) -> Self {
+        Self {
+            id: value.id.to_string(),
+            title: value.title.clone(),
+            owner: Some(core::Artist::from(&value.user)),
+            artwork: value.artwork_url.clone(),
+            tracks: value.tracks.iter()
+                .filter_map(|track| match track {
+                    PlaylistTrack::Full(track) => Some(core::Track::from(track)),
+                    PlaylistTrack::Partial { .. } => None,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<ApiError> for ProviderError {
+    fn from(value: ApiError) -> Self {
+        match value {
+            ApiError::NotFound(what) => ProviderError::NotFound(what),
+            e => ProviderError::Upstream(e.to_string()),
+        }
+    }
+}
+
+#[async_trait]
+impl MusicProvider for SoundCloudApi {
+    fn name(&self) -> &'static str {
+        "soundcloud"
+    }
+
+    async fn search(&self, query: &SearchQuery) -> Result<SearchResults, ProviderError> {
+        let res = self
+            .search(&query.q, &query.offset.to_string(), &query.limit.to_string())
+            .await?;
+
+        let wanted = |kind: SearchKind| query.kind.is_none_or(|k| k == kind);
+        let mut results = SearchResults::default();
+        for item in &res.collection {
+            match item {
+                SearchItem::Track(track) if wanted(SearchKind::Track) => results.tracks.push(core::Track::from(track)),
+                SearchItem::Playlist(playlist) if wanted(SearchKind::Playlist) => results.playlists.push(core::Playlist::from(playlist)),
+                SearchItem::User(user) if wanted(SearchKind::Artist) => results.artists.push(core::Artist::from(user)),
+                _ => {}
+            }
+        }
+
+        Ok(results)
+    }
+
+    async fn track(&self, id: &str) -> Result<core::Track, ProviderError> {
+        let tracks = self.get_track_data(id).await?;
+        let track = tracks.first().ok_or_else(|| ProviderError::NotFound(id.to_owned()))?;
+
+        Ok(core::Track::from(track))
+    }
+
+    async fn stream(&self, id: &str) -> Result<AudioStream, ProviderError> {
+        let tracks = self.get_track_data(id).await?;
+        let track = tracks.first().ok_or_else(|| ProviderError::NotFound(id.to_owned()))?;
+        if track.is_blocked() {
+            return Err(ApiError::GeoBlocked.into());
+        }
+        let media_data = TranscodingPreference::default().select(&track.media.transcodings)
+            .ok_or_else(|| ProviderError::NotFound(format!("transcoding for track {id}")))?;
+
+        let body = self
+            .stream_transcoding(media_data, &track.track_authorization)
+            .await?;
+
+        Ok(AudioStream { format: media_data.format.audio_format(), body })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use axum::extract::{Query, State};
+    use axum::response::{Html, IntoResponse};
+    use axum::routing::get;
+    use axum::Router;
+
+    const CLIENT_ID: &str = "a1B2c3D4e5F6g7H8i9J0k1L2m3N4o5P6";
+
+    /// Requests the stub server got, by what was requested.
+    #[derive(Default)]
+    struct Hits {
+        page: AtomicUsize,
+        bundle: AtomicUsize,
+        api: AtomicUsize,
+    }
+
+    /// Serves a soundcloud.com page whose last bundle sets `CLIENT_ID`, and an
+    /// API that only accepts that id. Tracks with id 403 are geo-blocked.
+    async fn stub_server() -> (SoundCloudApi, Arc<Hits>) {
+        let hits = Arc::new(Hits::default());
+        let app = Router::new()
+            .route("/", get(|State(hits): State<Arc<Hits>>| async move {
+                hits.page.fetch_add(1, Ordering::SeqCst);
+                Html(r#"<html><script crossorigin src="/assets/vendor.js"></script><script crossorigin src="/assets/app.js"></script></html>"#)
+            }))
+            .route("/assets/vendor.js", get(|| async { "var vendor = {};" }))
+            .route("/assets/app.js", get(|State(hits): State<Arc<Hits>>| async move {
+                hits.bundle.fetch_add(1, Ordering::SeqCst);
+                format!(r#"var config = {{client_id:"{}",env:"production"}};"#, CLIENT_ID)
+            }))
+            .route("/api/tracks", get(|Query(params): Query<HashMap<String, String>>, State(hits): State<Arc<Hits>>| async move {
+                hits.api.fetch_add(1, Ordering::SeqCst);
+                if params.get("client_id").map(String::as_str) != Some(CLIENT_ID) {
+                    return axum::http::StatusCode::UNAUTHORIZED.into_response();
+                }
+                if params.get("ids").map(String::as_str) == Some("403") {
+                    return axum::http::StatusCode::FORBIDDEN.into_response();
+                }
+                "[]".into_response()
+            }))
+            .with_state(hits.clone());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let api = SoundCloudApi::with_urls(&format!("http://{}/api", addr), Url::parse(&format!("http://{}/", addr)).unwrap());
+        (api, hits)
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn discovers_the_client_id_before_the_first_request() {
+        let (api, hits) = stub_server().await;
+
+        assert!(api.get_track_data("1").await.unwrap().is_empty());
+        assert!(api.get_track_data("2").await.unwrap().is_empty());
+
+        assert_eq!(hits.page.load(Ordering::SeqCst), 1);
+        assert_eq!(hits.bundle.load(Ordering::SeqCst), 1);
+        assert_eq!(hits.api.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn refreshes_a_rejected_client_id_and_retries() {
+        let (api, hits) = stub_server().await;
+        let api = api.with_client_id("expired0expired0expired0expired0");
+
+        assert!(api.get_track_data("1").await.unwrap().is_empty());
+        assert_eq!(hits.page.load(Ordering::SeqCst), 1);
+        assert_eq!(hits.api.load(Ordering::SeqCst), 2);
+
+        // The new id is kept.
+        assert!(api.get_track_data("1").await.unwrap().is_empty());
+        assert_eq!(hits.page.load(Ordering::SeqCst), 1);
+        assert_eq!(hits.api.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn geo_blocked_tracks_do_not_cause_a_scrape() {
+        let (api, hits) = stub_server().await;
+        let api = api.with_client_id(CLIENT_ID);
+
+        assert!(matches!(api.get_track_data("403").await, Err(ApiError::GeoBlocked)));
+        assert_eq!(hits.page.load(Ordering::SeqCst), 0);
+        assert_eq!(hits.api.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer};
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+use crate::redis_service::RedisConnection;
+use crate::sessions::{MemorySessionStore, SessionStore};
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read config file '{path}': {source}")]
+    File { path: String, source: std::io::Error },
+
+    #[error("Failed to parse config file '{path}': {source}")]
+    Toml { path: String, source: toml::de::Error },
+
+    #[error("Config file '{path}' must only contain flat `key = value` pairs, '{key}' isn't one")]
+    NestedValue { path: String, key: String },
+
+    #[error("Failed to load config from {prefix}* env vars: {source}")]
+    Env { prefix: String, source: envy::Error },
+
+    #[error("Invalid {field}: {reason}")]
+    Invalid { field: String, reason: String },
+}
+
+impl ConfigError {
+    pub fn invalid(field: &str, reason: impl Into<String>) -> Self {
+        ConfigError::Invalid { field: field.to_owned(), reason: reason.into() }
+    }
+}
+
+/// A value that must never end up in logs, `Debug` and `Display` only print
+/// a placeholder.
+#[derive(Clone, Deserialize, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(***)")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***")
+    }
+}
+
+fn default_s3_endpoint() -> String {
+    "http://localhost:9000".to_owned()
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_owned()
+}
+
+/// Connection settings for the S3 compatible storage (MinIO), meant to be
+/// `#[serde(flatten)]`ed into a service's config.
+#[derive(Debug, Clone, Deserialize)]
+pub struct S3Config {
+    #[serde(default = "default_s3_endpoint")]
+    pub s3_endpoint: String,
+    pub s3_access_key: Secret,
+    pub s3_secret_key: Secret,
+    #[serde(default = "default_s3_region")]
+    pub s3_region: String,
+}
+
+impl S3Config {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if !self.s3_endpoint.starts_with("http://") && !self.s3_endpoint.starts_with("https://") {
+            return Err(ConfigError::invalid("s3_endpoint", format!("'{}' is not an http(s) URL", self.s3_endpoint)));
+        }
+        if self.s3_access_key.expose().is_empty() || self.s3_secret_key.expose().is_empty() {
+            return Err(ConfigError::invalid("s3_access_key/s3_secret_key", "must not be empty"));
+        }
+        Ok(())
+    }
+}
+
+/// Values of `#[serde(flatten)]`ed structs reach them as the strings they
+/// are in the environment, envy only parses those of top-level fields.
+fn parse<'de, D: Deserializer<'de>, T: FromStr>(deserializer: D) -> Result<T, D::Error>
+where
+    T::Err: fmt::Display,
+{
+    String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+}
+
+fn parse_option<'de, D: Deserializer<'de>, T: FromStr>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T::Err: fmt::Display,
+{
+    parse(deserializer).map(Some)
+}
+
+fn default_ffmpeg_path() -> String {
+    "ffmpeg".to_owned()
+}
+
+fn default_transcoding_enabled() -> bool {
+    true
+}
+
+/// How tracks are transcoded, meant to be `#[serde(flatten)]`ed into a
+/// service's config. `max_transcodes` defaults to the number of CPUs.
+/// Transcoding needs ffmpeg at `ffmpeg_path`, services without it have to
+/// set `transcoding_enabled = false`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TranscodeConfig {
+    #[serde(default = "default_transcoding_enabled", deserialize_with = "parse")]
+    pub transcoding_enabled: bool,
+    #[serde(default = "default_ffmpeg_path")]
+    pub ffmpeg_path: String,
+    #[serde(default, deserialize_with = "parse_option")]
+    pub max_transcodes: Option<usize>,
+}
+
+impl TranscodeConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.ffmpeg_path.is_empty() {
+            return Err(ConfigError::invalid("ffmpeg_path", "must not be empty"));
+        }
+        if self.max_transcodes == Some(0) {
+            return Err(ConfigError::invalid("max_transcodes", "must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+fn default_session_index() -> String {
+    "session_idx".to_owned()
+}
+
+fn default_session_key_pattern() -> String {
+    r"(\d+)$".to_owned()
+}
+
+/// Where the sessions of the web app live, meant to be `#[serde(flatten)]`ed
+/// into a service's config. Without `redis_url` sessions are kept in memory
+/// and only API tokens authenticate in practice.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SessionConfig {
+    pub redis_url: Option<Secret>,
+    /// RediSearch index over the session documents.
+    #[serde(default = "default_session_index")]
+    pub session_index: String,
+    /// Its first capture group extracts the user id from a session's key.
+    #[serde(default = "default_session_key_pattern")]
+    pub session_key_pattern: String,
+}
+
+impl SessionConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(url) = &self.redis_url
+            && !url.expose().starts_with("redis://") && !url.expose().starts_with("rediss://") {
+            return Err(ConfigError::invalid("redis_url", "must start with redis:// or rediss://"));
+        }
+        self.key_regex().map(|_| ())
+    }
+
+    fn key_regex(&self) -> Result<Regex, ConfigError> {
+        let regex = Regex::new(&self.session_key_pattern)
+            .map_err(|e| ConfigError::invalid("session_key_pattern", e.to_string()))?;
+        if regex.captures_len() < 2 {
+            return Err(ConfigError::invalid("session_key_pattern", "needs a capture group for the user id"));
+        }
+        Ok(regex)
+    }
+
+    pub async fn connect(&self) -> Result<Arc<dyn SessionStore>, redis::RedisError> {
+        let Some(url) = &self.redis_url else {
+            return Ok(Arc::new(MemorySessionStore::default()));
+        };
+        let regex = self.key_regex().expect("validated by Config::load");
+        let connection = RedisConnection::try_new(url.expose(), self.session_index.clone(), regex).await?;
+        Ok(Arc::new(connection))
+    }
+}
+
+/// Checks that a database URL looks like a Postgres connection string.
+pub fn validate_database_url(url: &Secret) -> Result<(), ConfigError> {
+    let url = url.expose();
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        Ok(())
+    } else {
+        Err(ConfigError::invalid("database_url", "must start with postgres:// or postgresql://"))
+    }
+}
+
+fn toml_to_string(value: toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(s) => Some(s),
+        toml::Value::Integer(i) => Some(i.to_string()),
+        toml::Value::Float(f) => Some(f.to_string()),
+        toml::Value::Boolean(b) => Some(b.to_string()),
+        // Lists are read from env vars as comma separated values.
+        toml::Value::Array(values) => values.into_iter()
+            .map(|v| match v {
+                toml::Value::Array(_) | toml::Value::Table(_) => None,
+                v => toml_to_string(v),
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(|values| values.join(",")),
+        _ => None,
+    }
+}
+
+/// Loads `T` from env vars named `{prefix}{FIELD}`, e.g. `DEEZER_ARLS`. When
+/// `{prefix}CONFIG` points to a TOML file its keys are used for everything
+/// the environment doesn't set.
+pub fn load<T: DeserializeOwned>(prefix: &str) -> Result<T, ConfigError> {
+    let mut vars: HashMap<String, String> = std::env::vars()
+        .filter(|(key, _)| key.starts_with(prefix))
+        .collect();
+
+    if let Some(path) = vars.get(&format!("{}CONFIG", prefix)).cloned() {
+        let text = std::fs::read_to_string(&path)
+            .map_err(|source| ConfigError::File { path: path.clone(), source })?;
+        let table: toml::Table = toml::from_str(&text)
+            .map_err(|source| ConfigError::Toml { path: path.clone(), source })?;
+
+        for (key, value) in table {
+            let value = toml_to_string(value)
+                .ok_or_else(|| ConfigError::NestedValue { path: path.clone(), key: key.clone() })?;
+            vars.entry(format!("{}{}", prefix, key.to_uppercase())).or_insert(value);
+        }
+    }
+
+    envy::prefixed(prefix).from_iter(vars)
+        .map_err(|source| ConfigError::Env { prefix: prefix.to_owned(), source })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct TestConfig {
+        #[serde(flatten)]
+        transcoding: TranscodeConfig,
+    }
+
+    fn from_env(vars: &[(&str, &str)]) -> Result<TestConfig, envy::Error> {
+        envy::prefixed("TEST_").from_iter(vars.iter().map(|(key, value)| (key.to_string(), value.to_string())))
+    }
+
+    #[test]
+    fn flattened_values_are_parsed() {
+        let config = from_env(&[("TEST_TRANSCODING_ENABLED", "false"), ("TEST_MAX_TRANSCODES", "3")]).unwrap();
+        assert!(!config.transcoding.transcoding_enabled);
+        assert_eq!(config.transcoding.max_transcodes, Some(3));
+
+        let config = from_env(&[]).unwrap();
+        assert!(config.transcoding.transcoding_enabled);
+        assert_eq!(config.transcoding.max_transcodes, None);
+        assert_eq!(config.transcoding.ffmpeg_path, "ffmpeg");
+
+        assert!(from_env(&[("TEST_MAX_TRANSCODES", "many")]).is_err());
+    }
+}
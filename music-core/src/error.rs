@@ -0,0 +1,15 @@
+use serde::Serialize;
+
+/// JSON body of every error response. `code` is stable and meant for
+/// programs, `message` is for people and may change.
+#[derive(Serialize, Debug)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl ErrorBody {
+    pub fn new(code: &'static str, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+}
@@ -0,0 +1,163 @@
+//! Query parameters and response shapes shared by the listening history
+//! endpoints of every service.
+
+use serde::{Deserialize, Serialize};
+use crate::users::{AuthUser, UserError};
+
+pub const MAX_PAGE_LIMIT: u32 = 200;
+
+fn default_limit() -> u32 {
+    50
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct PageParams {
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+    #[serde(default)]
+    pub offset: u32,
+}
+
+impl PageParams {
+    pub fn limit(&self) -> u32 {
+        self.limit.clamp(1, MAX_PAGE_LIMIT)
+    }
+
+    /// One row more than the page holds, so `Page::new` can tell whether
+    /// there is a next one.
+    pub fn fetch_limit(&self) -> i64 {
+        self.limit() as i64 + 1
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub limit: u32,
+    pub offset: u32,
+    pub next_offset: Option<u32>,
+}
+
+impl<T> Page<T> {
+    /// Builds the page from rows fetched with `PageParams::fetch_limit`.
+    pub fn new(mut items: Vec<T>, params: PageParams) -> Self {
+        let limit = params.limit();
+        let has_more = items.len() > limit as usize;
+        items.truncate(limit as usize);
+
+        // An offset so large the next one doesn't fit has no next page to point to.
+        let next_offset = params.offset.checked_add(limit).filter(|_| has_more);
+        Self { items, limit, offset: params.offset, next_offset }
+    }
+}
+
+/// Time window as unix timestamps in seconds, open ended on a missing side.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub struct Window {
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+}
+
+/// `?mine=true` limits the history to the listens of the requesting user.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub struct Scope {
+    #[serde(default)]
+    pub mine: bool,
+}
+
+impl Scope {
+    /// User to filter by, `None` for everyone's listens.
+    pub fn user_id(&self, user: Option<&AuthUser>) -> Result<Option<i64>, UserError> {
+        match (self.mine, user) {
+            (false, _) => Ok(None),
+            (true, Some(AuthUser(user))) => Ok(Some(user.id)),
+            (true, None) => Err(UserError::Unauthorized),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Bucket {
+    Day,
+    Week,
+}
+
+impl Bucket {
+    /// Field name for Postgres' `date_trunc`.
+    pub fn pg_unit(&self) -> &'static str {
+        match self {
+            Bucket::Day => "day",
+            Bucket::Week => "week",
+        }
+    }
+}
+
+/// Taken as its own `Query` next to `Window`, `#[serde(flatten)]` breaks
+/// parsing numbers out of query strings.
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct ActivityParams {
+    pub bucket: Bucket,
+    /// Only count listens of this track.
+    pub track_id: Option<i32>,
+}
+
+/// Listens in the bucket starting at `start` (unix seconds).
+#[derive(Serialize, Debug, Clone)]
+pub struct BucketCount {
+    pub start: i64,
+    pub listen_count: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::User;
+
+    fn params(limit: u32, offset: u32) -> PageParams {
+        PageParams { limit, offset }
+    }
+
+    #[test]
+    fn limit_is_clamped() {
+        assert_eq!(params(0, 0).limit(), 1);
+        assert_eq!(params(20, 0).limit(), 20);
+        assert_eq!(params(10_000, 0).limit(), MAX_PAGE_LIMIT);
+        assert_eq!(params(10_000, 0).fetch_limit(), MAX_PAGE_LIMIT as i64 + 1);
+    }
+
+    #[test]
+    fn page_drops_the_lookahead_row() {
+        let page = Page::new((0..11).collect(), params(10, 30));
+        assert_eq!(page.items, (0..10).collect::<Vec<_>>());
+        assert_eq!((page.limit, page.offset, page.next_offset), (10, 30, Some(40)));
+
+        let page = Page::new((0..10).collect(), params(10, 30));
+        assert_eq!(page.items.len(), 10);
+        assert_eq!(page.next_offset, None);
+
+        let page = Page::new(Vec::<u32>::new(), params(10, 30));
+        assert!(page.items.is_empty());
+        assert_eq!(page.next_offset, None);
+    }
+
+    #[test]
+    fn page_at_the_end_of_the_offset_range_has_no_next() {
+        let page = Page::new((0..3).collect(), params(2, u32::MAX - 1));
+        assert_eq!(page.items.len(), 2);
+        assert_eq!(page.next_offset, None);
+
+        let page = Page::new((0..3).collect(), params(2, u32::MAX - 2));
+        assert_eq!(page.next_offset, Some(u32::MAX));
+    }
+
+    #[test]
+    fn scope_needs_a_user_only_for_mine() {
+        let user = AuthUser(User { id: 7, username: "ada".into(), is_admin: false, created_at: 0 });
+
+        assert_eq!(Scope { mine: false }.user_id(None).unwrap(), None);
+        assert_eq!(Scope { mine: false }.user_id(Some(&user)).unwrap(), None);
+        assert_eq!(Scope { mine: true }.user_id(Some(&user)).unwrap(), Some(7));
+        assert!(matches!(Scope { mine: true }.user_id(None), Err(UserError::Unauthorized)));
+    }
+}
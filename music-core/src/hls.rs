@@ -0,0 +1,374 @@
+//! HTTP Live Streaming of MP3 files as packed audio. Segments are cut at
+//! frame boundaries close to where a fixed duration ends, which CBR makes
+//! cheap to find, and each starts with the ID3 timestamp HLS asks for.
+//! FLAC can't be packed that way, it would need fragmented MP4.
+
+use bytes::{Bytes, BytesMut};
+use crate::tags::id3;
+
+/// Frames per segment, about 6 s at 44.1 kHz.
+const SEGMENT_FRAMES: u64 = 230;
+const TARGET_DURATION: u32 = 6;
+const SAMPLES_PER_FRAME: u64 = 1152;
+/// Read past a segment's estimated end to find the frame starting there,
+/// more than two frames at any MPEG-1 bitrate.
+const SLACK: u64 = 4096;
+/// Clock of the timestamps in the ID3 tag.
+const TIMESTAMP_RATE: u64 = 90_000;
+const TIMESTAMP_OWNER: &str = "com.apple.streaming.transportStreamTimestamp";
+/// Sync word, version, layer and sample rate, the same in every frame of a file.
+const STREAM_BITS: u32 = 0xfffe0c00;
+const ID3V1_LEN: usize = 128;
+
+pub const PLAYLIST_MIME_TYPE: &str = "application/vnd.apple.mpegurl";
+/// What HLS calls MP3 in `CODECS`.
+pub const MP3_CODECS: &str = "mp4a.40.34";
+
+/// Frame length and stream bits of the MPEG-1/2/2.5 Layer III frame `header`
+/// is the start of.
+fn frame(header: &[u8]) -> Option<(usize, u32)> {
+    const MPEG1_KBPS: [u32; 14] = [32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+    const MPEG2_KBPS: [u32; 14] = [8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+    const SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+    let header = u32::from_be_bytes(header.get(..4)?.try_into().ok()?);
+    let version = header >> 19 & 3;
+    let layer = header >> 17 & 3;
+    let bitrate = (header >> 12 & 0xf) as usize;
+    let sample_rate = (header >> 10 & 3) as usize;
+    if header >> 21 != 0x7ff || version == 1 || layer != 1 || !(1..=14).contains(&bitrate) || sample_rate == 3 {
+        return None;
+    }
+
+    let mpeg1 = version == 3;
+    let kbps = if mpeg1 { MPEG1_KBPS[bitrate - 1] } else { MPEG2_KBPS[bitrate - 1] };
+    // Halved for MPEG-2, quartered for MPEG-2.5.
+    let sample_rate = SAMPLE_RATES[sample_rate] >> (3 - version).min(2);
+    let padding = header >> 9 & 1;
+    let len = (if mpeg1 { 144 } else { 72 }) * kbps * 1000 / sample_rate + padding;
+
+    Some((len as usize, header & STREAM_BITS))
+}
+
+/// First frame at or after `from`. A header only counts when the next frame
+/// of the same stream follows it, or the data ends right after it.
+fn find_frame(data: &[u8], from: usize) -> Option<usize> {
+    (from..data.len()).find(|&pos| {
+        let Some((len, bits)) = frame(&data[pos..]) else {
+            return false;
+        };
+        match data.get(pos + len..) {
+            Some([]) => true,
+            Some(next) => frame(next).is_some_and(|(_, next_bits)| next_bits == bits),
+            None => false,
+        }
+    })
+}
+
+/// Where the audio of a CBR MPEG-1 MP3 file is, and how it's split into
+/// segments.
+#[derive(Debug, Clone, Copy)]
+pub struct Mp3Layout {
+    audio_start: u64,
+    total_len: u64,
+    /// Average, padding makes single frames a byte longer every now and then.
+    frame_len: f64,
+    sample_rate: u32,
+}
+
+impl Mp3Layout {
+    /// `head` is the start of the file, the first ten bytes are enough to
+    /// skip an ID3v2 tag.
+    pub fn cbr(kbps: u32, sample_rate: u32, head: &[u8], total_len: u64) -> Self {
+        let audio_start = id3::len(head).unwrap_or(0) as u64;
+
+        Self {
+            audio_start: audio_start.min(total_len),
+            total_len,
+            frame_len: 144_000.0 * kbps as f64 / sample_rate as f64,
+            sample_rate,
+        }
+    }
+
+    fn frames(&self) -> u64 {
+        ((self.total_len - self.audio_start) as f64 / self.frame_len).ceil() as u64
+    }
+
+    pub fn segments(&self) -> u64 {
+        self.frames().div_ceil(SEGMENT_FRAMES)
+    }
+
+    fn duration(&self, frames: u64) -> f64 {
+        (frames * SAMPLES_PER_FRAME) as f64 / self.sample_rate as f64
+    }
+
+    fn is_last(&self, segment: u64) -> bool {
+        segment + 1 >= self.segments()
+    }
+
+    /// Half a frame before where `segment` should start, so the first frame
+    /// from there on, which it starts with, is the closest one.
+    fn estimated_start(&self, segment: u64) -> u64 {
+        let frames = ((segment * SEGMENT_FRAMES) as f64 - 0.5).max(0.0);
+        let pos = self.audio_start + (frames * self.frame_len).round() as u64;
+        pos.min(self.total_len)
+    }
+
+    /// Inclusive byte range to read for `segment`.
+    pub fn segment_range(&self, segment: u64) -> (u64, u64) {
+        let end = if self.is_last(segment) {
+            self.total_len
+        } else {
+            (self.estimated_start(segment + 1) + SLACK).min(self.total_len)
+        };
+        (self.estimated_start(segment), end.saturating_sub(1))
+    }
+
+    /// Cuts `segment` out of `data`, read from `segment_range`, and puts its
+    /// timestamp in front. Neighbouring segments agree on the frame between
+    /// them since both look for it from the same estimate.
+    pub fn segment(&self, segment: u64, data: &[u8]) -> Bytes {
+        let offset = self.estimated_start(segment);
+        let start = find_frame(data, 0).unwrap_or(0);
+        let end = if self.is_last(segment) {
+            match data.len().checked_sub(ID3V1_LEN) {
+                Some(tag) if data[tag..].starts_with(b"TAG") => tag,
+                _ => data.len(),
+            }
+        } else {
+            let next = (self.estimated_start(segment + 1) - offset) as usize;
+            find_frame(data, next).unwrap_or(next.min(data.len()))
+        };
+
+        let frames = ((offset + start as u64 - self.audio_start) as f64 / self.frame_len).round() as u64;
+        let timestamp = frames * SAMPLES_PER_FRAME * TIMESTAMP_RATE / self.sample_rate as u64;
+        let tag = id3::private_tag(TIMESTAMP_OWNER, &(timestamp & ((1 << 33) - 1)).to_be_bytes());
+
+        let mut out = BytesMut::with_capacity(tag.len() + end.saturating_sub(start));
+        out.extend_from_slice(&tag);
+        out.extend_from_slice(&data[start..end.max(start)]);
+        out.freeze()
+    }
+
+    /// VOD playlist of all segments, named `{n}.{extension}`.
+    pub fn media_playlist(&self, extension: &str) -> String {
+        let segments = self.segments();
+        let mut playlist = format!(
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n",
+            TARGET_DURATION,
+        );
+        for segment in 0..segments {
+            let frames = if segment + 1 < segments {
+                SEGMENT_FRAMES
+            } else {
+                self.frames() - segment * SEGMENT_FRAMES
+            };
+            playlist += &format!("#EXTINF:{:.3},\n{}.{}\n", self.duration(frames), segment, extension);
+        }
+        playlist += "#EXT-X-ENDLIST\n";
+        playlist
+    }
+}
+
+/// One quality in a master playlist.
+pub struct Variant {
+    pub uri: String,
+    /// In bits per second.
+    pub bandwidth: u32,
+    pub codecs: &'static str,
+}
+
+/// Lists `variants`, best first, for the player to pick from.
+pub fn master_playlist(variants: &[Variant]) -> String {
+    let mut playlist = String::from("#EXTM3U\n#EXT-X-VERSION:3\n");
+    for variant in variants {
+        playlist += &format!(
+            "#EXT-X-STREAM-INF:BANDWIDTH={},CODECS=\"{}\"\n{}\n",
+            variant.bandwidth, variant.codecs, variant.uri,
+        );
+    }
+    playlist
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 44100;
+
+    /// Header of an MPEG-1 Layer III frame at 44.1 kHz.
+    fn header(kbps_index: u8, padding: bool) -> [u8; 4] {
+        [0xff, 0xfb, kbps_index << 4 | (padding as u8) << 1, 0x44]
+    }
+
+    /// A CBR file of `count` frames with noise for audio, padded the way
+    /// encoders do, and where each frame starts.
+    fn mp3(kbps: u32, kbps_index: u8, count: usize, id3_body: usize) -> (Vec<u8>, Vec<usize>) {
+        let mut file = Vec::new();
+        if id3_body > 0 {
+            file.extend_from_slice(b"ID3\x04\x00\x00");
+            file.extend_from_slice(&[
+                (id3_body >> 21 & 0x7f) as u8, (id3_body >> 14 & 0x7f) as u8, (id3_body >> 7 & 0x7f) as u8, (id3_body & 0x7f) as u8,
+            ]);
+            file.resize(10 + id3_body, 0);
+        }
+
+        let mut noise = 0x2545_f491_u32;
+        let mut remainder = 0;
+        let mut frames = Vec::with_capacity(count);
+        for _ in 0..count {
+            let bytes = 144_000 * kbps;
+            remainder += bytes % SAMPLE_RATE;
+            let padding = remainder >= SAMPLE_RATE;
+            if padding {
+                remainder -= SAMPLE_RATE;
+            }
+            let len = (bytes / SAMPLE_RATE) as usize + padding as usize;
+
+            frames.push(file.len());
+            file.extend_from_slice(&header(kbps_index, padding));
+            for _ in 4..len {
+                noise ^= noise << 13;
+                noise ^= noise >> 17;
+                noise ^= noise << 5;
+                file.push(noise as u8);
+            }
+        }
+        (file, frames)
+    }
+
+    #[test]
+    fn frame_lengths() {
+        let (len, bits) = frame(&header(9, false)).unwrap();
+        assert_eq!(len, 417);
+        assert_eq!(frame(&header(9, true)).unwrap().0, 418);
+        assert_eq!(frame(&header(14, false)).unwrap().0, 1044);
+        // Bitrate and padding may change from frame to frame within a stream.
+        assert_eq!(frame(&header(14, true)).unwrap().1, bits);
+        // MPEG-2 at 22.05 kHz, 64 kbps.
+        assert_eq!(frame(&[0xff, 0xf3, 0x80, 0x44]).unwrap().0, 208);
+        // MPEG-2.5 at 11.025 kHz, 8 kbps.
+        assert_eq!(frame(&[0xff, 0xe3, 0x10, 0x44]).unwrap().0, 52);
+
+        // Free format, bad bitrate, layer II, reserved sample rate and no sync.
+        for header in [[0xff, 0xfb, 0x00, 0x44], [0xff, 0xfb, 0xf0, 0x44], [0xff, 0xfd, 0x90, 0x44], [0xff, 0xfb, 0x9c, 0x44], [0x7f, 0xfb, 0x90, 0x44]] {
+            assert_eq!(frame(&header), None, "{:02x?}", header);
+        }
+        assert_eq!(frame(&[0xff, 0xfb]), None);
+    }
+
+    #[test]
+    fn find_frame_needs_a_following_frame() {
+        let (frames, starts) = mp3(128, 9, 3, 0);
+        let mut data = vec![0x12, 0xff, 0xfb, 0x90, 0x44, 0x00];
+        let offset = data.len();
+        data.extend_from_slice(&frames);
+
+        // The sync word in the junk isn't followed by another frame.
+        assert_eq!(find_frame(&data, 0), Some(offset));
+        assert_eq!(find_frame(&data, offset + 1), Some(offset + starts[1]));
+        // The last frame counts when the data ends right after it, not when it's cut short.
+        assert_eq!(find_frame(&data, offset + starts[2]), Some(offset + starts[2]));
+        assert_eq!(find_frame(&data[..data.len() - 1], offset + starts[2]), None);
+        assert_eq!(find_frame(&data, data.len()), None);
+    }
+
+    #[test]
+    fn find_frame_skips_other_streams() {
+        let mut data = header(9, false).to_vec();
+        data.resize(417, 0);
+        // A 48 kHz header where the next frame would be.
+        data.extend_from_slice(&[0xff, 0xfb, 0x94, 0x44]);
+        data.resize(417 + 384, 0);
+
+        assert_eq!(find_frame(&data, 0), Some(417));
+    }
+
+    /// Timestamp in the PRIV frame `segment` starts with, and the audio after it.
+    fn split_segment(segment: &[u8]) -> (u64, &[u8]) {
+        let tag_len = id3::len(segment).unwrap();
+        let tag = &segment[..tag_len];
+        assert_eq!(&tag[..10], b"ID3\x04\x00\x00\x00\x00\x00\x3f");
+
+        // Owner, its NUL and the timestamp.
+        let mut frame = b"PRIV\0\0\0".to_vec();
+        frame.extend_from_slice(&[(TIMESTAMP_OWNER.len() + 1 + 8) as u8, 0, 0]);
+        frame.extend_from_slice(TIMESTAMP_OWNER.as_bytes());
+        frame.push(0);
+        assert_eq!(&tag[10..tag_len - 8], frame);
+        (u64::from_be_bytes(tag[tag_len - 8..].try_into().unwrap()), &segment[tag_len..])
+    }
+
+    #[test]
+    fn segments_cover_the_audio_at_frame_boundaries() {
+        for (kbps, kbps_index, id3_body) in [(320, 14, 50_880), (128, 9, 0)] {
+            let (mut file, starts) = mp3(kbps, kbps_index, 3000, id3_body);
+            let audio_start = starts[0];
+            let audio_end = file.len();
+            // An ID3v1 tag at the end isn't audio.
+            file.extend_from_slice(b"TAG");
+            file.resize(file.len() + 125, 0);
+
+            let layout = Mp3Layout::cbr(kbps, SAMPLE_RATE, &file[..10], file.len() as u64);
+            assert_eq!(layout.segments(), 3000_u64.div_ceil(SEGMENT_FRAMES));
+
+            let mut joined = Vec::new();
+            for n in 0..layout.segments() {
+                let (start, end) = layout.segment_range(n);
+                let segment = layout.segment(n, &file[start as usize..=end as usize]);
+                let (timestamp, audio) = split_segment(&segment);
+
+                // Each segment starts on the frame where the previous one ended.
+                let first = starts.iter().position(|&pos| pos == audio_start + joined.len()).unwrap();
+                assert_eq!(first as u64, n * SEGMENT_FRAMES, "{}k segment {}", kbps, n);
+                assert_eq!(timestamp, first as u64 * SAMPLES_PER_FRAME * TIMESTAMP_RATE / SAMPLE_RATE as u64);
+                joined.extend_from_slice(audio);
+            }
+            assert_eq!(joined, &file[audio_start..audio_end]);
+        }
+    }
+
+    #[test]
+    fn media_playlist_lists_every_segment() {
+        let (file, _) = mp3(128, 9, 500, 0);
+        let layout = Mp3Layout::cbr(128, SAMPLE_RATE, &file[..10], file.len() as u64);
+        let playlist = layout.media_playlist("mp3");
+
+        assert!(playlist.starts_with("#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:6\n"));
+        assert!(playlist.ends_with("#EXT-X-ENDLIST\n"));
+        let durations: Vec<&str> = playlist.lines()
+            .filter_map(|line| line.strip_prefix("#EXTINF:"))
+            .collect();
+        // 230 + 230 + 40 frames of 1152 samples.
+        assert_eq!(durations, ["6.008,", "6.008,", "1.045,"]);
+        assert!(playlist.contains("\n0.mp3\n") && playlist.contains("\n2.mp3\n"));
+        // Target duration is the longest segment rounded.
+        assert!(layout.duration(SEGMENT_FRAMES).round() as u32 <= TARGET_DURATION);
+    }
+
+    #[test]
+    fn timestamps_wrap_at_33_bits() {
+        let (file, _) = mp3(128, 9, 1, 0);
+        // Long enough for segment 20000 to be past 2^33 ticks, about 26.5 hours.
+        let layout = Mp3Layout::cbr(128, SAMPLE_RATE, &file[..10], 417 * SEGMENT_FRAMES * 30_000);
+        let n = 20_000;
+        let (start, end) = layout.segment_range(n);
+        let data: Vec<u8> = (start..=end).map(|_| 0).collect();
+
+        let (timestamp, _) = split_segment(&layout.segment(n, &data));
+        let expected = n * SEGMENT_FRAMES * SAMPLES_PER_FRAME * TIMESTAMP_RATE / SAMPLE_RATE as u64;
+        assert!(expected >= 1 << 33);
+        assert_eq!(timestamp, expected & ((1 << 33) - 1));
+    }
+
+    #[test]
+    fn master_playlist_lists_variants() {
+        let variants = [
+            Variant { uri: "mp3_320/index.m3u8".into(), bandwidth: 323_200, codecs: MP3_CODECS },
+            Variant { uri: "mp3_128/index.m3u8".into(), bandwidth: 129_280, codecs: MP3_CODECS },
+        ];
+        assert_eq!(master_playlist(&variants), "#EXTM3U\n#EXT-X-VERSION:3\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=323200,CODECS=\"mp4a.40.34\"\nmp3_320/index.m3u8\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=129280,CODECS=\"mp4a.40.34\"\nmp3_128/index.m3u8\n");
+    }
+}
@@ -0,0 +1,24 @@
+pub mod config;
+pub mod error;
+pub mod history;
+pub mod hls;
+pub mod library;
+pub mod provider;
+pub mod range;
+mod redis_service;
+pub mod s3;
+pub mod sessions;
+pub mod tags;
+pub mod transcode;
+pub mod users;
+pub mod zip;
+
+use std::pin::Pin;
+use futures::Stream;
+
+pub use provider::{
+    Album, Artist, AudioFormat, AudioStream, MusicProvider, Playlist, ProviderError, QualifiedId,
+    SearchKind, SearchQuery, SearchResults, Track,
+};
+
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<bytes::Bytes, std::io::Error>> + Send>>;
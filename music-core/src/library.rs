@@ -0,0 +1,988 @@
+//! Playlists, favorites and saved albums of users. They mix every provider,
+//! items are resolved against the provider's catalog tables, tracks and albums
+//! missing there are fetched through the provider's `TrackCatalog` when
+//! they're added.
+
+use std::str::FromStr;
+use async_trait::async_trait;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, patch, post};
+use axum::{Json, Router};
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::{PgPool, Postgres};
+use sqlx::Transaction;
+use thiserror::Error;
+use crate::error::ErrorBody;
+use crate::history::{Page, PageParams};
+use crate::provider::{Album, Artist, ProviderError, QualifiedId, Track};
+use crate::sessions::SessionStoreState;
+use crate::users::{AuthUser, UserStoreState};
+
+const MAX_NAME_LEN: usize = 100;
+/// Most tracks or albums a single request may add.
+const MAX_BULK_ITEMS: usize = 500;
+/// Missing tracks and albums fetched from providers at the same time.
+const CONCURRENT_FETCHES: usize = 4;
+
+#[derive(Debug, Error)]
+pub enum LibraryError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+
+    #[error(transparent)]
+    Provider(#[from] ProviderError),
+}
+
+impl IntoResponse for LibraryError {
+    fn into_response(self) -> Response {
+        eprintln!("{}", self);
+
+        let (status, code) = match &self {
+            LibraryError::NotFound(_) | LibraryError::Provider(ProviderError::NotFound(_)) => (StatusCode::NOT_FOUND, "not_found"),
+            LibraryError::InvalidInput(_)
+            | LibraryError::Provider(ProviderError::InvalidId(_) | ProviderError::Unsupported(_)) => (StatusCode::BAD_REQUEST, "invalid_input"),
+            LibraryError::Provider(ProviderError::Upstream(_)) => (StatusCode::BAD_GATEWAY, "upstream_error"),
+            LibraryError::Database(_) => (StatusCode::INTERNAL_SERVER_ERROR, "internal_error"),
+        };
+
+        (status, Json(ErrorBody::new(code, self.to_string()))).into_response()
+    }
+}
+
+/// A track of some provider, written `deezer:3135556` in requests.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TrackRef {
+    pub provider: String,
+    pub track_id: i32,
+}
+
+/// Provider and numeric id of `s`, which is an id of a `kind`.
+fn parse_ref(s: &str, kind: &str) -> Result<(String, i32), LibraryError> {
+    let QualifiedId { provider, id } = s.parse()?;
+    let id = id.parse()
+        .map_err(|_| LibraryError::InvalidInput(format!("Invalid {} id '{}'", kind, s)))?;
+
+    Ok((provider, id))
+}
+
+impl FromStr for TrackRef {
+    type Err = LibraryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (provider, track_id) = parse_ref(s, "track")?;
+        Ok(Self { provider, track_id })
+    }
+}
+
+/// An album of some provider, written `deezer:302127` in requests.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AlbumRef {
+    pub provider: String,
+    pub album_id: i32,
+}
+
+impl FromStr for AlbumRef {
+    type Err = LibraryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (provider, album_id) = parse_ref(s, "album")?;
+        Ok(Self { provider, album_id })
+    }
+}
+
+/// `ids` of `kind`s added by a single request.
+fn parse_bulk<T: FromStr<Err = LibraryError>>(ids: &[String], kind: &str) -> Result<Vec<T>, LibraryError> {
+    if ids.is_empty() || ids.len() > MAX_BULK_ITEMS {
+        return Err(LibraryError::InvalidInput(format!("Between 1 and {} {}s can be added at once", MAX_BULK_ITEMS, kind)));
+    }
+    ids.iter().map(|id| id.parse()).collect()
+}
+
+fn validate_name(name: &str) -> Result<(), LibraryError> {
+    let len = name.trim().chars().count();
+    if len == 0 || len > MAX_NAME_LEN {
+        return Err(LibraryError::InvalidInput(format!("Playlist names are 1-{} characters", MAX_NAME_LEN)));
+    }
+    Ok(())
+}
+
+/// Stores the metadata of a provider's tracks and albums in its catalog tables.
+#[async_trait]
+pub trait TrackCatalog: Send + Sync {
+    /// Same name as the provider's `MusicProvider::name`.
+    fn provider(&self) -> &'static str;
+
+    /// Fetches the track from the provider and adds it to the catalog.
+    async fn add_track(&self, id: i32) -> Result<(), ProviderError>;
+
+    /// Fetches the album from the provider and adds it to the catalog, for
+    /// providers that have albums.
+    async fn add_album(&self, _id: i32) -> Result<(), ProviderError> {
+        Err(ProviderError::Unsupported("album"))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct PlaylistInfo {
+    pub id: i64,
+    pub name: String,
+    pub item_count: i64,
+    /// Unix time in seconds.
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+/// Columns of `catalog_tracks`, all `NULL` when the track isn't known.
+#[derive(sqlx::FromRow)]
+struct CatalogRow {
+    title: Option<String>,
+    duration: Option<i32>,
+    img: Option<String>,
+    author_id: Option<i32>,
+    author_title: Option<String>,
+    author_img: Option<String>,
+    album_id: Option<i32>,
+    album_title: Option<String>,
+}
+
+impl CatalogRow {
+    fn into_track(self, track_id: i32) -> Option<Track> {
+        let title = self.title?;
+        let artists = match (self.author_id, self.author_title) {
+            (Some(id), Some(name)) => vec![Artist { id: id.to_string(), name, picture: self.author_img }],
+            _ => Vec::new(),
+        };
+
+        Some(Track {
+            id: track_id.to_string(),
+            title,
+            artists,
+            album_id: self.album_id.map(|id| id.to_string()),
+            album_title: self.album_title,
+            duration_ms: self.duration.unwrap_or_default().max(0) as u64,
+            artwork: self.img,
+        })
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct ItemRow {
+    id: i64,
+    position: i32,
+    provider: String,
+    track_id: i32,
+    added_at: i64,
+    #[sqlx(flatten)]
+    track: CatalogRow,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlaylistItem {
+    pub id: i64,
+    pub position: i32,
+    pub provider: String,
+    pub track_id: i32,
+    pub added_at: i64,
+    /// `None` when the track's metadata isn't in the catalog.
+    pub track: Option<Track>,
+}
+
+impl From<ItemRow> for PlaylistItem {
+    fn from(row: ItemRow) -> Self {
+        Self {
+            id: row.id,
+            position: row.position,
+            track: row.track.into_track(row.track_id),
+            provider: row.provider,
+            track_id: row.track_id,
+            added_at: row.added_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserPlaylist {
+    #[serde(flatten)]
+    pub info: PlaylistInfo,
+    pub items: Vec<PlaylistItem>,
+}
+
+#[derive(sqlx::FromRow)]
+struct FavoriteRow {
+    provider: String,
+    track_id: i32,
+    added_at: i64,
+    #[sqlx(flatten)]
+    track: CatalogRow,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Favorite {
+    pub provider: String,
+    pub track_id: i32,
+    pub added_at: i64,
+    pub track: Option<Track>,
+}
+
+impl From<FavoriteRow> for Favorite {
+    fn from(row: FavoriteRow) -> Self {
+        Self {
+            track: row.track.into_track(row.track_id),
+            provider: row.provider,
+            track_id: row.track_id,
+            added_at: row.added_at,
+        }
+    }
+}
+
+/// Columns of `catalog_albums`, all `NULL` when the album isn't known.
+#[derive(sqlx::FromRow)]
+struct CatalogAlbumRow {
+    title: Option<String>,
+    img: Option<String>,
+    author_id: Option<i32>,
+    author_title: Option<String>,
+    author_img: Option<String>,
+}
+
+#[derive(sqlx::FromRow)]
+struct SavedAlbumRow {
+    provider: String,
+    album_id: i32,
+    added_at: i64,
+    #[sqlx(flatten)]
+    album: CatalogAlbumRow,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SavedAlbum {
+    pub provider: String,
+    pub album_id: i32,
+    pub added_at: i64,
+    /// Without its tracks, the provider's album endpoint has those. `None`
+    /// when the album's metadata isn't in the catalog.
+    pub album: Option<Album>,
+}
+
+impl From<SavedAlbumRow> for SavedAlbum {
+    fn from(row: SavedAlbumRow) -> Self {
+        let CatalogAlbumRow { title, img, author_id, author_title, author_img } = row.album;
+        let album = title.map(|title| Album {
+            id: row.album_id.to_string(),
+            title,
+            artists: match (author_id, author_title) {
+                (Some(id), Some(name)) => vec![Artist { id: id.to_string(), name, picture: author_img }],
+                _ => Vec::new(),
+            },
+            artwork: img,
+            tracks: Vec::new(),
+        });
+
+        Self { provider: row.provider, album_id: row.album_id, added_at: row.added_at, album }
+    }
+}
+
+const PLAYLIST_COLUMNS: &str = "p.id, p.name, \
+    (SELECT COUNT(*) FROM user_playlist_items i WHERE i.playlist_id = p.id) AS item_count, \
+    EXTRACT(EPOCH FROM p.created_at)::BIGINT AS created_at, \
+    EXTRACT(EPOCH FROM p.updated_at)::BIGINT AS updated_at";
+const CATALOG_COLUMNS: &str = "c.title, c.duration, c.img, c.author_id, c.author_title, c.author_img, c.album_id, c.album_title";
+
+/// Splits tracks into the parallel arrays `UNNEST` takes.
+fn unnest_args(tracks: &[TrackRef]) -> (Vec<&str>, Vec<i32>) {
+    tracks.iter().map(|t| (t.provider.as_str(), t.track_id)).unzip()
+}
+
+fn unnest_album_args(albums: &[AlbumRef]) -> (Vec<&str>, Vec<i32>) {
+    albums.iter().map(|a| (a.provider.as_str(), a.album_id)).unzip()
+}
+
+pub struct LibraryStore {
+    pool: PgPool,
+}
+
+impl LibraryStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// The tracks that aren't in their provider's catalog yet, each once.
+    pub async fn missing_tracks(&self, tracks: &[TrackRef]) -> Result<Vec<TrackRef>, LibraryError> {
+        let (providers, track_ids) = unnest_args(tracks);
+        let rows: Vec<(String, i32)> = sqlx::query_as(
+            "SELECT DISTINCT t.provider, t.track_id
+             FROM UNNEST($1::TEXT[], $2::INT[]) AS t(provider, track_id)
+             WHERE NOT EXISTS (
+               SELECT 1 FROM catalog_tracks c WHERE c.provider = t.provider AND c.id = t.track_id
+             )")
+            .bind(providers)
+            .bind(track_ids)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.into_iter().map(|(provider, track_id)| TrackRef { provider, track_id }).collect())
+    }
+
+    pub async fn playlists(&self, user_id: i64) -> Result<Vec<PlaylistInfo>, LibraryError> {
+        Ok(sqlx::query_as(&format!("SELECT {PLAYLIST_COLUMNS} FROM user_playlists p WHERE p.user_id = $1 ORDER BY p.id"))
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    pub async fn create_playlist(&self, user_id: i64, name: &str) -> Result<PlaylistInfo, LibraryError> {
+        validate_name(name)?;
+
+        Ok(sqlx::query_as(&format!(
+            "WITH p AS (INSERT INTO user_playlists (user_id, name) VALUES ($1, $2) RETURNING *)
+             SELECT {PLAYLIST_COLUMNS} FROM p"))
+            .bind(user_id)
+            .bind(name.trim())
+            .fetch_one(&self.pool)
+            .await?)
+    }
+
+    pub async fn playlist_info(&self, user_id: i64, id: i64) -> Result<PlaylistInfo, LibraryError> {
+        sqlx::query_as(&format!("SELECT {PLAYLIST_COLUMNS} FROM user_playlists p WHERE p.id = $1 AND p.user_id = $2"))
+            .bind(id)
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or_else(|| LibraryError::NotFound(format!("playlist {}", id)))
+    }
+
+    pub async fn playlist(&self, user_id: i64, id: i64) -> Result<UserPlaylist, LibraryError> {
+        let info = self.playlist_info(user_id, id).await?;
+        let rows: Vec<ItemRow> = sqlx::query_as(&format!(
+            "SELECT i.id, i.position, i.provider, i.track_id,
+                    EXTRACT(EPOCH FROM i.added_at)::BIGINT AS added_at, {CATALOG_COLUMNS}
+             FROM user_playlist_items i
+             LEFT JOIN catalog_tracks c ON c.provider = i.provider AND c.id = i.track_id
+             WHERE i.playlist_id = $1
+             ORDER BY i.position"))
+            .bind(id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(UserPlaylist { info, items: rows.into_iter().map(PlaylistItem::from).collect() })
+    }
+
+    pub async fn rename_playlist(&self, user_id: i64, id: i64, name: &str) -> Result<PlaylistInfo, LibraryError> {
+        validate_name(name)?;
+
+        sqlx::query_as(&format!(
+            "WITH p AS (
+               UPDATE user_playlists SET name = $3, updated_at = NOW() WHERE id = $1 AND user_id = $2 RETURNING *
+             )
+             SELECT {PLAYLIST_COLUMNS} FROM p"))
+            .bind(id)
+            .bind(user_id)
+            .bind(name.trim())
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or_else(|| LibraryError::NotFound(format!("playlist {}", id)))
+    }
+
+    pub async fn delete_playlist(&self, user_id: i64, id: i64) -> Result<(), LibraryError> {
+        let res = sqlx::query("DELETE FROM user_playlists WHERE id = $1 AND user_id = $2")
+            .bind(id)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(LibraryError::NotFound(format!("playlist {}", id)));
+        }
+        Ok(())
+    }
+
+    /// Locks the playlist for the rest of the transaction, so concurrent
+    /// edits can't hand out the same positions, and returns its length.
+    async fn lock_playlist(tx: &mut Transaction<'_, Postgres>, user_id: i64, id: i64) -> Result<i32, LibraryError> {
+        sqlx::query("SELECT 1 FROM user_playlists WHERE id = $1 AND user_id = $2 FOR UPDATE")
+            .bind(id)
+            .bind(user_id)
+            .fetch_optional(&mut **tx)
+            .await?
+            .ok_or_else(|| LibraryError::NotFound(format!("playlist {}", id)))?;
+
+        let len: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM user_playlist_items WHERE playlist_id = $1")
+            .bind(id)
+            .fetch_one(&mut **tx)
+            .await?;
+
+        Ok(len as i32)
+    }
+
+    async fn touch_playlist(tx: &mut Transaction<'_, Postgres>, id: i64) -> Result<(), LibraryError> {
+        sqlx::query("UPDATE user_playlists SET updated_at = NOW() WHERE id = $1")
+            .bind(id)
+            .execute(&mut **tx)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Inserts the tracks in order at `position`, at the end without one.
+    pub async fn insert_items(&self, user_id: i64, id: i64, tracks: &[TrackRef], position: Option<i32>) -> Result<(), LibraryError> {
+        let mut tx = self.pool.begin().await?;
+        let len = Self::lock_playlist(&mut tx, user_id, id).await?;
+        let position = position.map_or(len, |p| p.clamp(0, len));
+
+        sqlx::query("UPDATE user_playlist_items SET position = position + $3 WHERE playlist_id = $1 AND position >= $2")
+            .bind(id)
+            .bind(position)
+            .bind(tracks.len() as i32)
+            .execute(&mut *tx)
+            .await?;
+
+        let (providers, track_ids) = unnest_args(tracks);
+        sqlx::query(
+            "INSERT INTO user_playlist_items (playlist_id, position, provider, track_id)
+             SELECT $1, $2 + t.ord::INT - 1, t.provider, t.track_id
+             FROM UNNEST($3::TEXT[], $4::INT[]) WITH ORDINALITY AS t(provider, track_id, ord)")
+            .bind(id)
+            .bind(position)
+            .bind(providers)
+            .bind(track_ids)
+            .execute(&mut *tx)
+            .await?;
+
+        Self::touch_playlist(&mut tx, id).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Moves an item to `position`, shifting the ones in between.
+    pub async fn move_item(&self, user_id: i64, id: i64, item_id: i64, position: i32) -> Result<(), LibraryError> {
+        let mut tx = self.pool.begin().await?;
+        let len = Self::lock_playlist(&mut tx, user_id, id).await?;
+
+        let old: i32 = sqlx::query_scalar("SELECT position FROM user_playlist_items WHERE id = $1 AND playlist_id = $2")
+            .bind(item_id)
+            .bind(id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or_else(|| LibraryError::NotFound(format!("playlist item {}", item_id)))?;
+        let new = position.clamp(0, len - 1);
+
+        sqlx::query(
+            "UPDATE user_playlist_items SET position = CASE
+               WHEN id = $2 THEN $4
+               WHEN $4 < $3 THEN position + 1
+               ELSE position - 1
+             END
+             WHERE playlist_id = $1 AND position BETWEEN LEAST($3, $4) AND GREATEST($3, $4)")
+            .bind(id)
+            .bind(item_id)
+            .bind(old)
+            .bind(new)
+            .execute(&mut *tx)
+            .await?;
+
+        Self::touch_playlist(&mut tx, id).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn remove_item(&self, user_id: i64, id: i64, item_id: i64) -> Result<(), LibraryError> {
+        let mut tx = self.pool.begin().await?;
+        Self::lock_playlist(&mut tx, user_id, id).await?;
+
+        let old: i32 = sqlx::query_scalar("DELETE FROM user_playlist_items WHERE id = $1 AND playlist_id = $2 RETURNING position")
+            .bind(item_id)
+            .bind(id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or_else(|| LibraryError::NotFound(format!("playlist item {}", item_id)))?;
+
+        sqlx::query("UPDATE user_playlist_items SET position = position - 1 WHERE playlist_id = $1 AND position > $2")
+            .bind(id)
+            .bind(old)
+            .execute(&mut *tx)
+            .await?;
+
+        Self::touch_playlist(&mut tx, id).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Newest favorites first.
+    pub async fn favorites(&self, user_id: i64, page: PageParams) -> Result<Vec<Favorite>, LibraryError> {
+        let rows: Vec<FavoriteRow> = sqlx::query_as(&format!(
+            "SELECT f.provider, f.track_id, EXTRACT(EPOCH FROM f.added_at)::BIGINT AS added_at, {CATALOG_COLUMNS}
+             FROM user_favorites f
+             LEFT JOIN catalog_tracks c ON c.provider = f.provider AND c.id = f.track_id
+             WHERE f.user_id = $1
+             ORDER BY f.added_at DESC, f.provider, f.track_id
+             LIMIT $2 OFFSET $3"))
+            .bind(user_id)
+            .bind(page.fetch_limit())
+            .bind(page.offset as i64)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.into_iter().map(Favorite::from).collect())
+    }
+
+    /// Tracks that already are favorites are left alone.
+    pub async fn add_favorites(&self, user_id: i64, tracks: &[TrackRef]) -> Result<(), LibraryError> {
+        let (providers, track_ids) = unnest_args(tracks);
+        sqlx::query(
+            "INSERT INTO user_favorites (user_id, provider, track_id)
+             SELECT $1, t.provider, t.track_id FROM UNNEST($2::TEXT[], $3::INT[]) AS t(provider, track_id)
+             ON CONFLICT DO NOTHING")
+            .bind(user_id)
+            .bind(providers)
+            .bind(track_ids)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn remove_favorite(&self, user_id: i64, track: &TrackRef) -> Result<(), LibraryError> {
+        let res = sqlx::query("DELETE FROM user_favorites WHERE user_id = $1 AND provider = $2 AND track_id = $3")
+            .bind(user_id)
+            .bind(&track.provider)
+            .bind(track.track_id)
+            .execute(&self.pool)
+            .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(LibraryError::NotFound(format!("favorite {}:{}", track.provider, track.track_id)));
+        }
+        Ok(())
+    }
+
+    /// The albums that aren't in their provider's catalog yet, each once.
+    pub async fn missing_albums(&self, albums: &[AlbumRef]) -> Result<Vec<AlbumRef>, LibraryError> {
+        let (providers, album_ids) = unnest_album_args(albums);
+        let rows: Vec<(String, i32)> = sqlx::query_as(
+            "SELECT DISTINCT a.provider, a.album_id
+             FROM UNNEST($1::TEXT[], $2::INT[]) AS a(provider, album_id)
+             WHERE NOT EXISTS (
+               SELECT 1 FROM catalog_albums c WHERE c.provider = a.provider AND c.id = a.album_id
+             )")
+            .bind(providers)
+            .bind(album_ids)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.into_iter().map(|(provider, album_id)| AlbumRef { provider, album_id }).collect())
+    }
+
+    /// Most recently saved first.
+    pub async fn saved_albums(&self, user_id: i64, page: PageParams) -> Result<Vec<SavedAlbum>, LibraryError> {
+        let rows: Vec<SavedAlbumRow> = sqlx::query_as(
+            "SELECT s.provider, s.album_id, EXTRACT(EPOCH FROM s.added_at)::BIGINT AS added_at,
+                    c.title, c.img, c.author_id, c.author_title, c.author_img
+             FROM user_saved_albums s
+             LEFT JOIN catalog_albums c ON c.provider = s.provider AND c.id = s.album_id
+             WHERE s.user_id = $1
+             ORDER BY s.added_at DESC, s.provider, s.album_id
+             LIMIT $2 OFFSET $3")
+            .bind(user_id)
+            .bind(page.fetch_limit())
+            .bind(page.offset as i64)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.into_iter().map(SavedAlbum::from).collect())
+    }
+
+    /// Albums that already are saved are left alone.
+    pub async fn save_albums(&self, user_id: i64, albums: &[AlbumRef]) -> Result<(), LibraryError> {
+        let (providers, album_ids) = unnest_album_args(albums);
+        sqlx::query(
+            "INSERT INTO user_saved_albums (user_id, provider, album_id)
+             SELECT $1, a.provider, a.album_id FROM UNNEST($2::TEXT[], $3::INT[]) AS a(provider, album_id)
+             ON CONFLICT DO NOTHING")
+            .bind(user_id)
+            .bind(providers)
+            .bind(album_ids)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn remove_saved_album(&self, user_id: i64, album: &AlbumRef) -> Result<(), LibraryError> {
+        let res = sqlx::query("DELETE FROM user_saved_albums WHERE user_id = $1 AND provider = $2 AND album_id = $3")
+            .bind(user_id)
+            .bind(&album.provider)
+            .bind(album.album_id)
+            .execute(&self.pool)
+            .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(LibraryError::NotFound(format!("saved album {}:{}", album.provider, album.album_id)));
+        }
+        Ok(())
+    }
+}
+
+/// Implemented by router states serving the library.
+pub trait LibraryState: UserStoreState + SessionStoreState {
+    fn library(&self) -> &LibraryStore;
+
+    fn catalog(&self, provider: &str) -> Option<&dyn TrackCatalog>;
+}
+
+/// Fetches the tracks that aren't in their provider's catalog yet, so every
+/// added item resolves.
+async fn add_missing_tracks<S: LibraryState + Sync>(state: &S, tracks: &[TrackRef]) -> Result<(), LibraryError> {
+    check_providers(state, tracks.iter().map(|t| t.provider.as_str()))?;
+
+    let missing = state.library().missing_tracks(tracks).await?;
+    futures::stream::iter(missing)
+        .map(|track| async move {
+            let catalog = state.catalog(&track.provider).expect("providers are checked above");
+            catalog.add_track(track.track_id).await
+        })
+        .buffer_unordered(CONCURRENT_FETCHES)
+        .try_for_each(|_| async { Ok(()) })
+        .await?;
+
+    Ok(())
+}
+
+/// Same as `add_missing_tracks`, for albums.
+async fn add_missing_albums<S: LibraryState + Sync>(state: &S, albums: &[AlbumRef]) -> Result<(), LibraryError> {
+    check_providers(state, albums.iter().map(|a| a.provider.as_str()))?;
+
+    let missing = state.library().missing_albums(albums).await?;
+    futures::stream::iter(missing)
+        .map(|album| async move {
+            let catalog = state.catalog(&album.provider).expect("providers are checked above");
+            catalog.add_album(album.album_id).await
+        })
+        .buffer_unordered(CONCURRENT_FETCHES)
+        .try_for_each(|_| async { Ok(()) })
+        .await?;
+
+    Ok(())
+}
+
+fn check_providers<'a, S: LibraryState>(state: &S, mut providers: impl Iterator<Item = &'a str>) -> Result<(), LibraryError> {
+    match providers.find(|provider| state.catalog(provider).is_none()) {
+        Some(provider) => Err(LibraryError::InvalidInput(format!("Unknown provider '{}'", provider))),
+        None => Ok(()),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PlaylistBody {
+    name: String,
+}
+
+#[derive(Deserialize)]
+pub struct NewItems {
+    /// Provider-qualified ids such as `deezer:3135556`.
+    tracks: Vec<String>,
+    /// Where to insert the tracks, at the end without one.
+    position: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct ItemMove {
+    position: i32,
+}
+
+#[derive(Deserialize)]
+pub struct NewFavorites {
+    tracks: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct NewSavedAlbums {
+    /// Provider-qualified ids such as `deezer:302127`.
+    albums: Vec<String>,
+}
+
+async fn list_playlists<S: LibraryState>(AuthUser(user): AuthUser, State(state): State<S>) -> Result<impl IntoResponse, LibraryError> {
+    Ok(Json(state.library().playlists(user.id).await?))
+}
+
+async fn create_playlist<S: LibraryState>(
+    AuthUser(user): AuthUser,
+    State(state): State<S>,
+    Json(body): Json<PlaylistBody>,
+) -> Result<impl IntoResponse, LibraryError> {
+    let playlist = state.library().create_playlist(user.id, &body.name).await?;
+
+    Ok((StatusCode::CREATED, Json(playlist)))
+}
+
+async fn get_playlist<S: LibraryState>(
+    AuthUser(user): AuthUser,
+    Path(id): Path<i64>,
+    State(state): State<S>,
+) -> Result<impl IntoResponse, LibraryError> {
+    Ok(Json(state.library().playlist(user.id, id).await?))
+}
+
+async fn rename_playlist<S: LibraryState>(
+    AuthUser(user): AuthUser,
+    Path(id): Path<i64>,
+    State(state): State<S>,
+    Json(body): Json<PlaylistBody>,
+) -> Result<impl IntoResponse, LibraryError> {
+    Ok(Json(state.library().rename_playlist(user.id, id, &body.name).await?))
+}
+
+async fn delete_playlist<S: LibraryState>(
+    AuthUser(user): AuthUser,
+    Path(id): Path<i64>,
+    State(state): State<S>,
+) -> Result<impl IntoResponse, LibraryError> {
+    state.library().delete_playlist(user.id, id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Adds tracks in bulk and answers with the updated playlist.
+async fn add_items<S: LibraryState + Sync>(
+    AuthUser(user): AuthUser,
+    Path(id): Path<i64>,
+    State(state): State<S>,
+    Json(body): Json<NewItems>,
+) -> Result<impl IntoResponse, LibraryError> {
+    let tracks = parse_bulk(&body.tracks, "track")?;
+    let library = state.library();
+    // Don't fetch anything for playlists that aren't there.
+    library.playlist_info(user.id, id).await?;
+    add_missing_tracks(&state, &tracks).await?;
+    library.insert_items(user.id, id, &tracks, body.position).await?;
+
+    Ok(Json(library.playlist(user.id, id).await?))
+}
+
+async fn move_item<S: LibraryState>(
+    AuthUser(user): AuthUser,
+    Path((id, item_id)): Path<(i64, i64)>,
+    State(state): State<S>,
+    Json(body): Json<ItemMove>,
+) -> Result<impl IntoResponse, LibraryError> {
+    let library = state.library();
+    library.move_item(user.id, id, item_id, body.position).await?;
+
+    Ok(Json(library.playlist(user.id, id).await?))
+}
+
+async fn remove_item<S: LibraryState>(
+    AuthUser(user): AuthUser,
+    Path((id, item_id)): Path<(i64, i64)>,
+    State(state): State<S>,
+) -> Result<impl IntoResponse, LibraryError> {
+    state.library().remove_item(user.id, id, item_id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_favorites<S: LibraryState>(
+    AuthUser(user): AuthUser,
+    Query(page): Query<PageParams>,
+    State(state): State<S>,
+) -> Result<impl IntoResponse, LibraryError> {
+    let favorites = state.library().favorites(user.id, page).await?;
+
+    Ok(Json(Page::new(favorites, page)))
+}
+
+async fn add_favorites<S: LibraryState + Sync>(
+    AuthUser(user): AuthUser,
+    State(state): State<S>,
+    Json(body): Json<NewFavorites>,
+) -> Result<impl IntoResponse, LibraryError> {
+    let tracks = parse_bulk(&body.tracks, "track")?;
+    add_missing_tracks(&state, &tracks).await?;
+    state.library().add_favorites(user.id, &tracks).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn remove_favorite<S: LibraryState>(
+    AuthUser(user): AuthUser,
+    Path(track): Path<String>,
+    State(state): State<S>,
+) -> Result<impl IntoResponse, LibraryError> {
+    state.library().remove_favorite(user.id, &track.parse()?).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_saved_albums<S: LibraryState>(
+    AuthUser(user): AuthUser,
+    Query(page): Query<PageParams>,
+    State(state): State<S>,
+) -> Result<impl IntoResponse, LibraryError> {
+    let albums = state.library().saved_albums(user.id, page).await?;
+
+    Ok(Json(Page::new(albums, page)))
+}
+
+async fn save_albums<S: LibraryState + Sync>(
+    AuthUser(user): AuthUser,
+    State(state): State<S>,
+    Json(body): Json<NewSavedAlbums>,
+) -> Result<impl IntoResponse, LibraryError> {
+    let albums = parse_bulk(&body.albums, "album")?;
+    add_missing_albums(&state, &albums).await?;
+    state.library().save_albums(user.id, &albums).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn remove_saved_album<S: LibraryState>(
+    AuthUser(user): AuthUser,
+    Path(album): Path<String>,
+    State(state): State<S>,
+) -> Result<impl IntoResponse, LibraryError> {
+    state.library().remove_saved_album(user.id, &album.parse()?).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `/playlists`, `/favorites` and saved `/albums` of the requesting user,
+/// meant to be nested under `/library`. Only the gateway serves it: a library
+/// mixes providers, and a standalone service can only fetch missing tracks of
+/// its own.
+pub fn router<S>() -> Router<S>
+where
+    S: LibraryState + Clone + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/playlists", get(list_playlists::<S>).post(create_playlist::<S>))
+        .route("/playlists/{id}", get(get_playlist::<S>).patch(rename_playlist::<S>).delete(delete_playlist::<S>))
+        .route("/playlists/{id}/items", post(add_items::<S>))
+        .route("/playlists/{id}/items/{item_id}", patch(move_item::<S>).delete(remove_item::<S>))
+        .route("/favorites", get(list_favorites::<S>).post(add_favorites::<S>))
+        .route("/favorites/{track}", delete(remove_favorite::<S>))
+        .route("/albums", get(list_saved_albums::<S>).post(save_albums::<S>))
+        .route("/albums/{album}", delete(remove_saved_album::<S>))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::UserStore;
+
+    fn track(id: i32) -> TrackRef {
+        TrackRef { provider: "deezer".into(), track_id: id }
+    }
+
+    fn ids(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("soundcloud:{}", i)).collect()
+    }
+
+    #[test]
+    fn refs_parse() {
+        assert_eq!("deezer:3135556".parse::<TrackRef>().unwrap(), track(3135556));
+        assert_eq!(
+            "soundcloud:302127".parse::<AlbumRef>().unwrap(),
+            AlbumRef { provider: "soundcloud".into(), album_id: 302127 },
+        );
+    }
+
+    #[test]
+    fn malformed_refs_are_rejected() {
+        for id in ["3135556", "deezer:", ":3135556", ""] {
+            assert!(matches!(id.parse::<TrackRef>(), Err(LibraryError::Provider(ProviderError::InvalidId(_)))), "{}", id);
+        }
+        // Provider ids in the library are numeric and fit the catalog's INT.
+        for id in ["deezer:abc", "deezer:1.5", "deezer:99999999999"] {
+            assert!(matches!(id.parse::<TrackRef>(), Err(LibraryError::InvalidInput(_))), "{}", id);
+        }
+        assert!(matches!("deezer:abc".parse::<AlbumRef>(), Err(LibraryError::InvalidInput(e)) if e.contains("album")));
+    }
+
+    #[test]
+    fn bulk_adds_are_limited() {
+        assert_eq!(parse_bulk::<TrackRef>(&ids(1), "track").unwrap().len(), 1);
+        assert_eq!(parse_bulk::<TrackRef>(&ids(MAX_BULK_ITEMS), "track").unwrap().len(), MAX_BULK_ITEMS);
+        assert!(matches!(parse_bulk::<TrackRef>(&[], "track"), Err(LibraryError::InvalidInput(_))));
+        assert!(matches!(parse_bulk::<TrackRef>(&ids(MAX_BULK_ITEMS + 1), "track"), Err(LibraryError::InvalidInput(_))));
+
+        // One bad id fails the whole request.
+        let mut bad = ids(3);
+        bad[1] = "soundcloud:x".into();
+        assert!(matches!(parse_bulk::<TrackRef>(&bad, "track"), Err(LibraryError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn names_are_checked_without_surrounding_whitespace() {
+        assert!(validate_name("Road trip").is_ok());
+        assert!(validate_name(&"é".repeat(MAX_NAME_LEN)).is_ok());
+        assert!(validate_name(&format!("  {}  ", "a".repeat(MAX_NAME_LEN))).is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name(" \t ").is_err());
+        assert!(validate_name(&"a".repeat(MAX_NAME_LEN + 1)).is_err());
+    }
+
+    /// Track ids of the playlist in order, checking the positions have no gaps.
+    async fn order(store: &LibraryStore, user_id: i64, id: i64) -> Vec<i32> {
+        let playlist = store.playlist(user_id, id).await.unwrap();
+        for (i, item) in playlist.items.iter().enumerate() {
+            assert_eq!(item.position, i as i32);
+        }
+        playlist.items.iter().map(|item| item.track_id).collect()
+    }
+
+    /// Needs a database set up with `postgreSQL/db/init/seed.sql`:
+    /// `DATABASE_URL=postgres://... cargo test -p music-core -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn items_are_inserted_and_moved_without_gaps() {
+        let pool = PgPool::connect(&std::env::var("DATABASE_URL").unwrap()).await.unwrap();
+        let name = format!("library-test-{}", std::process::id());
+        let user = UserStore::new(pool.clone()).register(&name, "correct horse").await.unwrap();
+        let store = LibraryStore::new(pool.clone());
+        let playlist = store.create_playlist(user.id, "Test").await.unwrap();
+
+        store.insert_items(user.id, playlist.id, &[track(1), track(2), track(3)], None).await.unwrap();
+        assert_eq!(order(&store, user.id, playlist.id).await, [1, 2, 3]);
+        store.insert_items(user.id, playlist.id, &[track(4), track(5)], Some(1)).await.unwrap();
+        assert_eq!(order(&store, user.id, playlist.id).await, [1, 4, 5, 2, 3]);
+        // Positions outside the playlist are clamped to its ends.
+        store.insert_items(user.id, playlist.id, &[track(6)], Some(-3)).await.unwrap();
+        store.insert_items(user.id, playlist.id, &[track(7)], Some(100)).await.unwrap();
+        assert_eq!(order(&store, user.id, playlist.id).await, [6, 1, 4, 5, 2, 3, 7]);
+
+        let item = |track_id: i32| {
+            let store = &store;
+            async move {
+                let items = store.playlist(user.id, playlist.id).await.unwrap().items;
+                items.iter().find(|item| item.track_id == track_id).unwrap().id
+            }
+        };
+
+        // Down, shifting the ones in between up.
+        store.move_item(user.id, playlist.id, item(6).await, 3).await.unwrap();
+        assert_eq!(order(&store, user.id, playlist.id).await, [1, 4, 5, 6, 2, 3, 7]);
+        // Up, shifting the ones in between down.
+        store.move_item(user.id, playlist.id, item(3).await, 1).await.unwrap();
+        assert_eq!(order(&store, user.id, playlist.id).await, [1, 3, 4, 5, 6, 2, 7]);
+        // Past the end and onto itself.
+        store.move_item(user.id, playlist.id, item(1).await, 100).await.unwrap();
+        store.move_item(user.id, playlist.id, item(5).await, 2).await.unwrap();
+        assert_eq!(order(&store, user.id, playlist.id).await, [3, 4, 5, 6, 2, 7, 1]);
+
+        store.remove_item(user.id, playlist.id, item(6).await).await.unwrap();
+        assert_eq!(order(&store, user.id, playlist.id).await, [3, 4, 5, 2, 7, 1]);
+
+        // Someone else's playlist doesn't exist for them.
+        assert!(matches!(store.move_item(user.id + 1, playlist.id, item(3).await, 0).await, Err(LibraryError::NotFound(_))));
+
+        sqlx::query("DELETE FROM users WHERE id = $1").bind(user.id).execute(&pool).await.unwrap();
+    }
+}
@@ -0,0 +1,169 @@
+use std::fmt;
+use std::str::FromStr;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use crate::ByteStream;
+
+#[derive(Debug, Error)]
+pub enum ProviderError {
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    #[error("Operation is not supported by this provider: {0}")]
+    Unsupported(&'static str),
+
+    #[error("Upstream request failed: {0}")]
+    Upstream(String),
+
+    #[error("Invalid id: {0}")]
+    InvalidId(String),
+}
+
+/// Provider-qualified id such as `deezer:3135556`, used wherever results of
+/// several providers are mixed together.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct QualifiedId {
+    pub provider: String,
+    pub id: String,
+}
+
+impl QualifiedId {
+    pub fn new(provider: &str, id: &str) -> Self {
+        Self { provider: provider.to_owned(), id: id.to_owned() }
+    }
+}
+
+impl fmt::Display for QualifiedId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.provider, self.id)
+    }
+}
+
+impl FromStr for QualifiedId {
+    type Err = ProviderError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((provider, id)) if !provider.is_empty() && !id.is_empty() => Ok(Self::new(provider, id)),
+            _ => Err(ProviderError::InvalidId(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchKind {
+    Track,
+    Album,
+    Artist,
+    Playlist,
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchQuery {
+    pub q: String,
+    pub kind: Option<SearchKind>,
+    pub limit: u32,
+    pub offset: u32,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Artist {
+    pub id: String,
+    pub name: String,
+    pub picture: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Track {
+    pub id: String,
+    pub title: String,
+    pub artists: Vec<Artist>,
+    pub album_id: Option<String>,
+    pub album_title: Option<String>,
+    pub duration_ms: u64,
+    pub artwork: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Album {
+    pub id: String,
+    pub title: String,
+    pub artists: Vec<Artist>,
+    pub artwork: Option<String>,
+    pub tracks: Vec<Track>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Playlist {
+    pub id: String,
+    pub title: String,
+    pub owner: Option<Artist>,
+    pub artwork: Option<String>,
+    pub tracks: Vec<Track>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct SearchResults {
+    pub tracks: Vec<Track>,
+    pub albums: Vec<Album>,
+    pub artists: Vec<Artist>,
+    pub playlists: Vec<Playlist>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioFormat {
+    Flac,
+    Mp3,
+    Aac,
+    Opus,
+}
+
+impl AudioFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AudioFormat::Flac => "flac",
+            AudioFormat::Mp3 => "mp3",
+            AudioFormat::Aac => "m4a",
+            AudioFormat::Opus => "opus",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            AudioFormat::Flac => "audio/flac",
+            AudioFormat::Mp3 => "audio/mpeg",
+            AudioFormat::Aac => "audio/mp4",
+            AudioFormat::Opus => "audio/ogg",
+        }
+    }
+}
+
+pub struct AudioStream {
+    pub format: AudioFormat,
+    pub body: ByteStream,
+}
+
+/// Common interface over the catalog/streaming backends (Deezer, SoundCloud, ...).
+/// Ids are the provider's own ids, the caller is responsible for namespacing them.
+#[async_trait]
+pub trait MusicProvider: Send + Sync {
+    /// Short, stable provider name, e.g. "deezer".
+    fn name(&self) -> &'static str;
+
+    async fn search(&self, query: &SearchQuery) -> Result<SearchResults, ProviderError>;
+
+    async fn track(&self, id: &str) -> Result<Track, ProviderError>;
+
+    async fn album(&self, _id: &str) -> Result<Album, ProviderError> {
+        Err(ProviderError::Unsupported("album"))
+    }
+
+    async fn playlist(&self, _id: &str) -> Result<Playlist, ProviderError> {
+        Err(ProviderError::Unsupported("playlist"))
+    }
+
+    async fn stream(&self, id: &str) -> Result<AudioStream, ProviderError>;
+}
@@ -0,0 +1,117 @@
+/// A single `Range: bytes=...` request. Multi-range requests are not supported
+/// and are treated like no range at all, which RFC 9110 allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// `bytes=start-end`, both inclusive.
+    FromTo(u64, u64),
+    /// `bytes=start-`
+    From(u64),
+    /// `bytes=-len`, the last `len` bytes.
+    Suffix(u64),
+}
+
+impl ByteRange {
+    pub fn parse(value: &str) -> Option<Self> {
+        let spec = value.trim().strip_prefix("bytes=")?;
+        if spec.contains(',') {
+            return None;
+        }
+
+        let (start, end) = spec.split_once('-')?;
+        let (start, end) = (start.trim(), end.trim());
+        match (start.is_empty(), end.is_empty()) {
+            (false, false) => {
+                let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+                (start <= end).then_some(ByteRange::FromTo(start, end))
+            }
+            (false, true) => Some(ByteRange::From(start.parse().ok()?)),
+            (true, false) => Some(ByteRange::Suffix(end.parse().ok()?)),
+            (true, true) => None,
+        }
+    }
+
+    /// Inclusive `(start, end)` within a body of `total` bytes, `None` when the
+    /// range can't be satisfied.
+    pub fn resolve(&self, total: u64) -> Option<(u64, u64)> {
+        let (start, end) = match *self {
+            ByteRange::FromTo(start, end) => (start, end.min(total.checked_sub(1)?)),
+            ByteRange::From(start) => (start, total.checked_sub(1)?),
+            ByteRange::Suffix(0) => return None,
+            ByteRange::Suffix(len) => (total.saturating_sub(len), total.checked_sub(1)?),
+        };
+
+        (start <= end).then_some((start, end))
+    }
+
+    pub fn to_header(&self) -> String {
+        match self {
+            ByteRange::FromTo(start, end) => format!("bytes={}-{}", start, end),
+            ByteRange::From(start) => format!("bytes={}-", start),
+            ByteRange::Suffix(len) => format!("bytes=-{}", len),
+        }
+    }
+}
+
+/// Value for a `Content-Range` header of a satisfied range.
+pub fn content_range(start: u64, end: u64, total: u64) -> String {
+    format!("bytes {}-{}/{}", start, end, total)
+}
+
+/// Total length from a `Content-Range: bytes start-end/total` header.
+pub fn total_from_content_range(value: &str) -> Option<u64> {
+    value.rsplit_once('/')?.1.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(ByteRange::parse("bytes=0-499"), Some(ByteRange::FromTo(0, 499)));
+        assert_eq!(ByteRange::parse(" bytes= 500 - 999 "), Some(ByteRange::FromTo(500, 999)));
+        assert_eq!(ByteRange::parse("bytes=9500-"), Some(ByteRange::From(9500)));
+        assert_eq!(ByteRange::parse("bytes=-500"), Some(ByteRange::Suffix(500)));
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_serve() {
+        // Multiple ranges are answered with the whole body.
+        assert_eq!(ByteRange::parse("bytes=0-1,5-6"), None);
+        assert_eq!(ByteRange::parse("bytes=-"), None);
+        assert_eq!(ByteRange::parse("bytes=5-1"), None);
+        assert_eq!(ByteRange::parse("bytes=a-1"), None);
+        assert_eq!(ByteRange::parse("items=0-1"), None);
+    }
+
+    #[test]
+    fn resolves_against_the_total() {
+        assert_eq!(ByteRange::FromTo(0, 499).resolve(1000), Some((0, 499)));
+        // Ends past the body are cut to it.
+        assert_eq!(ByteRange::FromTo(900, 5000).resolve(1000), Some((900, 999)));
+        assert_eq!(ByteRange::From(9500).resolve(10_000), Some((9500, 9999)));
+        assert_eq!(ByteRange::Suffix(500).resolve(10_000), Some((9500, 9999)));
+        // A suffix longer than the body is the whole body.
+        assert_eq!(ByteRange::Suffix(500).resolve(100), Some((0, 99)));
+    }
+
+    #[test]
+    fn out_of_bounds_ranges_are_unsatisfiable() {
+        assert_eq!(ByteRange::FromTo(1000, 1100).resolve(1000), None);
+        assert_eq!(ByteRange::From(1000).resolve(1000), None);
+        assert_eq!(ByteRange::Suffix(0).resolve(1000), None);
+        assert_eq!(ByteRange::From(0).resolve(0), None);
+        assert_eq!(ByteRange::Suffix(10).resolve(0), None);
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        for range in [ByteRange::FromTo(1, 2), ByteRange::From(3), ByteRange::Suffix(4)] {
+            assert_eq!(ByteRange::parse(&range.to_header()), Some(range));
+        }
+        assert_eq!(content_range(0, 499, 1000), "bytes 0-499/1000");
+        assert_eq!(total_from_content_range("bytes 0-499/1000"), Some(1000));
+        assert_eq!(total_from_content_range("bytes 0-499/*"), None);
+        assert_eq!(total_from_content_range("bytes 0-499"), None);
+    }
+}
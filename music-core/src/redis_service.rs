@@ -0,0 +1,90 @@
+use async_trait::async_trait;
+use redis::RedisResult;
+use regex::Regex;
+use crate::sessions::SessionStore;
+use crate::users::UserError;
+
+/// Looks sessions up in a RediSearch index. Owns everything it needs, the
+/// multiplexed connection is cheap to clone per query, so it can sit in the
+/// shared state.
+pub struct RedisConnection {
+    index_name: String,
+    connection: redis::aio::MultiplexedConnection,
+    /// Extracts the user id from the key of the matching document.
+    regex_id: Regex,
+}
+
+/// Backslash-escapes everything RediSearch could read as query syntax.
+fn escape_tag(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if !c.is_ascii_alphanumeric() && c != '_' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+impl RedisConnection {
+    pub async fn try_new(
+        endpoint: &str,
+        index_name: String,
+        regex_id: Regex,
+    ) -> RedisResult<Self> {
+        let client = redis::Client::open(endpoint)?;
+        let connection = client.get_multiplexed_async_connection().await?;
+        Ok(Self {
+            connection,
+            index_name,
+            regex_id,
+        })
+    }
+
+    /// Searches for an ID in Redis using FT.SEARCH based on a session ID, asynchronously.
+    /// Returns Some(id) if found and matched by the regex, None if not found or no match.
+    pub async fn get_id(&self, session_id: &str) -> Result<Option<String>, redis::RedisError> {
+        let query = format!("@session_id:{{{}}}", escape_tag(session_id));
+
+        // The result of FT.SEARCH is a vector where the first element is the count,
+        // followed by key-value pairs of the documents.
+        let result: Vec<String> = redis::cmd("FT.SEARCH")
+            .arg(&self.index_name)
+            .arg(&query)
+            .arg("NOCONTENT")
+            .arg("LIMIT")
+            .arg("0")
+            .arg("1")
+            .query_async(&mut self.connection.clone())
+            .await?;
+
+        // A successful search with NOCONTENT returns a count and the key.
+        if result.len() == 2 {
+            let key = &result[1];
+            if let Some(caps) = self.regex_id.captures(key)
+                && let Some(regex_id) = caps.get(1) {
+                let id = regex_id.as_str();
+                return Ok(Some(id.to_owned()));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[async_trait]
+impl SessionStore for RedisConnection {
+    async fn user_id(&self, session_id: &str) -> Result<Option<i64>, UserError> {
+        let Some(id) = self.get_id(session_id).await? else {
+            return Ok(None);
+        };
+
+        match id.parse() {
+            Ok(id) => Ok(Some(id)),
+            Err(_) => {
+                eprintln!("Session key matched a non-numeric user id '{}'.", id);
+                Ok(None)
+            }
+        }
+    }
+}
@@ -0,0 +1,422 @@
+use aws_sdk_s3::Client;
+use aws_sdk_s3::config::{Credentials, Region};
+use aws_sdk_s3::error::DisplayErrorContext;
+use aws_sdk_s3::operation::get_object::GetObjectOutput;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use aws_smithy_types::error::metadata::ProvideErrorMetadata;
+use bytes::Bytes;
+use futures::StreamExt;
+use thiserror::Error;
+use tokio::sync::mpsc;
+use crate::config::S3Config;
+use crate::range::ByteRange;
+use crate::tags::Tagger;
+
+#[derive(Debug, Error)]
+pub enum CacheError {
+    #[error("Requested range is not satisfiable")]
+    RangeNotSatisfiable,
+
+    #[error("S3 request failed: {0}")]
+    S3(String),
+}
+
+/// Fetches a cached object, `None` when there is no such key. Any other
+/// failure is an error, an outage must not look like an empty cache.
+pub async fn get_cached(client: &Client, bucket: &str, key: &str, range: Option<ByteRange>) -> Result<Option<GetObjectOutput>, CacheError> {
+    let res = client.get_object().bucket(bucket).key(key)
+        .set_range(range.map(|r| r.to_header()))
+        .send().await;
+
+    match res {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => Ok(None),
+        Err(e) if e.raw_response().is_some_and(|res| res.status().as_u16() == 416) => Err(CacheError::RangeNotSatisfiable),
+        Err(e) => Err(CacheError::S3(DisplayErrorContext(e).to_string())),
+    }
+}
+
+pub async fn new_s3_client(config: &S3Config, buckets: Vec<&str>) -> Client {
+    let region = Region::new(config.s3_region.clone());
+
+    let credentials = Credentials::new(
+        config.s3_access_key.expose(),
+        config.s3_secret_key.expose(),
+        None,
+        None,
+        "Static",
+    );
+
+    // Build the AWS SDK config with all necessary settings for MinIO.
+    let config = aws_config::defaults(aws_config::BehaviorVersion::latest())
+      .region(region)
+      .credentials_provider(credentials)
+      .endpoint_url(&config.s3_endpoint)
+      .load()
+      .await;
+
+    // Create the S3 client with force_path_style enabled.
+    let s3_config = aws_sdk_s3::config::Builder::from(&config)
+      .force_path_style(true)
+      .build();
+
+    let client = Client::from_conf(s3_config);
+
+    // Create buckets.
+    for bucket_name in buckets {
+        match client.create_bucket().bucket(bucket_name).send().await {
+            Ok(_) => println!("Bucket '{}' created successfully.", bucket_name),
+            Err(e) => {
+                if let Some(service_error) = e.as_service_error() {
+                    if service_error.is_bucket_already_owned_by_you() || service_error.code() == Some("BucketAlreadyExists") {
+                        println!("Bucket '{}' already exists and is owned by you.", bucket_name);
+                    } else {
+                        panic!("Service error creating bucket '{}': {}", bucket_name, service_error);
+                    }
+                } else {
+                    // This could be a network error like a "dispatch failure".
+                    panic!("Error creating bucket '{}': {}", bucket_name, e);
+                }
+            }
+        }
+    }
+
+    client
+}
+
+/// S3 rejects parts smaller than this, except for the last one.
+const PART_SIZE: usize = 5 * 1024 * 1024;
+/// Chunks waiting to be uploaded, the streaming side waits once it's full.
+const UPLOAD_QUEUE: usize = 8;
+
+#[derive(Debug, Error)]
+pub enum UploadError {
+    #[error("S3 request failed: {0}")]
+    S3(String),
+
+    #[error("Upload is incomplete, expected {expected} bytes but got {actual}")]
+    Incomplete { expected: u64, actual: u64 },
+
+    #[error("Stream failed: {0}")]
+    Stream(std::io::Error),
+}
+
+fn s3_error<E: std::error::Error>(e: E) -> UploadError {
+    UploadError::S3(DisplayErrorContext(e).to_string())
+}
+
+/// A multipart upload that buffers at most one part in memory. Nothing is
+/// visible under `key` until `complete` succeeds.
+pub struct MultipartUpload {
+    client: Client,
+    bucket: String,
+    key: String,
+    upload_id: String,
+    buffer: Vec<u8>,
+    parts: Vec<CompletedPart>,
+    uploaded: u64,
+}
+
+impl MultipartUpload {
+    pub async fn start(client: &Client, bucket: &str, key: &str, content_type: &str) -> Result<Self, UploadError> {
+        let res = client.create_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .content_type(content_type)
+            .send()
+            .await
+            .map_err(s3_error)?;
+        let upload_id = res.upload_id()
+            .ok_or_else(|| UploadError::S3("CreateMultipartUpload returned no upload id".to_owned()))?;
+
+        Ok(Self {
+            client: client.clone(),
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            upload_id: upload_id.to_owned(),
+            buffer: Vec::with_capacity(PART_SIZE),
+            parts: Vec::new(),
+            uploaded: 0,
+        })
+    }
+
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<(), UploadError> {
+        self.buffer.extend_from_slice(chunk);
+        if self.buffer.len() >= PART_SIZE {
+            self.upload_part().await?;
+        }
+        Ok(())
+    }
+
+    async fn upload_part(&mut self) -> Result<(), UploadError> {
+        let part_number = self.parts.len() as i32 + 1;
+        let body = std::mem::replace(&mut self.buffer, Vec::with_capacity(PART_SIZE));
+        let len = body.len() as u64;
+
+        let res = self.client.upload_part()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .upload_id(&self.upload_id)
+            .part_number(part_number)
+            .body(ByteStream::from(body))
+            .send()
+            .await
+            .map_err(s3_error)?;
+
+        self.parts.push(CompletedPart::builder()
+            .part_number(part_number)
+            .set_e_tag(res.e_tag().map(str::to_owned))
+            .build());
+        self.uploaded += len;
+
+        Ok(())
+    }
+
+    /// Uploads what is left in the buffer and publishes the object. When
+    /// `expected_len` is known and doesn't match, the upload is aborted instead.
+    pub async fn complete(mut self, expected_len: Option<u64>) -> Result<(), UploadError> {
+        if (!self.buffer.is_empty() || self.parts.is_empty())
+            && let Err(e) = self.upload_part().await {
+            self.abort().await;
+            return Err(e);
+        }
+
+        if let Some(expected) = expected_len && expected != self.uploaded {
+            let actual = self.uploaded;
+            self.abort().await;
+            return Err(UploadError::Incomplete { expected, actual });
+        }
+
+        let parts = CompletedMultipartUpload::builder()
+            .set_parts(Some(std::mem::take(&mut self.parts)))
+            .build();
+        let res = self.client.complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .upload_id(&self.upload_id)
+            .multipart_upload(parts)
+            .send()
+            .await;
+
+        if let Err(e) = res {
+            self.abort().await;
+            return Err(s3_error(e));
+        }
+
+        Ok(())
+    }
+
+    pub async fn abort(self) {
+        let res = self.client.abort_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .upload_id(&self.upload_id)
+            .send()
+            .await;
+
+        if let Err(e) = res {
+            eprintln!("Failed to abort upload of '{}': {}", self.key, DisplayErrorContext(e));
+        }
+    }
+}
+
+enum UploadMessage {
+    Chunk(Bytes),
+    Finish,
+}
+
+/// Where a teed stream gets stored.
+pub struct UploadTarget {
+    pub client: Client,
+    pub bucket: String,
+    pub key: String,
+    pub content_type: String,
+    /// Size the teed stream must have, when known up front.
+    pub expected_len: Option<u64>,
+    /// Tags the stored object, the teed stream itself is left as it is.
+    pub tagger: Option<Tagger>,
+}
+
+/// What gets stored for `chunk`, tagged exactly like `tag_stream` tags what's
+/// served, so a track streamed while it's cached has the cached copy's layout.
+fn stored_chunks(tagger: &mut Option<Tagger>, chunk: Bytes) -> Vec<Bytes> {
+    match tagger {
+        Some(tagger) => tagger.push(chunk),
+        None => vec![chunk],
+    }
+}
+
+async fn run_upload(mut target: UploadTarget, mut rx: mpsc::Receiver<UploadMessage>) {
+    let mut upload = match MultipartUpload::start(&target.client, &target.bucket, &target.key, &target.content_type).await {
+        Ok(upload) => upload,
+        Err(e) => {
+            eprintln!("Failed to start upload of '{}': {}", target.key, e);
+            return;
+        }
+    };
+
+    let mut received = 0;
+    loop {
+        let chunks = match rx.recv().await {
+            Some(UploadMessage::Chunk(chunk)) => {
+                received += chunk.len() as u64;
+                stored_chunks(&mut target.tagger, chunk)
+            }
+            Some(UploadMessage::Finish) => {
+                if let Some(rest) = target.tagger.as_mut().and_then(Tagger::finish)
+                    && let Err(e) = upload.write(&rest).await {
+                    eprintln!("Upload of '{}' failed: {}", target.key, e);
+                    upload.abort().await;
+                    return;
+                }
+                if let Some(expected) = target.expected_len && expected != received {
+                    let e = UploadError::Incomplete { expected, actual: received };
+                    eprintln!("Upload of '{}' failed: {}", target.key, e);
+                    upload.abort().await;
+                    return;
+                }
+
+                // Tagging changes the size, the stream's was checked above.
+                match upload.complete(None).await {
+                    Ok(()) => println!("Uploaded '{}' to bucket '{}'.", target.key, target.bucket),
+                    Err(e) => eprintln!("Upload of '{}' failed: {}", target.key, e),
+                }
+                return;
+            }
+            // The stream was dropped (client went away) or failed before its end.
+            None => {
+                println!("Stream for '{}' ended early, aborting upload.", target.key);
+                upload.abort().await;
+                return;
+            }
+        };
+
+        for chunk in chunks {
+            if let Err(e) = upload.write(&chunk).await {
+                eprintln!("Upload of '{}' failed: {}", target.key, e);
+                upload.abort().await;
+                return;
+            }
+        }
+    }
+}
+
+/// Passes `stream` through unchanged while uploading every chunk to `target`.
+/// The object is only completed when the stream reached its end without
+/// errors, a failed or dropped stream aborts the upload. A slow upload slows
+/// the stream down instead of buffering without bound; if the upload itself
+/// fails the stream keeps going without it.
+pub fn tee_to_s3(target: UploadTarget, mut stream: crate::ByteStream) -> crate::ByteStream {
+    let (tx, rx) = mpsc::channel(UPLOAD_QUEUE);
+    tokio::spawn(run_upload(target, rx));
+
+    Box::pin(async_stream::stream! {
+        let mut tx = Some(tx);
+
+        while let Some(item) = stream.next().await {
+            match &item {
+                Ok(chunk) => {
+                    if let Some(sender) = &tx
+                        && sender.send(UploadMessage::Chunk(chunk.clone())).await.is_err() {
+                        tx = None;
+                    }
+                }
+                // Dropping the sender without `Finish` aborts the upload.
+                Err(_) => tx = None,
+            }
+
+            let failed = item.is_err();
+            yield item;
+            if failed {
+                return;
+            }
+        }
+
+        if let Some(sender) = tx {
+            let _ = sender.send(UploadMessage::Finish).await;
+        }
+    })
+}
+
+/// Writes all of `stream`, tagged if `target` says so, and checks its length.
+async fn write_all(upload: &mut MultipartUpload, target: &mut UploadTarget, mut stream: crate::ByteStream) -> Result<(), UploadError> {
+    let mut received = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(UploadError::Stream)?;
+        received += chunk.len() as u64;
+        for chunk in stored_chunks(&mut target.tagger, chunk) {
+            upload.write(&chunk).await?;
+        }
+    }
+
+    if let Some(rest) = target.tagger.as_mut().and_then(Tagger::finish) {
+        upload.write(&rest).await?;
+    }
+    if let Some(expected) = target.expected_len && expected != received {
+        return Err(UploadError::Incomplete { expected, actual: received });
+    }
+    Ok(())
+}
+
+/// Stores the whole of `stream` under `target` and only returns once the
+/// object is complete, for when nothing is streamed to a client on the way.
+pub async fn upload(mut target: UploadTarget, stream: crate::ByteStream) -> Result<(), UploadError> {
+    let mut upload = MultipartUpload::start(&target.client, &target.bucket, &target.key, &target.content_type).await?;
+
+    if let Err(e) = write_all(&mut upload, &mut target, stream).await {
+        upload.abort().await;
+        return Err(e);
+    }
+    // Tagging changes the size, the stream's was checked above.
+    upload.complete(None).await?;
+
+    println!("Uploaded '{}' to bucket '{}'.", target.key, target.bucket);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AudioFormat;
+    use crate::range::{self, ByteRange};
+    use crate::tags::{tag_stream, TrackTags};
+
+    /// An MP3 that already starts with an ID3v2 tag, which tagging replaces.
+    fn mp3() -> Vec<u8> {
+        let mut file = b"ID3\x04\x00\x00\x00\x00\x00\x0a".to_vec();
+        file.extend_from_slice(&[0; 10]);
+        file.extend((0..50_000).map(|i| (i % 251) as u8));
+        file
+    }
+
+    fn tags() -> TrackTags {
+        TrackTags { title: "Title".into(), artists: vec!["Artist".into()], ..Default::default() }
+    }
+
+    #[tokio::test]
+    async fn cached_copy_has_the_layout_that_was_streamed() {
+        let file = mp3();
+
+        // Served live, in the chunks the provider sends.
+        let chunks: Vec<std::io::Result<Bytes>> = file.chunks(4096).map(|c| Ok(Bytes::copy_from_slice(c))).collect();
+        let live = tag_stream(Tagger::new(AudioFormat::Mp3, &tags()).unwrap(), Box::pin(futures::stream::iter(chunks)));
+        let live: Vec<u8> = live.map(|chunk| chunk.unwrap()).collect::<Vec<_>>().await.concat();
+
+        // Stored by the upload, which sees the same bytes in other chunks.
+        let mut tagger = Tagger::new(AudioFormat::Mp3, &tags());
+        let mut stored: Vec<u8> = file.chunks(1000)
+            .flat_map(|c| stored_chunks(&mut tagger, Bytes::copy_from_slice(c)))
+            .collect::<Vec<_>>()
+            .concat();
+        stored.extend(tagger.as_mut().and_then(Tagger::finish).unwrap_or_default());
+
+        assert_eq!(stored, live);
+        assert_ne!(live.len(), file.len());
+
+        // A range asked for once the track is cached is out of the same total.
+        let (start, end) = ByteRange::parse("bytes=1000-").unwrap().resolve(stored.len() as u64).unwrap();
+        let content_range = range::content_range(start, end, stored.len() as u64);
+        assert_eq!(range::total_from_content_range(&content_range), Some(live.len() as u64));
+    }
+}
@@ -0,0 +1,319 @@
+//! Sessions of the web app, which logs users in and shares its session ids
+//! through Redis, and the extractors that authenticate requests with them or
+//! with API tokens.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use axum::extract::{FromRequestParts, OptionalFromRequestParts};
+use axum::http::header;
+use axum::http::request::Parts;
+use crate::users::{User, UserError, UserStoreState};
+
+/// Cookie the web app keeps its session id in, named like the indexed field.
+pub const SESSION_COOKIE: &str = "session_id";
+
+/// Resolves session ids to user ids.
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    async fn user_id(&self, session_id: &str) -> Result<Option<i64>, UserError>;
+}
+
+/// Sessions kept in memory, for tests and for running without Redis.
+#[derive(Default)]
+pub struct MemorySessionStore {
+    /// User id and when the session expires, if it does.
+    sessions: RwLock<HashMap<String, (i64, Option<Instant>)>>,
+}
+
+impl MemorySessionStore {
+    pub fn insert(&self, session_id: impl Into<String>, user_id: i64) {
+        self.sessions.write().unwrap().insert(session_id.into(), (user_id, None));
+    }
+
+    /// A session that's gone after `ttl`, like a Redis key with an expiry.
+    pub fn insert_expiring(&self, session_id: impl Into<String>, user_id: i64, ttl: Duration) {
+        self.sessions.write().unwrap().insert(session_id.into(), (user_id, Some(Instant::now() + ttl)));
+    }
+
+    pub fn remove(&self, session_id: &str) {
+        self.sessions.write().unwrap().remove(session_id);
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemorySessionStore {
+    async fn user_id(&self, session_id: &str) -> Result<Option<i64>, UserError> {
+        let sessions = self.sessions.read().unwrap();
+        Ok(sessions.get(session_id)
+            .filter(|(_, expires_at)| expires_at.is_none_or(|expires_at| Instant::now() < expires_at))
+            .map(|(user_id, _)| *user_id))
+    }
+}
+
+/// Implemented by router states that can look sessions up.
+pub trait SessionStoreState {
+    fn session_store(&self) -> &dyn SessionStore;
+}
+
+impl<T: SessionStoreState> SessionStoreState for Arc<T> {
+    fn session_store(&self) -> &dyn SessionStore {
+        (**self).session_store()
+    }
+}
+
+/// Blank ids are never a session, don't even look them up.
+fn non_blank(id: &str) -> Option<&str> {
+    Some(id.trim()).filter(|id| !id.is_empty())
+}
+
+fn session_cookie(parts: &Parts) -> Option<&str> {
+    parts.headers.get_all(header::COOKIE).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .and_then(|(_, value)| non_blank(value))
+}
+
+fn bearer_token(parts: &Parts) -> Option<&str> {
+    parts.headers.get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .and_then(non_blank)
+}
+
+/// Who a request says it's from.
+enum Identity {
+    Session(i64),
+    Token(User),
+}
+
+/// The session cookie is checked first; a bearer token can be a session id as
+/// well as an API token of the user store. `None` without credentials or with
+/// a stale cookie only, a bearer token that matches nothing is rejected.
+async fn identify<S: SessionStoreState + UserStoreState>(parts: &Parts, state: &S) -> Result<Option<Identity>, UserError> {
+    let sessions = state.session_store();
+
+    if let Some(session_id) = session_cookie(parts)
+        && let Some(user_id) = sessions.user_id(session_id).await? {
+        return Ok(Some(Identity::Session(user_id)));
+    }
+
+    let Some(token) = bearer_token(parts) else {
+        return Ok(None);
+    };
+    if let Some(user_id) = sessions.user_id(token).await? {
+        return Ok(Some(Identity::Session(user_id)));
+    }
+    match state.user_store().authenticate(token).await? {
+        Some(user) => Ok(Some(Identity::Token(user))),
+        None => Err(UserError::Unauthorized),
+    }
+}
+
+/// The user behind a request, rejects it with 401 otherwise. Only resolves
+/// the id, use `AuthUser` for the whole user.
+pub struct Authenticated {
+    pub user_id: i64,
+}
+
+impl<S: SessionStoreState + UserStoreState + Send + Sync> FromRequestParts<S> for Authenticated {
+    type Rejection = UserError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match identify(parts, state).await? {
+            Some(Identity::Session(user_id)) => Ok(Self { user_id }),
+            Some(Identity::Token(user)) => Ok(Self { user_id: user.id }),
+            None => Err(UserError::Unauthorized),
+        }
+    }
+}
+
+/// The user behind a request, authenticated like `Authenticated`. As an
+/// `Option` a request without credentials is fine, an invalid token is still
+/// rejected.
+pub struct AuthUser(pub User);
+
+impl<S: SessionStoreState + UserStoreState + Send + Sync> OptionalFromRequestParts<S> for AuthUser {
+    type Rejection = UserError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Option<Self>, Self::Rejection> {
+        match identify(parts, state).await? {
+            // The session can outlive the user it was created for.
+            Some(Identity::Session(user_id)) => match state.user_store().user(user_id).await? {
+                Some(user) => Ok(Some(AuthUser(user))),
+                None => Err(UserError::Unauthorized),
+            },
+            Some(Identity::Token(user)) => Ok(Some(AuthUser(user))),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<S: SessionStoreState + UserStoreState + Send + Sync> FromRequestParts<S> for AuthUser {
+    type Rejection = UserError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        <Self as OptionalFromRequestParts<S>>::from_request_parts(parts, state).await?
+            .ok_or(UserError::Unauthorized)
+    }
+}
+
+/// An authenticated admin, other users are rejected with 403.
+pub struct Admin {
+    pub user_id: i64,
+}
+
+impl<S: SessionStoreState + UserStoreState + Send + Sync> FromRequestParts<S> for Admin {
+    type Rejection = UserError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Authenticated { user_id } = Authenticated::from_request_parts(parts, state).await?;
+        if !state.user_store().is_admin(user_id).await? {
+            return Err(UserError::Forbidden);
+        }
+
+        Ok(Self { user_id })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+    use sqlx::postgres::PgPool;
+    use crate::users::UserStore;
+
+    struct TestState {
+        sessions: MemorySessionStore,
+        users: UserStore,
+    }
+
+    impl TestState {
+        /// The user store never connects, none of these requests gets to it.
+        fn new() -> Self {
+            let pool = PgPool::connect_lazy("postgres://localhost/unused").unwrap();
+            Self { sessions: MemorySessionStore::default(), users: UserStore::new(pool) }
+        }
+
+        /// What the web app does when someone logs in.
+        fn login(&self, session_id: &str, user_id: i64) {
+            self.sessions.insert(session_id, user_id);
+        }
+
+        async fn authenticate(&self, headers: &[(header::HeaderName, &str)]) -> Result<i64, UserError> {
+            Authenticated::from_request_parts(&mut parts(headers), self).await.map(|user| user.user_id)
+        }
+    }
+
+    fn parts(headers: &[(header::HeaderName, &str)]) -> Parts {
+        let mut request = Request::builder();
+        for (name, value) in headers {
+            request = request.header(name, *value);
+        }
+        request.body(()).unwrap().into_parts().0
+    }
+
+    impl SessionStoreState for TestState {
+        fn session_store(&self) -> &dyn SessionStore {
+            &self.sessions
+        }
+    }
+
+    impl UserStoreState for TestState {
+        fn user_store(&self) -> &UserStore {
+            &self.users
+        }
+    }
+
+    #[tokio::test]
+    async fn session_cookie_authenticates() {
+        let state = TestState::new();
+        state.login("abc123", 7);
+
+        let user_id = state.authenticate(&[(header::COOKIE, "theme=dark; session_id=abc123")]).await.unwrap();
+        assert_eq!(user_id, 7);
+    }
+
+    #[tokio::test]
+    async fn session_id_works_as_bearer_token() {
+        let state = TestState::new();
+        state.login("abc123", 7);
+
+        let user_id = state.authenticate(&[(header::AUTHORIZATION, "Bearer abc123")]).await.unwrap();
+        assert_eq!(user_id, 7);
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_session_are_rejected() {
+        let state = TestState::new();
+        state.login("abc123", 7);
+
+        assert!(matches!(state.authenticate(&[]).await, Err(UserError::Unauthorized)));
+        assert!(matches!(
+            state.authenticate(&[(header::COOKIE, "session_id=other")]).await,
+            Err(UserError::Unauthorized),
+        ));
+        // Only the session cookie counts.
+        assert!(matches!(
+            state.authenticate(&[(header::COOKIE, "sid=abc123")]).await,
+            Err(UserError::Unauthorized),
+        ));
+    }
+
+    #[tokio::test]
+    async fn logging_out_ends_the_session() {
+        let state = TestState::new();
+        state.login("abc123", 7);
+        state.sessions.remove("abc123");
+
+        assert!(matches!(
+            state.authenticate(&[(header::COOKIE, "session_id=abc123")]).await,
+            Err(UserError::Unauthorized),
+        ));
+    }
+
+    #[tokio::test]
+    async fn expired_sessions_are_rejected() {
+        let state = TestState::new();
+        state.sessions.insert_expiring("abc123", 7, Duration::from_millis(50));
+
+        let user_id = state.authenticate(&[(header::COOKIE, "session_id=abc123")]).await.unwrap();
+        assert_eq!(user_id, 7);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(matches!(
+            state.authenticate(&[(header::COOKIE, "session_id=abc123")]).await,
+            Err(UserError::Unauthorized),
+        ));
+    }
+
+    #[tokio::test]
+    async fn blank_session_ids_are_not_looked_up() {
+        let state = TestState::new();
+        // Would match if a blank id ever reached the store.
+        state.login("", 7);
+
+        for headers in [
+            [(header::COOKIE, "session_id=")],
+            [(header::COOKIE, "session_id=   ")],
+            [(header::AUTHORIZATION, "Bearer ")],
+            [(header::AUTHORIZATION, "Bearer \t ")],
+        ] {
+            assert!(matches!(state.authenticate(&headers).await, Err(UserError::Unauthorized)), "{:?}", headers);
+        }
+    }
+
+    #[tokio::test]
+    async fn optional_user_is_none_without_a_live_session() {
+        let state = TestState::new();
+        state.login("abc123", 7);
+
+        for headers in [&[][..], &[(header::COOKIE, "session_id=stale")], &[(header::COOKIE, "session_id=")]] {
+            let user = <AuthUser as OptionalFromRequestParts<_>>::from_request_parts(&mut parts(headers), &state).await.unwrap();
+            assert!(user.is_none());
+        }
+    }
+}
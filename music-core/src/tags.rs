@@ -0,0 +1,604 @@
+//! Writes track metadata into audio files while they're streamed: Vorbis
+//! comments and a PICTURE block for FLAC, an ID3v2.4 tag for MP3. Only the
+//! head of the file is rewritten, the audio itself passes through untouched.
+
+use bytes::{Buf, Bytes};
+use futures::StreamExt;
+use crate::{AudioFormat, ByteStream};
+
+/// Most bytes buffered while looking for the end of the original metadata,
+/// files with more are passed through untagged.
+const MAX_HEAD_LEN: usize = 16 * 1024 * 1024;
+/// FLAC metadata block lengths are 24 bit.
+const MAX_FLAC_BLOCK_LEN: usize = (1 << 24) - 1;
+const VENDOR: &str = "music-core";
+
+#[derive(Debug, Clone)]
+pub struct Picture {
+    pub mime_type: String,
+    pub data: Bytes,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TrackTags {
+    pub title: String,
+    pub artists: Vec<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub duration_ms: Option<u64>,
+    /// Front cover.
+    pub cover: Option<Picture>,
+}
+
+/// How far the head of the file got.
+enum State {
+    /// Original metadata isn't complete yet.
+    Buffering(Vec<u8>),
+    /// Our tags are out, the rest of the original metadata is dropped.
+    Skipping(u64),
+    Passing,
+}
+
+enum Kind {
+    /// Replaces the Vorbis comment, picture and padding blocks.
+    Flac { blocks: Vec<(u8, Vec<u8>)> },
+    /// Replaces a leading ID3v2 tag.
+    Mp3 { tag: Vec<u8> },
+}
+
+/// What to write in place of the original metadata, and where the original
+/// file continues.
+enum Head {
+    NeedMore,
+    Ready { header: Vec<u8>, resume_at: u64 },
+}
+
+/// Rewrites the head of a FLAC or MP3 stream fed to it chunk by chunk.
+pub struct Tagger {
+    kind: Kind,
+    state: State,
+}
+
+impl Tagger {
+    /// `None` for formats that can't be tagged.
+    pub fn new(format: AudioFormat, tags: &TrackTags) -> Option<Self> {
+        let kind = match format {
+            AudioFormat::Flac => {
+                let mut blocks = vec![(flac::VORBIS_COMMENT, flac::vorbis_comment(tags))];
+                if let Some(cover) = &tags.cover {
+                    let picture = flac::picture(cover);
+                    if picture.len() <= MAX_FLAC_BLOCK_LEN {
+                        blocks.push((flac::PICTURE, picture));
+                    }
+                }
+                Kind::Flac { blocks }
+            }
+            AudioFormat::Mp3 => Kind::Mp3 { tag: id3::tag(tags) },
+            AudioFormat::Aac | AudioFormat::Opus => return None,
+        };
+
+        Some(Self { kind, state: State::Buffering(Vec::new()) })
+    }
+
+    fn head(&self, buf: &[u8]) -> Head {
+        match &self.kind {
+            Kind::Flac { blocks } => flac::head(buf, blocks),
+            Kind::Mp3 { tag } => id3::head(buf, tag),
+        }
+    }
+
+    /// The output for `chunk`, empty while the original metadata is read.
+    pub fn push(&mut self, mut chunk: Bytes) -> Vec<Bytes> {
+        match &mut self.state {
+            State::Passing => vec![chunk],
+            State::Skipping(remaining) => {
+                let skip = (*remaining).min(chunk.len() as u64);
+                *remaining -= skip;
+                chunk.advance(skip as usize);
+                if *remaining == 0 {
+                    self.state = State::Passing;
+                }
+                if chunk.is_empty() { Vec::new() } else { vec![chunk] }
+            }
+            State::Buffering(buf) => {
+                buf.extend_from_slice(&chunk);
+                let buf = std::mem::take(buf);
+
+                match self.head(&buf) {
+                    Head::NeedMore if buf.len() > MAX_HEAD_LEN => {
+                        eprintln!("Metadata of a stream is over {} bytes, leaving it untagged.", MAX_HEAD_LEN);
+                        self.state = State::Passing;
+                        vec![Bytes::from(buf)]
+                    }
+                    Head::NeedMore => {
+                        self.state = State::Buffering(buf);
+                        Vec::new()
+                    }
+                    Head::Ready { header, resume_at } => {
+                        self.state = State::Skipping(resume_at);
+                        let mut out = vec![Bytes::from(header)];
+                        out.extend(self.push(Bytes::from(buf)));
+                        out
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whatever is still buffered when the stream ends. Files too short to
+    /// have complete metadata are passed on as they are.
+    pub fn finish(&mut self) -> Option<Bytes> {
+        match std::mem::replace(&mut self.state, State::Passing) {
+            State::Buffering(buf) if !buf.is_empty() => Some(Bytes::from(buf)),
+            _ => None,
+        }
+    }
+}
+
+/// Tags `stream` on the fly.
+pub fn tag_stream(mut tagger: Tagger, mut stream: ByteStream) -> ByteStream {
+    Box::pin(async_stream::stream! {
+        while let Some(item) = stream.next().await {
+            match item {
+                Ok(chunk) => {
+                    for chunk in tagger.push(chunk) {
+                        yield Ok(chunk);
+                    }
+                }
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            }
+        }
+        if let Some(rest) = tagger.finish() {
+            yield Ok(rest);
+        }
+    })
+}
+
+mod flac {
+    use super::{Head, Picture, TrackTags, VENDOR};
+
+    const MARKER: &[u8] = b"fLaC";
+    const LAST_BLOCK: u8 = 0x80;
+    const PADDING: u8 = 1;
+    pub(super) const VORBIS_COMMENT: u8 = 4;
+    pub(super) const PICTURE: u8 = 6;
+    /// Picture type of the front cover, shared with ID3's APIC.
+    const FRONT_COVER: u32 = 3;
+
+    fn push_le_string(out: &mut Vec<u8>, value: &str) {
+        out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        out.extend_from_slice(value.as_bytes());
+    }
+
+    pub(super) fn vorbis_comment(tags: &TrackTags) -> Vec<u8> {
+        let mut comments = vec![format!("TITLE={}", tags.title)];
+        comments.extend(tags.artists.iter().map(|artist| format!("ARTIST={}", artist)));
+        if let Some(album) = &tags.album {
+            comments.push(format!("ALBUM={}", album));
+        }
+        if let Some(album_artist) = &tags.album_artist {
+            comments.push(format!("ALBUMARTIST={}", album_artist));
+        }
+        if let Some(track_number) = tags.track_number {
+            comments.push(format!("TRACKNUMBER={}", track_number));
+        }
+        if let Some(disc_number) = tags.disc_number {
+            comments.push(format!("DISCNUMBER={}", disc_number));
+        }
+        // The duration is already in STREAMINFO.
+
+        let mut out = Vec::new();
+        push_le_string(&mut out, VENDOR);
+        out.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for comment in &comments {
+            push_le_string(&mut out, comment);
+        }
+        out
+    }
+
+    pub(super) fn picture(picture: &Picture) -> Vec<u8> {
+        let mut out = Vec::with_capacity(picture.data.len() + 64);
+        out.extend_from_slice(&FRONT_COVER.to_be_bytes());
+        out.extend_from_slice(&(picture.mime_type.len() as u32).to_be_bytes());
+        out.extend_from_slice(picture.mime_type.as_bytes());
+        // Empty description, then width, height, depth and palette size,
+        // which may all be left at 0.
+        out.extend_from_slice(&[0; 4 * 5]);
+        out.extend_from_slice(&(picture.data.len() as u32).to_be_bytes());
+        out.extend_from_slice(&picture.data);
+        out
+    }
+
+    fn push_block(out: &mut Vec<u8>, kind: u8, body: &[u8], last: bool) {
+        out.push(if last { kind | LAST_BLOCK } else { kind });
+        out.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        out.extend_from_slice(body);
+    }
+
+    /// Keeps STREAMINFO, seek tables and the like, and puts `blocks` in
+    /// place of the old tags.
+    pub(super) fn head(buf: &[u8], blocks: &[(u8, Vec<u8>)]) -> Head {
+        if buf.len() < MARKER.len() {
+            return Head::NeedMore;
+        }
+        if !buf.starts_with(MARKER) {
+            eprintln!("Stream isn't a native FLAC file, leaving it untagged.");
+            return Head::Ready { header: Vec::new(), resume_at: 0 };
+        }
+
+        let mut kept = Vec::new();
+        let mut pos = MARKER.len();
+        loop {
+            let Some(block_header) = buf.get(pos..pos + 4) else {
+                return Head::NeedMore;
+            };
+            let kind = block_header[0] & !LAST_BLOCK;
+            let len = u32::from_be_bytes([0, block_header[1], block_header[2], block_header[3]]) as usize;
+            let Some(body) = buf.get(pos + 4..pos + 4 + len) else {
+                return Head::NeedMore;
+            };
+            if !matches!(kind, PADDING | VORBIS_COMMENT | PICTURE) {
+                kept.push((kind, body));
+            }
+            pos += 4 + len;
+
+            if block_header[0] & LAST_BLOCK != 0 {
+                break;
+            }
+        }
+
+        let mut header = MARKER.to_vec();
+        let count = kept.len() + blocks.len();
+        let all = kept.into_iter().chain(blocks.iter().map(|(kind, body)| (*kind, body.as_slice())));
+        for (i, (kind, body)) in all.enumerate() {
+            push_block(&mut header, kind, body, i + 1 == count);
+        }
+
+        Head::Ready { header, resume_at: pos as u64 }
+    }
+}
+
+pub(crate) mod id3 {
+    use super::{Head, TrackTags};
+
+    const HEADER_LEN: usize = 10;
+    const FOOTER_FLAG: u8 = 0x10;
+    const UTF8: u8 = 3;
+    const FRONT_COVER: u8 = 3;
+
+    pub(super) fn synchsafe(value: usize) -> [u8; 4] {
+        [
+            (value >> 21 & 0x7f) as u8,
+            (value >> 14 & 0x7f) as u8,
+            (value >> 7 & 0x7f) as u8,
+            (value & 0x7f) as u8,
+        ]
+    }
+
+    pub(super) fn from_synchsafe(bytes: &[u8]) -> usize {
+        bytes.iter().fold(0, |acc, &b| acc << 7 | (b & 0x7f) as usize)
+    }
+
+    fn push_frame(out: &mut Vec<u8>, id: &[u8; 4], body: &[u8]) {
+        out.extend_from_slice(id);
+        out.extend_from_slice(&synchsafe(body.len()));
+        out.extend_from_slice(&[0, 0]);
+        out.extend_from_slice(body);
+    }
+
+    /// v2.4 separates multiple values of a text frame with NUL.
+    fn push_text_frame(out: &mut Vec<u8>, id: &[u8; 4], values: &[&str]) {
+        let mut body = vec![UTF8];
+        body.extend_from_slice(values.join("\0").as_bytes());
+        push_frame(out, id, &body);
+    }
+
+    pub(super) fn tag(tags: &TrackTags) -> Vec<u8> {
+        let mut frames = Vec::new();
+        push_text_frame(&mut frames, b"TIT2", &[&tags.title]);
+        if !tags.artists.is_empty() {
+            let artists: Vec<&str> = tags.artists.iter().map(String::as_str).collect();
+            push_text_frame(&mut frames, b"TPE1", &artists);
+        }
+        if let Some(album) = &tags.album {
+            push_text_frame(&mut frames, b"TALB", &[album]);
+        }
+        if let Some(album_artist) = &tags.album_artist {
+            push_text_frame(&mut frames, b"TPE2", &[album_artist]);
+        }
+        if let Some(track_number) = tags.track_number {
+            push_text_frame(&mut frames, b"TRCK", &[&track_number.to_string()]);
+        }
+        if let Some(disc_number) = tags.disc_number {
+            push_text_frame(&mut frames, b"TPOS", &[&disc_number.to_string()]);
+        }
+        if let Some(duration_ms) = tags.duration_ms {
+            push_text_frame(&mut frames, b"TLEN", &[&duration_ms.to_string()]);
+        }
+        if let Some(cover) = &tags.cover {
+            let mut body = vec![UTF8];
+            body.extend_from_slice(cover.mime_type.as_bytes());
+            // NUL ends the MIME type, then the picture type and an empty description.
+            body.extend_from_slice(&[0, FRONT_COVER, 0]);
+            body.extend_from_slice(&cover.data);
+            push_frame(&mut frames, b"APIC", &body);
+        }
+
+        let mut out = Vec::with_capacity(HEADER_LEN + frames.len());
+        out.extend_from_slice(b"ID3\x04\x00\x00");
+        out.extend_from_slice(&synchsafe(frames.len()));
+        out.extend_from_slice(&frames);
+        out
+    }
+
+    /// A tag holding nothing but a PRIV frame.
+    pub(crate) fn private_tag(owner: &str, data: &[u8]) -> Vec<u8> {
+        let mut body = owner.as_bytes().to_vec();
+        body.push(0);
+        body.extend_from_slice(data);
+
+        let mut frames = Vec::new();
+        push_frame(&mut frames, b"PRIV", &body);
+
+        let mut out = Vec::with_capacity(HEADER_LEN + frames.len());
+        out.extend_from_slice(b"ID3\x04\x00\x00");
+        out.extend_from_slice(&synchsafe(frames.len()));
+        out.extend_from_slice(&frames);
+        out
+    }
+
+    /// Length of the ID3v2 tag `buf` starts with, 0 without one and `None`
+    /// while `buf` is too short to tell.
+    pub(crate) fn len(buf: &[u8]) -> Option<usize> {
+        if buf.len() < HEADER_LEN {
+            return None;
+        }
+        if !buf.starts_with(b"ID3") {
+            return Some(0);
+        }
+
+        let footer = if buf[5] & FOOTER_FLAG != 0 { HEADER_LEN } else { 0 };
+        Some(HEADER_LEN + from_synchsafe(&buf[6..10]) + footer)
+    }
+
+    /// Puts `tag` first and drops an ID3v2 tag the file already starts with.
+    pub(super) fn head(buf: &[u8], tag: &[u8]) -> Head {
+        match len(buf) {
+            Some(resume_at) => Head::Ready { header: tag.to_vec(), resume_at: resume_at as u64 },
+            None => Head::NeedMore,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags() -> TrackTags {
+        TrackTags {
+            title: "Title".into(),
+            artists: vec!["First".into(), "Second".into()],
+            album: Some("Album".into()),
+            album_artist: Some("First".into()),
+            track_number: Some(3),
+            disc_number: Some(1),
+            duration_ms: Some(215_000),
+            // Long enough for the frame size to need more than one synchsafe byte.
+            cover: Some(Picture { mime_type: "image/jpeg".into(), data: Bytes::from(vec![0xab; 300]) }),
+        }
+    }
+
+    /// Feeds `input` through a tagger in `chunk_len` sized chunks.
+    fn tag(format: AudioFormat, tags: &TrackTags, input: &[u8], chunk_len: usize) -> Vec<u8> {
+        let mut tagger = Tagger::new(format, tags).unwrap();
+        let mut out = Vec::new();
+        for chunk in input.chunks(chunk_len) {
+            for chunk in tagger.push(Bytes::copy_from_slice(chunk)) {
+                out.extend_from_slice(&chunk);
+            }
+        }
+        if let Some(rest) = tagger.finish() {
+            out.extend_from_slice(&rest);
+        }
+        out
+    }
+
+    fn be_u32(buf: &[u8], pos: usize) -> usize {
+        u32::from_be_bytes(buf[pos..pos + 4].try_into().unwrap()) as usize
+    }
+
+    fn le_u32(buf: &[u8], pos: usize) -> usize {
+        u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap()) as usize
+    }
+
+    fn flac_block(out: &mut Vec<u8>, kind: u8, body: &[u8]) {
+        out.push(kind);
+        out.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        out.extend_from_slice(body);
+    }
+
+    /// The metadata blocks of a FLAC file as (header byte, body), and the audio after them.
+    fn flac_blocks(file: &[u8]) -> (Vec<(u8, &[u8])>, &[u8]) {
+        assert_eq!(&file[..4], b"fLaC");
+        let mut blocks = Vec::new();
+        let mut pos = 4;
+        loop {
+            let header = file[pos];
+            let len = u32::from_be_bytes([0, file[pos + 1], file[pos + 2], file[pos + 3]]) as usize;
+            blocks.push((header, &file[pos + 4..pos + 4 + len]));
+            pos += 4 + len;
+            if header & 0x80 != 0 {
+                return (blocks, &file[pos..]);
+            }
+        }
+    }
+
+    fn le_string(buf: &[u8], pos: &mut usize) -> String {
+        let len = le_u32(buf, *pos);
+        let value = String::from_utf8(buf[*pos + 4..*pos + 4 + len].to_vec()).unwrap();
+        *pos += 4 + len;
+        value
+    }
+
+    /// The vendor string and comments of a Vorbis comment block.
+    fn vorbis_comments(body: &[u8]) -> (String, Vec<String>) {
+        let mut pos = 0;
+        let vendor = le_string(body, &mut pos);
+        let count = le_u32(body, pos);
+        pos += 4;
+        let comments = (0..count).map(|_| le_string(body, &mut pos)).collect();
+        assert_eq!(pos, body.len());
+        (vendor, comments)
+    }
+
+    #[test]
+    fn flac_tags_replace_old_metadata() {
+        let stream_info = [0x11; 34];
+        let seek_table = [0x22; 18];
+        let mut input = b"fLaC".to_vec();
+        flac_block(&mut input, 0, &stream_info);
+        flac_block(&mut input, flac::VORBIS_COMMENT, b"\x03\x00\x00\x00old\x00\x00\x00\x00");
+        flac_block(&mut input, 3, &seek_table);
+        flac_block(&mut input, flac::PICTURE, &[0x33; 40]);
+        flac_block(&mut input, 1 | 0x80, &[0; 1024]);
+        let audio: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        input.extend_from_slice(&audio);
+
+        let out = tag(AudioFormat::Flac, &tags(), &input, 7);
+        let (blocks, rest) = flac_blocks(&out);
+
+        // STREAMINFO and the seek table are kept in order, padding and the old tags are gone.
+        let kinds: Vec<u8> = blocks.iter().map(|(header, _)| *header).collect();
+        assert_eq!(kinds, [0, 3, flac::VORBIS_COMMENT, flac::PICTURE | 0x80]);
+        assert_eq!(blocks[0].1, stream_info);
+        assert_eq!(blocks[1].1, seek_table);
+        assert_eq!(rest, audio);
+
+        let (vendor, comments) = vorbis_comments(blocks[2].1);
+        assert_eq!(vendor, VENDOR);
+        assert_eq!(comments, [
+            "TITLE=Title", "ARTIST=First", "ARTIST=Second", "ALBUM=Album",
+            "ALBUMARTIST=First", "TRACKNUMBER=3", "DISCNUMBER=1",
+        ]);
+
+        let picture = blocks[3].1;
+        assert_eq!(be_u32(picture, 0), 3);
+        let mime_len = be_u32(picture, 4);
+        assert_eq!(&picture[8..8 + mime_len], b"image/jpeg");
+        let pos = 8 + mime_len;
+        // Description length, width, height, depth and palette size.
+        assert_eq!(&picture[pos..pos + 20], [0; 20]);
+        assert_eq!(be_u32(picture, pos + 20), 300);
+        assert_eq!(&picture[pos + 24..], [0xab; 300]);
+    }
+
+    #[test]
+    fn flac_last_block_flag_moves_to_the_new_blocks() {
+        let mut input = b"fLaC".to_vec();
+        flac_block(&mut input, 0x80, &[0x11; 34]);
+        input.extend_from_slice(b"audio");
+
+        let tags = TrackTags { title: "Title".into(), ..TrackTags::default() };
+        let out = tag(AudioFormat::Flac, &tags, &input, 1);
+        let (blocks, rest) = flac_blocks(&out);
+
+        let kinds: Vec<u8> = blocks.iter().map(|(header, _)| *header).collect();
+        assert_eq!(kinds, [0, flac::VORBIS_COMMENT | 0x80]);
+        assert_eq!(rest, b"audio");
+    }
+
+    #[test]
+    fn non_flac_streams_pass_through() {
+        let input = b"OggS and whatever follows".to_vec();
+        assert_eq!(tag(AudioFormat::Flac, &tags(), &input, 3), input);
+    }
+
+    #[test]
+    fn synchsafe_round_trips() {
+        for value in [0, 0x7f, 0x80, 300, 0x3fff, 0x4000, (1 << 28) - 1] {
+            let bytes = id3::synchsafe(value);
+            assert!(bytes.iter().all(|b| b & 0x80 == 0));
+            assert_eq!(id3::from_synchsafe(&bytes), value);
+        }
+        assert_eq!(id3::synchsafe(300), [0, 0, 2, 44]);
+    }
+
+    /// The frames of an ID3v2.4 tag as (id, body), and what follows the tag.
+    fn id3_frames(file: &[u8]) -> (Vec<(String, &[u8])>, &[u8]) {
+        assert_eq!(&file[..6], b"ID3\x04\x00\x00");
+        let end = 10 + id3::from_synchsafe(&file[6..10]);
+        let mut frames = Vec::new();
+        let mut pos = 10;
+        while pos < end {
+            let id = String::from_utf8(file[pos..pos + 4].to_vec()).unwrap();
+            let len = id3::from_synchsafe(&file[pos + 4..pos + 8]);
+            assert_eq!(&file[pos + 8..pos + 10], [0, 0]);
+            frames.push((id, &file[pos + 10..pos + 10 + len]));
+            pos += 10 + len;
+        }
+        assert_eq!(pos, end);
+        (frames, &file[end..])
+    }
+
+    #[test]
+    fn id3_tag_replaces_the_old_one() {
+        // A v2.3 tag with a 200 byte body, which also needs two synchsafe bytes.
+        let mut input = b"ID3\x03\x00\x00".to_vec();
+        input.extend_from_slice(&id3::synchsafe(200));
+        input.extend_from_slice(&[0x44; 200]);
+        let audio: Vec<u8> = (0..3000).map(|i| (i % 253) as u8).collect();
+        input.extend_from_slice(&audio);
+
+        let out = tag(AudioFormat::Mp3, &tags(), &input, 9);
+        let (frames, rest) = id3_frames(&out);
+        assert_eq!(rest, audio);
+
+        let ids: Vec<&str> = frames.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(ids, ["TIT2", "TPE1", "TALB", "TPE2", "TRCK", "TPOS", "TLEN", "APIC"]);
+        assert_eq!(frames[0].1, b"\x03Title");
+        assert_eq!(frames[1].1, b"\x03First\0Second");
+        assert_eq!(frames[6].1, b"\x03215000");
+
+        let mut apic = b"\x03image/jpeg\0\x03\0".to_vec();
+        apic.extend_from_slice(&[0xab; 300]);
+        assert_eq!(frames[7].1, apic);
+    }
+
+    #[test]
+    fn id3_tag_is_added_to_untagged_files() {
+        let audio = b"\xff\xfb\x90\x64 and more frames".to_vec();
+        let out = tag(AudioFormat::Mp3, &tags(), &audio, 4);
+        let (frames, rest) = id3_frames(&out);
+        assert_eq!(frames.len(), 8);
+        assert_eq!(rest, audio);
+    }
+
+    #[test]
+    fn id3_footer_counts_towards_the_tag() {
+        let mut input = b"ID3\x04\x00\x10".to_vec();
+        input.extend_from_slice(&id3::synchsafe(20));
+        input.extend_from_slice(&[0x44; 20]);
+        input.extend_from_slice(b"3DI\x04\x00\x10");
+        input.extend_from_slice(&id3::synchsafe(20));
+        input.extend_from_slice(b"audio");
+
+        assert_eq!(id3::len(&input), Some(40));
+        assert_eq!(id3::len(b"ID3\x04"), None);
+        assert_eq!(id3::len(b"\xff\xfb\x90\x64\0\0\0\0\0\0"), Some(0));
+
+        let out = tag(AudioFormat::Mp3, &tags(), &input, 5);
+        let (_, rest) = id3_frames(&out);
+        assert_eq!(rest, b"audio");
+    }
+
+    #[test]
+    fn short_streams_pass_through() {
+        assert_eq!(tag(AudioFormat::Mp3, &tags(), b"ID3", 1), b"ID3");
+        assert_eq!(tag(AudioFormat::Flac, &tags(), b"fLaC\0\0", 2), b"fLaC\0\0");
+    }
+}
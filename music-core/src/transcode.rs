@@ -0,0 +1,305 @@
+//! CPU transcoding through `ffmpeg`, the source is piped into its stdin and
+//! the result read from its stdout while both are being streamed.
+//!
+//! ffmpeg has to be installed wherever a service with transcoding enabled
+//! runs, built with libopus and libmp3lame (AAC uses its native encoder).
+//! Services check it at startup with `Transcoder::probe`.
+
+use std::process::Stdio;
+use std::sync::Arc;
+use futures::StreamExt;
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tokio_util::io::ReaderStream;
+use crate::config::TranscodeConfig;
+use crate::{AudioFormat, ByteStream};
+
+/// Most of ffmpeg's stderr kept for the error message.
+const MAX_STDERR_LEN: u64 = 4096;
+
+#[derive(Debug, Error)]
+pub enum TranscodeError {
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+
+    #[error("Failed to start '{path}': {source}")]
+    Spawn { path: String, source: std::io::Error },
+
+    #[error("'{path} -version' failed: {reason}")]
+    Probe { path: String, reason: String },
+
+    #[error("Transcoding is disabled")]
+    Disabled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// In an Ogg container.
+    Opus,
+    Mp3,
+    /// AAC-LC in fragmented MP4, which can be written without seeking back.
+    Aac,
+}
+
+impl Codec {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "opus" => Some(Codec::Opus),
+            "mp3" => Some(Codec::Mp3),
+            "aac" => Some(Codec::Aac),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Opus => "opus",
+            Codec::Mp3 => "mp3",
+            Codec::Aac => "aac",
+        }
+    }
+
+    pub fn audio_format(&self) -> AudioFormat {
+        match self {
+            Codec::Opus => AudioFormat::Opus,
+            Codec::Mp3 => AudioFormat::Mp3,
+            Codec::Aac => AudioFormat::Aac,
+        }
+    }
+
+    /// In kbps.
+    fn default_bitrate(&self) -> u32 {
+        match self {
+            Codec::Opus => 128,
+            Codec::Mp3 => 192,
+            Codec::Aac => 160,
+        }
+    }
+
+    /// Bitrates the encoder accepts, in kbps.
+    fn bitrates(&self) -> std::ops::RangeInclusive<u32> {
+        match self {
+            Codec::Opus => 6..=510,
+            Codec::Mp3 | Codec::Aac => 32..=320,
+        }
+    }
+
+    fn ffmpeg_args(&self) -> &'static [&'static str] {
+        match self {
+            Codec::Opus => &["-c:a", "libopus", "-f", "ogg"],
+            Codec::Mp3 => &["-c:a", "libmp3lame", "-f", "mp3"],
+            Codec::Aac => &["-c:a", "aac", "-f", "mp4", "-movflags", "frag_keyframe+empty_moov"],
+        }
+    }
+}
+
+/// What a track is transcoded to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TranscodeTarget {
+    pub codec: Codec,
+    /// In kbps.
+    pub bitrate: u32,
+}
+
+impl TranscodeTarget {
+    pub fn new(codec: Codec, bitrate: Option<u32>) -> Result<Self, TranscodeError> {
+        let bitrate = bitrate.unwrap_or(codec.default_bitrate());
+        if !codec.bitrates().contains(&bitrate) {
+            let (min, max) = codec.bitrates().into_inner();
+            return Err(TranscodeError::InvalidInput(
+                format!("{} bitrate must be between {} and {} kbps", codec.name(), min, max),
+            ));
+        }
+        Ok(Self { codec, bitrate })
+    }
+
+    /// Tells variants apart, e.g. in cache keys: `opus_128k`.
+    pub fn name(&self) -> String {
+        format!("{}_{}k", self.codec.name(), self.bitrate)
+    }
+}
+
+/// `?codec=opus&bitrate=96`, no codec means the track as it is.
+#[derive(Deserialize, Debug)]
+pub struct TranscodeParams {
+    codec: Option<String>,
+    /// In kbps, defaults to a codec specific one.
+    bitrate: Option<u32>,
+}
+
+impl TranscodeParams {
+    pub fn target(&self) -> Result<Option<TranscodeTarget>, TranscodeError> {
+        let Some(codec) = &self.codec else {
+            return match self.bitrate {
+                Some(_) => Err(TranscodeError::InvalidInput("bitrate needs a codec".to_owned())),
+                None => Ok(None),
+            };
+        };
+        let codec = Codec::from_name(codec)
+            .ok_or_else(|| TranscodeError::InvalidInput(format!("Unknown codec '{}'", codec)))?;
+
+        TranscodeTarget::new(codec, self.bitrate).map(Some)
+    }
+}
+
+/// Runs ffmpeg, at most `max_transcodes` at once so transcoding can't starve
+/// the rest of the service of CPU. Further ones wait for a free slot.
+#[derive(Clone)]
+pub struct Transcoder {
+    enabled: bool,
+    ffmpeg_path: String,
+    permits: Arc<Semaphore>,
+}
+
+impl Transcoder {
+    pub fn new(config: &TranscodeConfig) -> Self {
+        let max_transcodes = config.max_transcodes
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+
+        Self {
+            enabled: config.transcoding_enabled,
+            ffmpeg_path: config.ffmpeg_path.clone(),
+            permits: Arc::new(Semaphore::new(max_transcodes)),
+        }
+    }
+
+    /// Checks that ffmpeg runs when transcoding is enabled, so a missing one
+    /// shows at startup rather than on the first transcode.
+    pub async fn probe(&self) -> Result<(), TranscodeError> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        let output = Command::new(&self.ffmpeg_path)
+            .arg("-version")
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .map_err(|source| TranscodeError::Spawn { path: self.ffmpeg_path.clone(), source })?;
+        if !output.status.success() {
+            let reason = format!("exited with {}: {}", output.status, String::from_utf8_lossy(&output.stderr).trim());
+            return Err(TranscodeError::Probe { path: self.ffmpeg_path.clone(), reason });
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        println!("Transcoding with {}", stdout.lines().next().unwrap_or_default());
+        Ok(())
+    }
+
+    /// Transcodes `input`, whatever ffmpeg can decode, to `target`. Tags are
+    /// carried over, embedded covers aren't. The returned stream ends with an
+    /// error when `input` fails or ffmpeg exits unsuccessfully, dropping it
+    /// kills ffmpeg.
+    pub async fn transcode(&self, input: ByteStream, target: TranscodeTarget) -> Result<ByteStream, TranscodeError> {
+        if !self.enabled {
+            return Err(TranscodeError::Disabled);
+        }
+        let permit = self.permits.clone().acquire_owned().await.expect("the semaphore is never closed");
+
+        let bitrate = format!("{}k", target.bitrate);
+        let mut child = Command::new(&self.ffmpeg_path)
+            .args(["-hide_banner", "-loglevel", "error", "-i", "pipe:0", "-map", "0:a:0"])
+            .args(target.codec.ffmpeg_args())
+            .args(["-b:a", &bitrate, "pipe:1"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|source| TranscodeError::Spawn { path: self.ffmpeg_path.clone(), source })?;
+
+        let (Some(mut stdin), Some(stdout), Some(stderr)) = (child.stdin.take(), child.stdout.take(), child.stderr.take()) else {
+            unreachable!("all pipes are requested above");
+        };
+
+        let feeder = tokio::spawn(async move {
+            let mut input = input;
+            while let Some(chunk) = input.next().await {
+                stdin.write_all(&chunk?).await?;
+            }
+            // Dropping stdin closes it, which is how ffmpeg learns the input ended.
+            Ok::<_, std::io::Error>(())
+        });
+        let errors = tokio::spawn(async move {
+            let mut errors = String::new();
+            let _ = stderr.take(MAX_STDERR_LEN).read_to_string(&mut errors).await;
+            errors
+        });
+
+        Ok(Box::pin(async_stream::stream! {
+            let _permit = permit;
+            let mut stdout = ReaderStream::new(stdout);
+
+            while let Some(chunk) = stdout.next().await {
+                let failed = chunk.is_err();
+                yield chunk;
+                if failed {
+                    return;
+                }
+            }
+
+            let status = match child.wait().await {
+                Ok(status) => status,
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            };
+            if !status.success() {
+                let errors = errors.await.unwrap_or_default();
+                yield Err(std::io::Error::other(format!("ffmpeg failed with {}: {}", status, errors.trim())));
+                return;
+            }
+            match feeder.await {
+                Ok(Err(e)) => yield Err(e),
+                Err(e) => yield Err(std::io::Error::other(e)),
+                Ok(Ok(())) => {}
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transcoder(transcoding_enabled: bool, ffmpeg_path: &str) -> Transcoder {
+        Transcoder::new(&TranscodeConfig {
+            transcoding_enabled,
+            ffmpeg_path: ffmpeg_path.to_owned(),
+            max_transcodes: Some(1),
+        })
+    }
+
+    #[test]
+    fn targets_from_params() {
+        let params = |codec: Option<&str>, bitrate| TranscodeParams { codec: codec.map(str::to_owned), bitrate };
+
+        assert_eq!(params(None, None).target().unwrap(), None);
+        assert_eq!(params(Some("AAC"), None).target().unwrap().unwrap().name(), "aac_160k");
+        assert_eq!(params(Some("opus"), Some(96)).target().unwrap().unwrap().name(), "opus_96k");
+        assert_eq!(Codec::Aac.audio_format(), AudioFormat::Aac);
+
+        assert!(params(None, Some(96)).target().is_err());
+        assert!(params(Some("flac"), None).target().is_err());
+        assert!(params(Some("mp3"), Some(512)).target().is_err());
+    }
+
+    #[tokio::test]
+    async fn probe_finds_missing_ffmpeg() {
+        let missing = "/nonexistent/ffmpeg";
+        assert!(matches!(transcoder(true, missing).probe().await, Err(TranscodeError::Spawn { .. })));
+        // Nothing is run when transcoding is off.
+        assert!(transcoder(false, missing).probe().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn disabled_transcoder_refuses() {
+        let target = TranscodeTarget::new(Codec::Opus, None).unwrap();
+        let input: ByteStream = Box::pin(futures::stream::empty());
+        assert!(matches!(transcoder(false, "ffmpeg").transcode(input, target).await, Err(TranscodeError::Disabled)));
+    }
+}
@@ -0,0 +1,340 @@
+//! Accounts of the people using the services: registration, password login
+//! and the API tokens requests are authenticated with.
+
+use std::sync::Arc;
+use argon2::Argon2;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use axum::extract::{Path, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::postgres::PgPool;
+use thiserror::Error;
+use crate::error::ErrorBody;
+use crate::sessions::SessionStoreState;
+
+pub use crate::sessions::AuthUser;
+
+const MIN_PASSWORD_LEN: usize = 8;
+const MAX_USERNAME_LEN: usize = 32;
+const TOKEN_BYTES: usize = 32;
+
+#[derive(Debug, Error)]
+pub enum UserError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error("Username '{0}' is already taken")]
+    UsernameTaken(String),
+
+    #[error("Wrong username or password")]
+    InvalidCredentials,
+
+    #[error("A valid API token is required")]
+    Unauthorized,
+
+    #[error("Only admins may do this")]
+    Forbidden,
+
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+
+    #[error("Failed to hash password: {0}")]
+    Hash(argon2::password_hash::Error),
+
+    #[error("Session store error: {0}")]
+    SessionStore(#[from] redis::RedisError),
+}
+
+impl IntoResponse for UserError {
+    fn into_response(self) -> Response {
+        eprintln!("{}", self);
+
+        let (status, code) = match &self {
+            UserError::UsernameTaken(_) => (StatusCode::CONFLICT, "username_taken"),
+            UserError::InvalidCredentials => (StatusCode::UNAUTHORIZED, "invalid_credentials"),
+            UserError::Unauthorized => (StatusCode::UNAUTHORIZED, "unauthorized"),
+            UserError::Forbidden => (StatusCode::FORBIDDEN, "forbidden"),
+            UserError::NotFound(_) => (StatusCode::NOT_FOUND, "not_found"),
+            UserError::InvalidInput(_) => (StatusCode::BAD_REQUEST, "invalid_input"),
+            UserError::Database(_) | UserError::Hash(_) => (StatusCode::INTERNAL_SERVER_ERROR, "internal_error"),
+            UserError::SessionStore(_) => (StatusCode::SERVICE_UNAVAILABLE, "session_store_unavailable"),
+        };
+
+        let mut response = (status, Json(ErrorBody::new(code, self.to_string()))).into_response();
+        if status == StatusCode::UNAUTHORIZED {
+            response.headers_mut().insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+        }
+
+        response
+    }
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+    /// Admins see and manage what the services run on, e.g. the Deezer accounts.
+    pub is_admin: bool,
+    /// Unix time in seconds.
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct ApiToken {
+    pub id: i64,
+    pub name: String,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+}
+
+/// A freshly created token, the only time its value is ever shown.
+#[derive(Debug, Serialize)]
+pub struct IssuedToken {
+    #[serde(flatten)]
+    pub info: ApiToken,
+    pub token: String,
+}
+
+fn validate_username(username: &str) -> Result<(), UserError> {
+    let valid_chars = username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if username.is_empty() || username.len() > MAX_USERNAME_LEN || !valid_chars {
+        return Err(UserError::InvalidInput(format!(
+            "Usernames are 1-{} characters of letters, digits, '_' and '-'", MAX_USERNAME_LEN,
+        )));
+    }
+    Ok(())
+}
+
+/// Tokens are random enough that a plain SHA-256 is all the database needs,
+/// unlike passwords.
+fn token_hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+const USER_COLUMNS: &str = "id, username, is_admin, EXTRACT(EPOCH FROM created_at)::BIGINT AS created_at";
+const TOKEN_COLUMNS: &str = "id, name, EXTRACT(EPOCH FROM created_at)::BIGINT AS created_at, \
+    EXTRACT(EPOCH FROM last_used_at)::BIGINT AS last_used_at";
+
+pub struct UserStore {
+    pool: PgPool,
+}
+
+impl UserStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn register(&self, username: &str, password: &str) -> Result<User, UserError> {
+        validate_username(username)?;
+        if password.len() < MIN_PASSWORD_LEN {
+            return Err(UserError::InvalidInput(format!("Passwords need at least {} characters", MIN_PASSWORD_LEN)));
+        }
+
+        let salt = SaltString::generate(&mut OsRng);
+        let password_hash = Argon2::default().hash_password(password.as_bytes(), &salt)
+            .map_err(UserError::Hash)?
+            .to_string();
+
+        let res = sqlx::query_as(&format!(
+            "INSERT INTO users (username, password_hash) VALUES ($1, $2) RETURNING {USER_COLUMNS}"))
+            .bind(username)
+            .bind(password_hash)
+            .fetch_one(&self.pool)
+            .await;
+
+        match res {
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Err(UserError::UsernameTaken(username.to_owned())),
+            res => Ok(res?),
+        }
+    }
+
+    pub async fn login(&self, username: &str, password: &str) -> Result<User, UserError> {
+        let row: Option<(String,)> = sqlx::query_as("SELECT password_hash FROM users WHERE username = $1")
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await?;
+        let (password_hash,) = row.ok_or(UserError::InvalidCredentials)?;
+
+        let password_hash = PasswordHash::new(&password_hash).map_err(UserError::Hash)?;
+        Argon2::default().verify_password(password.as_bytes(), &password_hash)
+            .map_err(|_| UserError::InvalidCredentials)?;
+
+        self.user_by_name(username).await?.ok_or(UserError::InvalidCredentials)
+    }
+
+    pub async fn user(&self, id: i64) -> Result<Option<User>, UserError> {
+        Ok(sqlx::query_as(&format!("SELECT {USER_COLUMNS} FROM users WHERE id = $1"))
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    async fn user_by_name(&self, username: &str) -> Result<Option<User>, UserError> {
+        Ok(sqlx::query_as(&format!("SELECT {USER_COLUMNS} FROM users WHERE username = $1"))
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    pub async fn create_token(&self, user_id: i64, name: &str) -> Result<IssuedToken, UserError> {
+        let mut bytes = [0u8; TOKEN_BYTES];
+        OsRng.fill_bytes(&mut bytes);
+        let token = hex::encode(bytes);
+
+        let info = sqlx::query_as(&format!(
+            "INSERT INTO api_tokens (user_id, name, token_hash) VALUES ($1, $2, $3) RETURNING {TOKEN_COLUMNS}"))
+            .bind(user_id)
+            .bind(name)
+            .bind(token_hash(&token))
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(IssuedToken { info, token })
+    }
+
+    pub async fn tokens(&self, user_id: i64) -> Result<Vec<ApiToken>, UserError> {
+        Ok(sqlx::query_as(&format!("SELECT {TOKEN_COLUMNS} FROM api_tokens WHERE user_id = $1 ORDER BY id"))
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    pub async fn revoke_token(&self, user_id: i64, token_id: i64) -> Result<(), UserError> {
+        let res = sqlx::query("DELETE FROM api_tokens WHERE id = $1 AND user_id = $2")
+            .bind(token_id)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(UserError::NotFound(format!("token {}", token_id)));
+        }
+        Ok(())
+    }
+
+    pub async fn is_admin(&self, user_id: i64) -> Result<bool, UserError> {
+        let row: Option<(bool,)> = sqlx::query_as("SELECT is_admin FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.is_some_and(|(is_admin,)| is_admin))
+    }
+
+    /// The owner of `token`, `None` for unknown or revoked tokens.
+    pub async fn authenticate(&self, token: &str) -> Result<Option<User>, UserError> {
+        Ok(sqlx::query_as(
+            "WITH used AS (
+               UPDATE api_tokens SET last_used_at = NOW() WHERE token_hash = $1 RETURNING user_id
+             )
+             SELECT u.id, u.username, u.is_admin, EXTRACT(EPOCH FROM u.created_at)::BIGINT AS created_at
+             FROM users u JOIN used ON used.user_id = u.id")
+            .bind(token_hash(token))
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+}
+
+/// Implemented by router states that can authenticate users.
+pub trait UserStoreState {
+    fn user_store(&self) -> &UserStore;
+}
+
+impl<T: UserStoreState> UserStoreState for Arc<T> {
+    fn user_store(&self) -> &UserStore {
+        (**self).user_store()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Credentials {
+    username: String,
+    password: String,
+}
+
+fn default_token_name() -> String {
+    "login".to_owned()
+}
+
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    #[serde(flatten)]
+    credentials: Credentials,
+    /// Name of the issued token, to tell them apart when listing.
+    #[serde(default = "default_token_name")]
+    token_name: String,
+}
+
+#[derive(Serialize)]
+pub struct LoginResponse {
+    user: User,
+    token: IssuedToken,
+}
+
+#[derive(Deserialize)]
+pub struct NewToken {
+    name: String,
+}
+
+async fn register<S: UserStoreState>(State(state): State<S>, Json(body): Json<Credentials>) -> Result<impl IntoResponse, UserError> {
+    let user = state.user_store().register(&body.username, &body.password).await?;
+
+    Ok((StatusCode::CREATED, Json(user)))
+}
+
+/// Checks the password and hands out a new API token.
+async fn login<S: UserStoreState>(State(state): State<S>, Json(body): Json<LoginRequest>) -> Result<impl IntoResponse, UserError> {
+    let store = state.user_store();
+    let user = store.login(&body.credentials.username, &body.credentials.password).await?;
+    let token = store.create_token(user.id, &body.token_name).await?;
+
+    Ok(Json(LoginResponse { user, token }))
+}
+
+async fn me(AuthUser(user): AuthUser) -> impl IntoResponse {
+    Json(user)
+}
+
+async fn list_tokens<S: UserStoreState>(AuthUser(user): AuthUser, State(state): State<S>) -> Result<impl IntoResponse, UserError> {
+    Ok(Json(state.user_store().tokens(user.id).await?))
+}
+
+async fn create_token<S: UserStoreState>(
+    AuthUser(user): AuthUser,
+    State(state): State<S>,
+    Json(body): Json<NewToken>,
+) -> Result<impl IntoResponse, UserError> {
+    let token = state.user_store().create_token(user.id, &body.name).await?;
+
+    Ok((StatusCode::CREATED, Json(token)))
+}
+
+async fn revoke_token<S: UserStoreState>(
+    AuthUser(user): AuthUser,
+    Path(id): Path<i64>,
+    State(state): State<S>,
+) -> Result<impl IntoResponse, UserError> {
+    state.user_store().revoke_token(user.id, id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `/register`, `/login` and the `/me` endpoints, meant to be nested under `/users`.
+pub fn router<S>() -> Router<S>
+where
+    S: UserStoreState + SessionStoreState + Clone + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/register", post(register::<S>))
+        .route("/login", post(login::<S>))
+        .route("/me", get(me))
+        .route("/me/tokens", get(list_tokens::<S>).post(create_token::<S>))
+        .route("/me/tokens/{id}", delete(revoke_token::<S>))
+}
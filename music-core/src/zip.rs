@@ -0,0 +1,412 @@
+//! ZIP archives written while they're streamed. Entries are stored without
+//! compression and their CRC and sizes follow the data in a data descriptor,
+//! so nothing has to be buffered. Single entries are limited to 4 GiB, the
+//! archive itself switches to ZIP64 records once it outgrows the classic ones.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+use crate::ByteStream;
+
+const LOCAL_HEADER: u32 = 0x04034b50;
+const DATA_DESCRIPTOR: u32 = 0x08074b50;
+const CENTRAL_HEADER: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIR: u32 = 0x06054b50;
+const ZIP64_END_OF_CENTRAL_DIR: u32 = 0x06064b50;
+const ZIP64_LOCATOR: u32 = 0x07064b50;
+const ZIP64_EXTRA: u16 = 0x0001;
+
+/// Sizes and CRC come after the data, names are UTF-8.
+const FLAGS: u16 = 1 << 3 | 1 << 11;
+const VERSION: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+/// Unix, so the external attributes carry file permissions.
+const MADE_BY_UNIX: u16 = 3 << 8;
+const FILE_MODE: u32 = 0o100644;
+/// 1980-01-01 00:00, the earliest date DOS time can express.
+const DOS_TIME: u16 = 0;
+const DOS_DATE: u16 = 1 << 5 | 1;
+
+struct Entry {
+    name: String,
+    crc: u32,
+    size: u64,
+    offset: u64,
+}
+
+impl Entry {
+    /// Whether the entry starts too far in for the classic records, its
+    /// offset goes in a ZIP64 extra field then.
+    fn zip64(&self) -> bool {
+        self.offset >= u32::MAX as u64
+    }
+}
+
+/// Produces the bytes of an archive around entry data passed through it.
+#[derive(Default)]
+pub struct ZipWriter {
+    entries: Vec<Entry>,
+    /// Bytes written so far.
+    offset: u64,
+    hasher: crc32fast::Hasher,
+}
+
+impl ZipWriter {
+    /// Local header of a new entry, its data follows through `write`.
+    pub fn start_entry(&mut self, name: &str) -> Bytes {
+        let entry = Entry { name: name.to_owned(), crc: 0, size: 0, offset: self.offset };
+        let version = if entry.zip64() { VERSION_ZIP64 } else { VERSION };
+        self.entries.push(entry);
+        self.hasher = crc32fast::Hasher::new();
+
+        let mut out = BytesMut::with_capacity(30 + name.len());
+        out.put_u32_le(LOCAL_HEADER);
+        out.put_u16_le(version);
+        out.put_u16_le(FLAGS);
+        out.put_u16_le(0); // Stored.
+        out.put_u16_le(DOS_TIME);
+        out.put_u16_le(DOS_DATE);
+        out.put_bytes(0, 12); // CRC and sizes, in the data descriptor.
+        out.put_u16_le(name.len() as u16);
+        out.put_u16_le(0);
+        out.put_slice(name.as_bytes());
+        self.emit(out)
+    }
+
+    /// Accounts for a chunk of the current entry's data.
+    pub fn write(&mut self, chunk: &[u8]) {
+        self.hasher.update(chunk);
+        self.offset += chunk.len() as u64;
+        if let Some(entry) = self.entries.last_mut() {
+            entry.size += chunk.len() as u64;
+        }
+    }
+
+    /// Data descriptor of the current entry.
+    pub fn finish_entry(&mut self) -> Bytes {
+        let crc = std::mem::take(&mut self.hasher).finalize();
+        let Some(entry) = self.entries.last_mut() else {
+            return Bytes::new();
+        };
+        entry.crc = crc;
+
+        let mut out = BytesMut::with_capacity(16);
+        out.put_u32_le(DATA_DESCRIPTOR);
+        out.put_u32_le(crc);
+        out.put_u32_le(entry.size as u32);
+        out.put_u32_le(entry.size as u32);
+        self.emit(out)
+    }
+
+    /// Central directory and end records.
+    pub fn finish(mut self) -> Bytes {
+        let mut out = BytesMut::new();
+        let cd_offset = self.offset;
+
+        for entry in &self.entries {
+            let zip64 = entry.zip64();
+            out.put_u32_le(CENTRAL_HEADER);
+            out.put_u16_le(MADE_BY_UNIX | VERSION_ZIP64);
+            out.put_u16_le(if zip64 { VERSION_ZIP64 } else { VERSION });
+            out.put_u16_le(FLAGS);
+            out.put_u16_le(0);
+            out.put_u16_le(DOS_TIME);
+            out.put_u16_le(DOS_DATE);
+            out.put_u32_le(entry.crc);
+            out.put_u32_le(entry.size as u32);
+            out.put_u32_le(entry.size as u32);
+            out.put_u16_le(entry.name.len() as u16);
+            out.put_u16_le(if zip64 { 12 } else { 0 });
+            out.put_u16_le(0); // Comment.
+            out.put_u16_le(0); // Disk.
+            out.put_u16_le(0); // Internal attributes.
+            out.put_u32_le(FILE_MODE << 16);
+            out.put_u32_le(if zip64 { u32::MAX } else { entry.offset as u32 });
+            out.put_slice(entry.name.as_bytes());
+            if zip64 {
+                out.put_u16_le(ZIP64_EXTRA);
+                out.put_u16_le(8);
+                out.put_u64_le(entry.offset);
+            }
+        }
+
+        let cd_size = out.len() as u64;
+        let count = self.entries.len() as u64;
+        let zip64 = count >= u16::MAX as u64 || cd_offset >= u32::MAX as u64 || cd_size >= u32::MAX as u64;
+
+        if zip64 {
+            let record_offset = cd_offset + cd_size;
+            out.put_u32_le(ZIP64_END_OF_CENTRAL_DIR);
+            out.put_u64_le(44); // Size of the rest of the record.
+            out.put_u16_le(MADE_BY_UNIX | VERSION_ZIP64);
+            out.put_u16_le(VERSION_ZIP64);
+            out.put_u32_le(0);
+            out.put_u32_le(0);
+            out.put_u64_le(count);
+            out.put_u64_le(count);
+            out.put_u64_le(cd_size);
+            out.put_u64_le(cd_offset);
+
+            out.put_u32_le(ZIP64_LOCATOR);
+            out.put_u32_le(0);
+            out.put_u64_le(record_offset);
+            out.put_u32_le(1); // Total number of disks.
+        }
+
+        out.put_u32_le(END_OF_CENTRAL_DIR);
+        out.put_u16_le(0);
+        out.put_u16_le(0);
+        out.put_u16_le(count.min(u16::MAX as u64) as u16);
+        out.put_u16_le(count.min(u16::MAX as u64) as u16);
+        out.put_u32_le(cd_size.min(u32::MAX as u64) as u32);
+        out.put_u32_le(cd_offset.min(u32::MAX as u64) as u32);
+        out.put_u16_le(0); // Comment.
+        self.emit(out)
+    }
+
+    /// A writer whose output goes after `offset` bytes of something else.
+    #[cfg(test)]
+    fn at_offset(offset: u64) -> Self {
+        Self { offset, ..Self::default() }
+    }
+
+    fn emit(&mut self, out: BytesMut) -> Bytes {
+        self.offset += out.len() as u64;
+        out.freeze()
+    }
+}
+
+/// `name` made safe to use as a file name inside the archive and on the disk
+/// it's extracted to.
+pub fn file_name(name: &str) -> String {
+    let name: String = name.chars()
+        .map(|c| if c.is_control() || matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') { '_' } else { c })
+        .collect();
+    let name = name.trim().trim_end_matches('.');
+
+    if name.is_empty() { "_".to_owned() } else { name.to_owned() }
+}
+
+pub struct ZipEntry {
+    pub name: String,
+    pub body: ByteStream,
+}
+
+impl ZipEntry {
+    pub fn from_bytes(name: impl Into<String>, data: impl Into<Bytes>) -> Self {
+        let data = data.into();
+        Self { name: name.into(), body: futures::stream::once(async move { Ok(data) }).boxed() }
+    }
+}
+
+/// Archives `entries` in order. An error in an entry's body cuts that entry
+/// short, the archive is still completed so the other entries can be read.
+pub fn zip_stream<S>(entries: S) -> ByteStream
+where
+    S: Stream<Item = ZipEntry> + Send + 'static,
+{
+    Box::pin(async_stream::stream! {
+        let mut zip = ZipWriter::default();
+        let mut entries = std::pin::pin!(entries);
+
+        while let Some(ZipEntry { name, mut body }) = entries.next().await {
+            yield Ok(zip.start_entry(&name));
+            while let Some(chunk) = body.next().await {
+                match chunk {
+                    Ok(chunk) => {
+                        zip.write(&chunk);
+                        yield Ok(chunk);
+                    }
+                    Err(e) => {
+                        eprintln!("Archive entry {} cut short: {}", name, e);
+                        break;
+                    }
+                }
+            }
+            yield Ok(zip.finish_entry());
+        }
+
+        yield Ok(zip.finish());
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+
+    async fn collect(stream: ByteStream) -> Vec<u8> {
+        let chunks: Vec<Bytes> = stream.map(Result::unwrap).collect().await;
+        chunks.concat()
+    }
+
+    fn entry(name: &str, chunks: Vec<std::io::Result<&'static [u8]>>) -> ZipEntry {
+        let chunks = chunks.into_iter().map(|chunk| chunk.map(Bytes::from_static));
+        ZipEntry { name: name.to_owned(), body: futures::stream::iter(chunks).boxed() }
+    }
+
+    fn read_all(archive: &mut ::zip::ZipArchive<impl Read + Seek>, name: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        // The reader checks the CRC once the entry is read to its end.
+        archive.by_name(name).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    fn u16_at(buf: &[u8], pos: usize) -> u16 {
+        u16::from_le_bytes(buf[pos..pos + 2].try_into().unwrap())
+    }
+
+    fn u32_at(buf: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap())
+    }
+
+    fn u64_at(buf: &[u8], pos: usize) -> u64 {
+        u64::from_le_bytes(buf[pos..pos + 8].try_into().unwrap())
+    }
+
+    #[tokio::test]
+    async fn archives_read_back() {
+        let entries = futures::stream::iter(vec![
+            entry("01 - First.mp3", vec![Ok(b"first "), Ok(b"track")]),
+            entry("cover.jpg", vec![]),
+            entry("Ålbum.m3u", vec![Ok(b"#EXTM3U\n")]),
+        ]);
+        let archive = collect(zip_stream(entries)).await;
+
+        let mut archive = ::zip::ZipArchive::new(Cursor::new(archive)).unwrap();
+        assert_eq!(archive.len(), 3);
+        assert_eq!(read_all(&mut archive, "01 - First.mp3"), b"first track");
+        assert_eq!(read_all(&mut archive, "cover.jpg"), b"");
+        assert_eq!(read_all(&mut archive, "Ålbum.m3u"), b"#EXTM3U\n");
+        assert_eq!(archive.by_name("cover.jpg").unwrap().unix_mode(), Some(FILE_MODE));
+    }
+
+    #[tokio::test]
+    async fn failing_entries_are_cut_short() {
+        let entries = futures::stream::iter(vec![
+            entry("a.mp3", vec![Ok(b"partial"), Err(std::io::Error::other("gone")), Ok(b"never")]),
+            entry("b.mp3", vec![Ok(b"whole")]),
+        ]);
+        let archive = collect(zip_stream(entries)).await;
+
+        let mut archive = ::zip::ZipArchive::new(Cursor::new(archive)).unwrap();
+        assert_eq!(read_all(&mut archive, "a.mp3"), b"partial");
+        assert_eq!(read_all(&mut archive, "b.mp3"), b"whole");
+    }
+
+    #[test]
+    fn classic_records_below_4_gib() {
+        let mut zip = ZipWriter::default();
+        let local = zip.start_entry("a.mp3");
+        zip.write(b"data");
+        let descriptor = zip.finish_entry();
+        let end = zip.finish();
+
+        assert_eq!(u32_at(&local, 0), LOCAL_HEADER);
+        assert_eq!(u16_at(&local, 4), VERSION);
+        assert_eq!(u32_at(&descriptor, 0), DATA_DESCRIPTOR);
+        assert_eq!(u32_at(&descriptor, 4), crc32fast::hash(b"data"));
+        assert_eq!(u32_at(&descriptor, 8), 4);
+
+        assert_eq!(u32_at(&end, 0), CENTRAL_HEADER);
+        assert_eq!(u16_at(&end, 6), VERSION);
+        assert_eq!(u16_at(&end, 30), 0); // No extra field.
+        assert_eq!(u32_at(&end, 42), 0);
+        let eocd = 46 + "a.mp3".len();
+        assert_eq!(u32_at(&end, eocd), END_OF_CENTRAL_DIR);
+        assert_eq!(end.len(), eocd + 22);
+    }
+
+    #[test]
+    fn zip64_records_past_4_gib() {
+        let start = u32::MAX as u64 + 100;
+        let mut zip = ZipWriter::at_offset(start);
+        let local = zip.start_entry("a.mp3");
+        zip.write(b"data");
+        let descriptor = zip.finish_entry();
+        let cd_offset = start + local.len() as u64 + 4 + descriptor.len() as u64;
+        let end = zip.finish();
+
+        assert_eq!(u16_at(&local, 4), VERSION_ZIP64);
+
+        // Central header, its offset is in the ZIP64 extra field.
+        assert_eq!(u32_at(&end, 0), CENTRAL_HEADER);
+        assert_eq!(u16_at(&end, 6), VERSION_ZIP64);
+        assert_eq!(u32_at(&end, 20), 4);
+        assert_eq!(u16_at(&end, 30), 12);
+        assert_eq!(u32_at(&end, 42), u32::MAX);
+        let extra = 46 + "a.mp3".len();
+        assert_eq!(u16_at(&end, extra), ZIP64_EXTRA);
+        assert_eq!(u16_at(&end, extra + 2), 8);
+        assert_eq!(u64_at(&end, extra + 4), start);
+
+        let record = extra + 12;
+        assert_eq!(u32_at(&end, record), ZIP64_END_OF_CENTRAL_DIR);
+        assert_eq!(u64_at(&end, record + 4), 44);
+        assert_eq!(u16_at(&end, record + 14), VERSION_ZIP64);
+        assert_eq!(u64_at(&end, record + 24), 1);
+        assert_eq!(u64_at(&end, record + 32), 1);
+        assert_eq!(u64_at(&end, record + 40), record as u64);
+        assert_eq!(u64_at(&end, record + 48), cd_offset);
+
+        let locator = record + 56;
+        assert_eq!(u32_at(&end, locator), ZIP64_LOCATOR);
+        assert_eq!(u64_at(&end, locator + 8), cd_offset + record as u64);
+
+        let eocd = locator + 20;
+        assert_eq!(u32_at(&end, eocd), END_OF_CENTRAL_DIR);
+        assert_eq!(u16_at(&end, eocd + 10), 1);
+        assert_eq!(u32_at(&end, eocd + 16), u32::MAX);
+        assert_eq!(end.len(), eocd + 22);
+    }
+
+    /// An archive written after `offset` zero bytes, without keeping them around.
+    struct Padded {
+        offset: u64,
+        data: Vec<u8>,
+        pos: u64,
+    }
+
+    impl Read for Padded {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = if self.pos < self.offset {
+                let len = buf.len().min((self.offset - self.pos) as usize);
+                buf[..len].fill(0);
+                len
+            } else {
+                let data = self.data.get((self.pos - self.offset) as usize..).unwrap_or_default();
+                let len = buf.len().min(data.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                len
+            };
+            self.pos += len as u64;
+            Ok(len)
+        }
+    }
+
+    impl Seek for Padded {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            let end = self.offset + self.data.len() as u64;
+            self.pos = match pos {
+                SeekFrom::Start(pos) => pos,
+                SeekFrom::End(delta) => end.checked_add_signed(delta).unwrap(),
+                SeekFrom::Current(delta) => self.pos.checked_add_signed(delta).unwrap(),
+            };
+            Ok(self.pos)
+        }
+    }
+
+    #[test]
+    fn zip64_archives_read_back() {
+        let offset = u32::MAX as u64 + 100;
+        let mut zip = ZipWriter::at_offset(offset);
+        let mut data = zip.start_entry("a.mp3").to_vec();
+        zip.write(b"data");
+        data.extend_from_slice(b"data");
+        data.extend_from_slice(&zip.finish_entry());
+        data.extend_from_slice(&zip.finish());
+
+        let mut archive = ::zip::ZipArchive::new(Padded { offset, data, pos: 0 }).unwrap();
+        assert_eq!(archive.len(), 1);
+        assert_eq!(read_all(&mut archive, "a.mp3"), b"data");
+    }
+}
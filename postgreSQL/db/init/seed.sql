@@ -5,6 +5,30 @@
 -- DROP TYPE IF EXISTS track_input, album_input, author_input CASCADE;
 
 
+-- =================================================================
+-- 0. USERS
+-- =================================================================
+
+CREATE TABLE users (
+  id            BIGSERIAL   PRIMARY KEY,
+  username      TEXT        NOT NULL UNIQUE,
+  password_hash TEXT        NOT NULL, -- Argon2 PHC string
+  is_admin      BOOLEAN     NOT NULL DEFAULT FALSE, -- set by hand, e.g. UPDATE users SET is_admin = TRUE WHERE username = '...'
+  created_at    TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE api_tokens (
+  id           BIGSERIAL   PRIMARY KEY,
+  user_id      BIGINT      NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  name         TEXT        NOT NULL,
+  token_hash   TEXT        NOT NULL UNIQUE, -- SHA-256 of the token, the token itself is never stored
+  created_at   TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  last_used_at TIMESTAMPTZ
+);
+
+CREATE INDEX ON api_tokens (user_id);
+
+
 -- =================================================================
 -- 1. SCHEMAS 
 -- =================================================================
@@ -26,10 +50,13 @@ CREATE TABLE tracks_soundcloud (
 CREATE TABLE listenings_soundcloud (
   id          BIGSERIAL PRIMARY KEY ,
   track_id    INT NOT NULL REFERENCES tracks_soundcloud(id) ON DELETE CASCADE,
+  user_id     BIGINT REFERENCES users(id) ON DELETE SET NULL, -- NULL for anonymous plays
   listened_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
 );
 
 CREATE INDEX ON listenings_soundcloud (track_id);
+CREATE INDEX ON listenings_soundcloud (listened_at);
+CREATE INDEX ON listenings_soundcloud (user_id, listened_at);
 
 
 -- =================================================================
@@ -75,12 +102,13 @@ $$;
 
 
 CREATE OR REPLACE FUNCTION record_listen_soundcloud(
-  _track_id INT
+  _track_id INT,
+  _user_id  BIGINT DEFAULT NULL
 )
 RETURNS BOOLEAN AS $$
 BEGIN
   -- Пытаемся вставить запись
-INSERT INTO listenings_soundcloud (track_id) VALUES (_track_id);
+INSERT INTO listenings_soundcloud (track_id, user_id) VALUES (_track_id, _user_id);
 
 -- Если мы дошли до этой строки, значит INSERT прошел успешно
 RETURN TRUE;
@@ -142,13 +170,31 @@ CREATE TABLE tracks_deezer (
   album_id  INT     NOT NULL REFERENCES albums_deezer(id) ON DELETE CASCADE
 );
 
+CREATE TABLE playlists_deezer (
+  id        BIGINT  PRIMARY KEY,
+  title     TEXT    NOT NULL,
+  img       TEXT
+);
+
+CREATE TABLE playlist_tracks_deezer (
+  playlist_id BIGINT NOT NULL REFERENCES playlists_deezer(id) ON DELETE CASCADE,
+  track_id    INT    NOT NULL REFERENCES tracks_deezer(id) ON DELETE CASCADE,
+  position    INT    NOT NULL,
+  PRIMARY KEY (playlist_id, position)
+);
+
+CREATE INDEX ON playlist_tracks_deezer (track_id);
+
 CREATE TABLE listenings_deezer (
   id          BIGSERIAL PRIMARY KEY ,
   track_id    INT NOT NULL REFERENCES tracks_deezer(id) ON DELETE CASCADE,
+  user_id     BIGINT REFERENCES users(id) ON DELETE SET NULL, -- NULL for anonymous plays
   listened_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
 );
 
 CREATE INDEX ON listenings_deezer (track_id);
+CREATE INDEX ON listenings_deezer (listened_at);
+CREATE INDEX ON listenings_deezer (user_id, listened_at);
 
 
 -- =================================================================
@@ -173,6 +219,25 @@ CREATE TYPE album_input_deezer AS (
   img       TEXT
 );
 
+CREATE TYPE playlist_input_deezer AS (
+  id        BIGINT,
+  title     TEXT,
+  img       TEXT
+);
+
+-- Playlist tracks come from many albums, so each one carries its own album and author.
+CREATE TYPE playlist_track_input_deezer AS (
+  id            INT,
+  title         TEXT,
+  duration      INT,
+  album_id      INT,
+  album_title   TEXT,
+  album_img     TEXT,
+  author_id     INT,
+  author_title  TEXT,
+  author_img    TEXT
+);
+
 
 -- =================================================================
 -- 3. PROCEDURES
@@ -235,13 +300,69 @@ END;
 $$;
 
 
+CREATE OR REPLACE PROCEDURE add_playlist_deezer(
+    p_playlist playlist_input_deezer,
+    p_tracks   playlist_track_input_deezer[]
+)
+LANGUAGE plpgsql
+AS $$
+DECLARE
+    v_track    playlist_track_input_deezer;
+    v_position INT := 0;
+BEGIN
+    -- Step 1: Insert or update the playlist itself.
+    INSERT INTO playlists_deezer (id, title, img)
+    VALUES (p_playlist.id, p_playlist.title, p_playlist.img)
+    ON CONFLICT (id) DO UPDATE SET
+      title = EXCLUDED.title,
+      img = EXCLUDED.img;
+
+    -- Step 2: The track list is replaced as a whole, so removed and reordered tracks are picked up.
+    DELETE FROM playlist_tracks_deezer WHERE playlist_id = p_playlist.id;
+
+    -- Step 3: Make sure every track's author and album exist, then upsert the track and link it.
+    -- Authors and albums are only created here, never overwritten, add_album_deezer owns their details.
+    FOREACH v_track IN ARRAY p_tracks
+    LOOP
+      INSERT INTO authors_deezer (id, title, img)
+      VALUES (v_track.author_id, v_track.author_title, v_track.author_img)
+      ON CONFLICT (id) DO NOTHING;
+
+      INSERT INTO albums_deezer (id, title, img, author_id)
+      VALUES (v_track.album_id, v_track.album_title, v_track.album_img, v_track.author_id)
+      ON CONFLICT (id) DO NOTHING;
+
+      INSERT INTO tracks_deezer (id, title, duration, img, author_id, album_id)
+      VALUES (
+        v_track.id,
+        v_track.title,
+        v_track.duration,
+        v_track.album_img,
+        v_track.author_id,
+        v_track.album_id
+      )
+      ON CONFLICT (id) DO UPDATE SET
+        title = EXCLUDED.title,
+        duration = EXCLUDED.duration;
+
+      INSERT INTO playlist_tracks_deezer (playlist_id, track_id, position)
+      VALUES (p_playlist.id, v_track.id, v_position);
+
+      v_position := v_position + 1;
+    END LOOP;
+
+END;
+$$;
+
+
 CREATE OR REPLACE FUNCTION record_listen_deezer(
-  _track_id INT
+  _track_id INT,
+  _user_id  BIGINT DEFAULT NULL
 )
 RETURNS BOOLEAN AS $$
 BEGIN
   -- Пытаемся вставить запись
-INSERT INTO listenings_deezer (track_id) VALUES (_track_id);
+INSERT INTO listenings_deezer (track_id, user_id) VALUES (_track_id, _user_id);
 
 -- Если мы дошли до этой строки, значит INSERT прошел успешно
 RETURN TRUE;
@@ -269,3 +390,94 @@ FROM tracks_deezer t
 LEFT JOIN listenings_deezer l ON t.id = l.track_id
 GROUP BY t.id;
 
+
+-- =================================================================
+-- 5. USER LIBRARY
+-- =================================================================
+
+-- Playlists and favorites mix providers, items point into tracks_deezer or
+-- tracks_soundcloud depending on `provider`, so there's no foreign key on the track.
+CREATE TABLE user_playlists (
+  id         BIGSERIAL   PRIMARY KEY,
+  user_id    BIGINT      NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  name       TEXT        NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX ON user_playlists (user_id);
+
+CREATE TABLE user_playlist_items (
+  id          BIGSERIAL   PRIMARY KEY,
+  playlist_id BIGINT      NOT NULL REFERENCES user_playlists(id) ON DELETE CASCADE,
+  position    INT         NOT NULL, -- 0-based, without gaps
+  provider    TEXT        NOT NULL CHECK (provider IN ('deezer', 'soundcloud')),
+  track_id    INT         NOT NULL,
+  added_at    TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  -- Deferred so positions can be shifted in a single UPDATE.
+  UNIQUE (playlist_id, position) DEFERRABLE INITIALLY DEFERRED
+);
+
+CREATE TABLE user_favorites (
+  user_id  BIGINT      NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  provider TEXT        NOT NULL CHECK (provider IN ('deezer', 'soundcloud')),
+  track_id INT         NOT NULL,
+  added_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (user_id, provider, track_id)
+);
+
+CREATE INDEX ON user_favorites (user_id, added_at);
+
+CREATE TABLE user_saved_albums (
+  user_id  BIGINT      NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  provider TEXT        NOT NULL CHECK (provider IN ('deezer', 'soundcloud')),
+  album_id INT         NOT NULL,
+  added_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (user_id, provider, album_id)
+);
+
+CREATE INDEX ON user_saved_albums (user_id, added_at);
+
+-- The tracks of every provider in one shape, to resolve library items against.
+CREATE OR REPLACE VIEW catalog_tracks AS
+SELECT
+  'deezer' AS provider,
+  t.id,
+  t.title,
+  t.duration,
+  t.img,
+  a.id     AS author_id,
+  a.title  AS author_title,
+  a.img    AS author_img,
+  al.id    AS album_id,
+  al.title AS album_title
+FROM tracks_deezer t
+JOIN authors_deezer a ON a.id = t.author_id
+JOIN albums_deezer al ON al.id = t.album_id
+UNION ALL
+SELECT
+  'soundcloud',
+  t.id,
+  t.title,
+  t.duration,
+  t.img,
+  a.id,
+  a.title,
+  a.img,
+  NULL,
+  NULL
+FROM tracks_soundcloud t
+JOIN authors_soundcloud a ON a.id = t.author_id;
+
+-- The albums of every provider in one shape, SoundCloud has none.
+CREATE OR REPLACE VIEW catalog_albums AS
+SELECT
+  'deezer' AS provider,
+  al.id,
+  al.title,
+  al.img,
+  a.id     AS author_id,
+  a.title  AS author_title,
+  a.img    AS author_img
+FROM albums_deezer al
+JOIN authors_deezer a ON a.id = al.author_id;
@@ -0,0 +1,250 @@
+-- Brings a database created from an older seed.sql up to date with user
+-- accounts, per-user listens, Deezer playlists and the user library. Fresh
+-- databases get all of this from init/seed.sql already. Safe to run more than
+-- once:
+--
+--   psql -h localhost -U admin -d musicdb -v ON_ERROR_STOP=1 -f 001_users_and_library.sql
+
+BEGIN;
+
+-- =================================================================
+-- 0. USERS
+-- =================================================================
+
+CREATE TABLE IF NOT EXISTS users (
+  id            BIGSERIAL   PRIMARY KEY,
+  username      TEXT        NOT NULL UNIQUE,
+  password_hash TEXT        NOT NULL, -- Argon2 PHC string
+  is_admin      BOOLEAN     NOT NULL DEFAULT FALSE,
+  created_at    TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS is_admin BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE IF NOT EXISTS api_tokens (
+  id           BIGSERIAL   PRIMARY KEY,
+  user_id      BIGINT      NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  name         TEXT        NOT NULL,
+  token_hash   TEXT        NOT NULL UNIQUE, -- SHA-256 of the token, the token itself is never stored
+  created_at   TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  last_used_at TIMESTAMPTZ
+);
+
+-- Named like the ones `CREATE INDEX ON` gives seed.sql databases.
+CREATE INDEX IF NOT EXISTS api_tokens_user_id_idx ON api_tokens (user_id);
+
+
+-- =================================================================
+-- 1. LISTENINGS
+-- =================================================================
+
+ALTER TABLE listenings_soundcloud ADD COLUMN IF NOT EXISTS user_id BIGINT REFERENCES users(id) ON DELETE SET NULL;
+ALTER TABLE listenings_deezer ADD COLUMN IF NOT EXISTS user_id BIGINT REFERENCES users(id) ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS listenings_soundcloud_listened_at_idx ON listenings_soundcloud (listened_at);
+CREATE INDEX IF NOT EXISTS listenings_soundcloud_user_id_listened_at_idx ON listenings_soundcloud (user_id, listened_at);
+CREATE INDEX IF NOT EXISTS listenings_deezer_listened_at_idx ON listenings_deezer (listened_at);
+CREATE INDEX IF NOT EXISTS listenings_deezer_user_id_listened_at_idx ON listenings_deezer (user_id, listened_at);
+
+-- The single argument versions would make calls with one argument ambiguous.
+DROP FUNCTION IF EXISTS record_listen_soundcloud(INT);
+DROP FUNCTION IF EXISTS record_listen_deezer(INT);
+
+CREATE OR REPLACE FUNCTION record_listen_soundcloud(
+  _track_id INT,
+  _user_id  BIGINT DEFAULT NULL
+)
+RETURNS BOOLEAN AS $$
+BEGIN
+INSERT INTO listenings_soundcloud (track_id, user_id) VALUES (_track_id, _user_id);
+RETURN TRUE;
+
+EXCEPTION
+  -- The track isn't in the db yet.
+  WHEN foreign_key_violation THEN
+    RETURN FALSE;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION record_listen_deezer(
+  _track_id INT,
+  _user_id  BIGINT DEFAULT NULL
+)
+RETURNS BOOLEAN AS $$
+BEGIN
+INSERT INTO listenings_deezer (track_id, user_id) VALUES (_track_id, _user_id);
+RETURN TRUE;
+
+EXCEPTION
+  -- The track isn't in the db yet.
+  WHEN foreign_key_violation THEN
+    RETURN FALSE;
+END;
+$$ LANGUAGE plpgsql;
+
+
+-- =================================================================
+-- 2. DEEZER PLAYLISTS
+-- =================================================================
+
+CREATE TABLE IF NOT EXISTS playlists_deezer (
+  id        BIGINT  PRIMARY KEY,
+  title     TEXT    NOT NULL,
+  img       TEXT
+);
+
+CREATE TABLE IF NOT EXISTS playlist_tracks_deezer (
+  playlist_id BIGINT NOT NULL REFERENCES playlists_deezer(id) ON DELETE CASCADE,
+  track_id    INT    NOT NULL REFERENCES tracks_deezer(id) ON DELETE CASCADE,
+  position    INT    NOT NULL,
+  PRIMARY KEY (playlist_id, position)
+);
+
+CREATE INDEX IF NOT EXISTS playlist_tracks_deezer_track_id_idx ON playlist_tracks_deezer (track_id);
+
+-- Types have no IF NOT EXISTS.
+DO $$
+BEGIN
+  IF to_regtype('playlist_input_deezer') IS NULL THEN
+    CREATE TYPE playlist_input_deezer AS (
+      id        BIGINT,
+      title     TEXT,
+      img       TEXT
+    );
+  END IF;
+
+  IF to_regtype('playlist_track_input_deezer') IS NULL THEN
+    CREATE TYPE playlist_track_input_deezer AS (
+      id            INT,
+      title         TEXT,
+      duration      INT,
+      album_id      INT,
+      album_title   TEXT,
+      album_img     TEXT,
+      author_id     INT,
+      author_title  TEXT,
+      author_img    TEXT
+    );
+  END IF;
+END;
+$$;
+
+CREATE OR REPLACE PROCEDURE add_playlist_deezer(
+    p_playlist playlist_input_deezer,
+    p_tracks   playlist_track_input_deezer[]
+)
+LANGUAGE plpgsql
+AS $$
+DECLARE
+    v_track    playlist_track_input_deezer;
+    v_position INT := 0;
+BEGIN
+    INSERT INTO playlists_deezer (id, title, img)
+    VALUES (p_playlist.id, p_playlist.title, p_playlist.img)
+    ON CONFLICT (id) DO UPDATE SET
+      title = EXCLUDED.title,
+      img = EXCLUDED.img;
+
+    -- The track list is replaced as a whole, so removed and reordered tracks are picked up.
+    DELETE FROM playlist_tracks_deezer WHERE playlist_id = p_playlist.id;
+
+    -- Authors and albums are only created here, never overwritten, add_album_deezer owns their details.
+    FOREACH v_track IN ARRAY p_tracks
+    LOOP
+      INSERT INTO authors_deezer (id, title, img)
+      VALUES (v_track.author_id, v_track.author_title, v_track.author_img)
+      ON CONFLICT (id) DO NOTHING;
+
+      INSERT INTO albums_deezer (id, title, img, author_id)
+      VALUES (v_track.album_id, v_track.album_title, v_track.album_img, v_track.author_id)
+      ON CONFLICT (id) DO NOTHING;
+
+      INSERT INTO tracks_deezer (id, title, duration, img, author_id, album_id)
+      VALUES (
+        v_track.id,
+        v_track.title,
+        v_track.duration,
+        v_track.album_img,
+        v_track.author_id,
+        v_track.album_id
+      )
+      ON CONFLICT (id) DO UPDATE SET
+        title = EXCLUDED.title,
+        duration = EXCLUDED.duration;
+
+      INSERT INTO playlist_tracks_deezer (playlist_id, track_id, position)
+      VALUES (p_playlist.id, v_track.id, v_position);
+
+      v_position := v_position + 1;
+    END LOOP;
+
+END;
+$$;
+
+
+-- =================================================================
+-- 3. USER LIBRARY
+-- =================================================================
+
+CREATE TABLE IF NOT EXISTS user_playlists (
+  id         BIGSERIAL   PRIMARY KEY,
+  user_id    BIGINT      NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  name       TEXT        NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS user_playlists_user_id_idx ON user_playlists (user_id);
+
+CREATE TABLE IF NOT EXISTS user_playlist_items (
+  id          BIGSERIAL   PRIMARY KEY,
+  playlist_id BIGINT      NOT NULL REFERENCES user_playlists(id) ON DELETE CASCADE,
+  position    INT         NOT NULL, -- 0-based, without gaps
+  provider    TEXT        NOT NULL CHECK (provider IN ('deezer', 'soundcloud')),
+  track_id    INT         NOT NULL,
+  added_at    TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  -- Deferred so positions can be shifted in a single UPDATE.
+  UNIQUE (playlist_id, position) DEFERRABLE INITIALLY DEFERRED
+);
+
+CREATE TABLE IF NOT EXISTS user_favorites (
+  user_id  BIGINT      NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  provider TEXT        NOT NULL CHECK (provider IN ('deezer', 'soundcloud')),
+  track_id INT         NOT NULL,
+  added_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (user_id, provider, track_id)
+);
+
+CREATE INDEX IF NOT EXISTS user_favorites_user_id_added_at_idx ON user_favorites (user_id, added_at);
+
+CREATE OR REPLACE VIEW catalog_tracks AS
+SELECT
+  'deezer' AS provider,
+  t.id,
+  t.title,
+  t.duration,
+  t.img,
+  a.id     AS author_id,
+  a.title  AS author_title,
+  a.img    AS author_img,
+  al.id    AS album_id,
+  al.title AS album_title
+FROM tracks_deezer t
+JOIN authors_deezer a ON a.id = t.author_id
+JOIN albums_deezer al ON al.id = t.album_id
+UNION ALL
+SELECT
+  'soundcloud',
+  t.id,
+  t.title,
+  t.duration,
+  t.img,
+  a.id,
+  a.title,
+  a.img,
+  NULL,
+  NULL
+FROM tracks_soundcloud t
+JOIN authors_soundcloud a ON a.id = t.author_id;
+
+COMMIT;
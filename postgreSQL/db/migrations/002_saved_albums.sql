@@ -0,0 +1,31 @@
+-- Adds saved albums to a database created from an older seed.sql, run after
+-- 001_users_and_library.sql. Safe to run more than once:
+--
+--   psql -h localhost -U admin -d musicdb -v ON_ERROR_STOP=1 -f 002_saved_albums.sql
+
+BEGIN;
+
+CREATE TABLE IF NOT EXISTS user_saved_albums (
+  user_id  BIGINT      NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  provider TEXT        NOT NULL CHECK (provider IN ('deezer', 'soundcloud')),
+  album_id INT         NOT NULL,
+  added_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (user_id, provider, album_id)
+);
+
+CREATE INDEX IF NOT EXISTS user_saved_albums_user_id_added_at_idx ON user_saved_albums (user_id, added_at);
+
+-- The albums of every provider in one shape, SoundCloud has none.
+CREATE OR REPLACE VIEW catalog_albums AS
+SELECT
+  'deezer' AS provider,
+  al.id,
+  al.title,
+  al.img,
+  a.id     AS author_id,
+  a.title  AS author_title,
+  a.img    AS author_img
+FROM albums_deezer al
+JOIN authors_deezer a ON a.id = al.author_id;
+
+COMMIT;